@@ -1,9 +1,11 @@
 use std::ffi::OsStr;
 use std::fs::{self, File};
-use std::io::Write;
 use std::path::Path;
 use std::process::Command;
 
+#[path = "src/render/material_file.rs"]
+mod materials;
+
 fn get_vulkan_sdk_path() -> String {
     let vulkan_sdk_path =
         std::env::var("VULKAN_SDK").expect("The environment variable $VULKAN_SDK is blank.");
@@ -15,204 +17,29 @@ fn get_vulkan_sdk_path() -> String {
 
 fn gen_material_code() {
     println!("cargo:rerun-if-changed=misc/*");
-    let material_defs =
-        csv::Reader::from_path("misc/materials.csv").expect("Failed to open misc/materials.csv");
-
-    fn parse_number(from: &str, min: i32, max: i32) -> i32 {
-        let initial: i32 = from
-            .trim()
-            .parse()
-            .expect("Malformed number in materials.csv");
-        if initial < min || initial > max {
-            panic!(
-                "The value {} in materials.csv is outside the range {}-{}.",
-                initial, min, max
-            );
-        }
-        initial
-    }
-
-    fn parse_rgb(r: &str, g: &str, b: &str) -> (i32, i32, i32) {
-        (
-            parse_number(r, 0x00, 0xFF),
-            parse_number(g, 0x00, 0xFF),
-            parse_number(b, 0x00, 0xFF),
-        )
-    }
-
-    #[derive(Debug)]
-    struct Material {
-        index: i32,
-        albedo: (i32, i32, i32),
-        emission: (i32, i32, i32),
-    }
-
-    let mut correct_index = 0;
-    let mut materials = Vec::new();
-    for item in material_defs.into_records() {
-        let item = item.expect("Failed to read materail from materials.csv");
-        if item.len() < 8 {
-            println!(
-                "Material number {} in materials.csv is improperly formatted.",
-                correct_index
-            );
-        }
-        let index = parse_number(&item[0], 0, 0xFFFF);
-        if index != correct_index {
-            println!(
-                "Material number {} is incorrectly labeled as being material number {}.",
-                correct_index, index
-            );
-        }
-        let albedo = parse_rgb(&item[1], &item[2], &item[3]);
-        let emission = {
-            let mul = parse_number(&item[7], 0, 9);
-            let (r, g, b) = parse_rgb(&item[4], &item[5], &item[6]);
-            (r * mul, g * mul, b * mul)
-        };
-        materials.push(Material {
-            index,
-            albedo,
-            emission,
-        });
-        correct_index += 1;
-    }
-
-    let mut glsl_header = File::create("shaders/glsl/GEN_MATERIALS.glsl")
-        .expect("Failed to open shaders/glsl/GEN_MATERIALS.glsl for writing");
-
-    writeln!(glsl_header, "vec3 get_material_albedo(uint material) {{").unwrap();
-    writeln!(glsl_header, "\tswitch(material) {{").unwrap();
-    for material in &materials {
-        writeln!(
-            glsl_header,
-            "\t\tcase {}: return vec3({}, {}, {});",
-            material.index,
-            material.albedo.0 as f32 / 255.0,
-            material.albedo.1 as f32 / 255.0,
-            material.albedo.2 as f32 / 255.0,
-        )
-        .unwrap();
-    }
-    writeln!(glsl_header, "\t}}\n}}\n").unwrap();
-
-    writeln!(glsl_header, "vec3 get_material_emission(uint material) {{").unwrap();
-    writeln!(glsl_header, "\tswitch(material) {{").unwrap();
-    for material in &materials {
-        writeln!(
-            glsl_header,
-            "\t\tcase {}: return vec3({}, {}, {});",
-            material.index,
-            material.emission.0 as f32 / 255.0,
-            material.emission.1 as f32 / 255.0,
-            material.emission.2 as f32 / 255.0,
-        )
-        .unwrap();
-    }
-    writeln!(glsl_header, "\t}}\n}}\n").unwrap();
-
-    let mut rust_materials = File::create("src/render/GEN_MATERIALS.rs")
-        .expect("Failed to open src/render/GEN_MATERIALS.rs for writing");
-    writeln!(
-        rust_materials,
-        r#"
-#[derive(Clone, PartialEq, Debug)]
-pub struct Material {{
-    pub albedo: (u16, u16, u16),
-    pub emission: (u16, u16, u16),
-    pub solid: bool,
-}}
-
-impl Material {{
-    pub fn air() -> Self {{
-        Self {{
-            albedo: (0, 0, 0),
-            emission: (0, 0, 0),
-            solid: false,
-        }}
-    }}
-
-    pub fn black() -> Self {{
-        Self {{
-            albedo: (0, 0, 0),
-            emission: (0, 0, 0),
-            solid: true,
-        }}
-    }}
-
-	pub fn add(&mut self, other: &Self) {{
-		self.albedo.0 += other.albedo.0;
-		self.albedo.1 += other.albedo.1;
-		self.albedo.2 += other.albedo.2;
-		self.emission.0 += other.emission.0;
-		self.emission.1 += other.emission.1;
-        self.emission.2 += other.emission.2;
-	}}
-
-	pub fn divide(&mut self, factor: u16) {{
-		self.albedo.0 /= factor;
-		self.albedo.1 /= factor;
-		self.albedo.2 /= factor;
-		self.emission.0 /= factor;
-		self.emission.1 /= factor;
-        self.emission.2 /= factor;
-    }}
-
-    pub fn pack(&self) -> u32 {{
-        let ar = (self.albedo.0) as u32;
-        let ag = (self.albedo.1) as u32;
-        let ab = (self.albedo.2) as u32;
-        let albedo = ar << 14 | ag << 7 | ab;
-        let solid = if self.solid {{ 1 }} else {{ 0 }};
-        (solid << 15) | albedo
-    }}
-
-    pub fn unpack(packed: u32) -> Self {{
-        let albedo = (
-            (packed >> 14 & 0x7F) as u16,
-            (packed >> 7 & 0x7F) as u16,
-            (packed >> 0 & 0x7F) as u16,
-        );
-        let emission = (0, 0, 0);
-        let solid = packed >> 15 & 0b1 != 0;
-        Self {{
-            albedo,
-            emission,
-            solid,
-        }}
-    }}
-}}
-
-#[rustfmt::skip]"#
+    println!("cargo:rerun-if-changed=src/render/material_file.rs");
+    let file_name = "misc/materials.csv";
+    let contents = fs::read_to_string(file_name).expect("Failed to open misc/materials.csv");
+    let materials = match materials::parse_materials(file_name, &contents) {
+        Ok(materials) => materials,
+        Err(errors) => panic!(
+            "\n{}\nMATERIAL ERROR: {} is invalid:\n\n{}\n",
+            "============================================================",
+            file_name,
+            errors.join("\n")
+        ),
+    };
+
+    fs::write(
+        "shaders/glsl/GEN_MATERIALS.glsl",
+        materials::generate_glsl(),
     )
-    .unwrap();
-    writeln!(
-        rust_materials,
-        "pub const MATERIALS: [Material; {}] = [",
-        materials.len()
+    .expect("Failed to write shaders/glsl/GEN_MATERIALS.glsl");
+    fs::write(
+        "src/render/GEN_MATERIALS.rs",
+        materials::generate_rust(&materials),
     )
-    .unwrap();
-    for (index, material) in materials.iter().enumerate() {
-        writeln!(
-            rust_materials,
-            concat!(
-                "\tMaterial {{\n",
-                "\t\talbedo:   ({:.9}, {:.9}, {:.9}),\n",
-                "\t\temission: ({:.9}, {:.9}, {:.9}),\n",
-                "\t\tsolid: {},\n",
-                "\t}},",
-            ),
-            material.albedo.0 / 2,
-            material.albedo.1 / 2,
-            material.albedo.2 / 2,
-            material.emission.0 / 2,
-            material.emission.1 / 2,
-            material.emission.2 / 2,
-            index != 0,
-        )
-        .unwrap();
-    }
-    writeln!(rust_materials, "];",).unwrap();
+    .expect("Failed to write src/render/GEN_MATERIALS.rs");
 }
 
 fn compile_shaders() {
@@ -1,89 +1,382 @@
 extern crate raytrace;
 
 use raytrace::util::prelude::*;
+use raytrace::world::ChunkStorage;
 use raytrace::*;
-use std::io::Write;
-use std::time::Instant;
-
-const RADIUS: usize = 32;
-
-struct StatTracker {
-    total_items: usize,
-    remaining_items: usize,
-    total_item_time: f64,
-    num_timed_items: f64,
-    item_timer: Option<Instant>,
-    update_interval: usize,
-    update_ticker: usize,
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "\
+Usage: generate [OPTIONS]
+
+Generates chunks ahead of time so that they do not have to be generated while exploring. If the
+program is stopped, running it again with the same options continues where it left off.
+
+Generation options:
+    --center X Y Z              The chunk at the center of the area to generate, 0 0 0 by default.
+    --radius R                  Generate chunks up to R chunks away from the center, 32 by default.
+    --bounds X0 Y0 Z0 X1 Y1 Z1  Generate every chunk from the first coordinate up to but not
+                                including the second, instead of around a center.
+    --threads N                 How many threads to generate with, one per processor by default.
+    --overwrite                 Generate chunks again even if they have been generated before.";
+
+const DEFAULT_RADIUS: isize = 32;
+// Progress is saved this often so that stopping the program does not lose much work.
+const SAVE_INTERVAL: Duration = Duration::from_secs(2);
+const PROGRESS_FILE_NAME: &str = "generate-progress.toml";
+
+fn exit_with_usage(message: &str) -> ! {
+    println!("{}\n\n{}\n\n{}", message, USAGE, cli::WORLD_USAGE);
+    std::process::exit(1);
+}
+
+struct GenerateOptions {
+    // Inclusive.
+    min: SignedCoord3D,
+    // Exclusive.
+    max: SignedCoord3D,
+    threads: Option<usize>,
+    overwrite: bool,
 }
 
-impl StatTracker {
-    fn new(num_items: usize) -> Self {
-        StatTracker {
-            total_items: num_items,
-            remaining_items: num_items,
-            total_item_time: 0.0,
-            num_timed_items: 0.0,
-            item_timer: None,
-            update_interval: 100,
-            update_ticker: 0,
+fn next_number(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<isize, String> {
+    let value = args
+        .next()
+        .ok_or_else(|| format!("{} requires more values.", flag))?;
+    value
+        .parse::<isize>()
+        .map_err(|_| format!("'{}' is not a valid number for {}.", value, flag))
+}
+
+impl GenerateOptions {
+    fn parse(args: Vec<String>) -> Result<GenerateOptions, String> {
+        let mut args = args.into_iter();
+        let mut center = (0, 0, 0);
+        let mut radius = DEFAULT_RADIUS;
+        let mut bounds = None;
+        let mut threads = None;
+        let mut overwrite = false;
+        while let Some(arg) = args.next() {
+            match &arg[..] {
+                "--center" => {
+                    center = (
+                        next_number(&mut args, &arg)?,
+                        next_number(&mut args, &arg)?,
+                        next_number(&mut args, &arg)?,
+                    );
+                }
+                "--radius" => {
+                    radius = next_number(&mut args, &arg)?;
+                    if radius <= 0 {
+                        return Err("--radius must be at least 1.".to_owned());
+                    }
+                }
+                "--bounds" => {
+                    let mut values = [0; 6];
+                    for value in &mut values {
+                        *value = next_number(&mut args, &arg)?;
+                    }
+                    let min = (values[0], values[1], values[2]);
+                    let max = (values[3], values[4], values[5]);
+                    if !(min.0 < max.0 && min.1 < max.1 && min.2 < max.2) {
+                        return Err("--bounds must describe a non-empty area.".to_owned());
+                    }
+                    bounds = Some((min, max));
+                }
+                "--threads" => {
+                    let count = next_number(&mut args, &arg)?;
+                    if count <= 0 {
+                        return Err("--threads must be at least 1.".to_owned());
+                    }
+                    threads = Some(count as usize);
+                }
+                "--overwrite" => overwrite = true,
+                _ => return Err(format!("Unrecognized option '{}'.", arg)),
+            }
         }
+        let (min, max) =
+            bounds.unwrap_or_else(|| (center.sub(radius.repeat()), center.add(radius.repeat())));
+        Ok(GenerateOptions {
+            min,
+            max,
+            threads,
+            overwrite,
+        })
+    }
+
+    fn get_size(&self) -> (u64, u64, u64) {
+        let size = self.max.sub(self.min);
+        (size.0 as u64, size.1 as u64, size.2 as u64)
     }
 
-    fn start_item(&mut self) {
-        self.item_timer = Some(Instant::now());
+    fn get_num_chunks(&self) -> u64 {
+        let size = self.get_size();
+        size.0 * size.1 * size.2
     }
 
-    fn end_item(&mut self) {
-        self.remaining_items -= 1;
-        if let Some(timer) = self.item_timer.take() {
-            let time = timer.elapsed().as_secs_f64();
-            // If it's less than a couple milliseconds, don't bother counting it.
-            if time > 0.004 {
-                self.total_item_time += time;
-                self.num_timed_items += 1.0;
+    /// Chunks are generated in order of their index, X increases the fastest.
+    fn index_to_coord(&self, index: u64) -> SignedCoord3D {
+        let size = self.get_size();
+        let offset = (
+            index % size.0,
+            index / size.0 % size.1,
+            index / (size.0 * size.1),
+        );
+        self.min
+            .add((offset.0 as isize, offset.1 as isize, offset.2 as isize))
+    }
+}
+
+/// Saved while generating so that the program can continue where it left off if it is stopped.
+#[derive(Serialize, Deserialize, PartialEq)]
+struct Progress {
+    min: [i64; 3],
+    max: [i64; 3],
+    overwrite: bool,
+    /// Every chunk with an index lower than this has been generated and stored.
+    completed: u64,
+}
+
+impl Progress {
+    fn new(options: &GenerateOptions, completed: u64) -> Progress {
+        let convert = |coord: SignedCoord3D| [coord.0 as i64, coord.1 as i64, coord.2 as i64];
+        Progress {
+            min: convert(options.min),
+            max: convert(options.max),
+            overwrite: options.overwrite,
+            completed,
+        }
+    }
+
+    /// Returns how many chunks a previous run with the same options completed.
+    fn load(path: &Path, options: &GenerateOptions) -> u64 {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(..) => return 0,
+        };
+        match toml::from_str::<Progress>(&text) {
+            Ok(progress) if progress == Progress::new(options, progress.completed) => {
+                progress.completed
+            }
+            Ok(..) => {
+                println!("A previous run with different options was stopped, starting over.");
+                0
+            }
+            Err(err) => {
+                println!("WARNING: Failed to read {:?}, starting over.", path);
+                println!("Caused by: {}", err);
+                0
             }
         }
     }
 
-    fn print_status(&mut self) {
-        self.update_ticker += 1;
-        if self.update_ticker < self.update_interval {
-            return;
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let text =
+            toml::to_string(self).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let temp_path = path.with_extension("toml.tmp");
+        std::fs::write(&temp_path, text)?;
+        std::fs::rename(&temp_path, path)
+    }
+}
+
+/// Keeps track of which chunks have been completed. Chunks finish out of order, so only the
+/// chunks up to the first unfinished one can be recorded as completed.
+struct CompletionTracker {
+    completed: u64,
+    // Finished chunks with a higher index than an unfinished chunk.
+    finished_early: BTreeSet<u64>,
+}
+
+impl CompletionTracker {
+    fn new(completed: u64) -> Self {
+        Self {
+            completed,
+            finished_early: BTreeSet::new(),
         }
-        self.update_ticker = 0;
-        let percent = (self.total_items - self.remaining_items) as f64 / self.total_items as f64;
-        print!("\r{:.1}%", percent * 100.0);
-        if self.num_timed_items > 50.0 {
-            let time_per_item = self.total_item_time / self.num_timed_items;
-            let remaining_seconds = (self.remaining_items as f64 * time_per_item) as u64;
-            print!(
-                ", ETA {:01}m{:01}s",
-                remaining_seconds / 60,
-                remaining_seconds % 60,
+    }
+
+    fn finish(&mut self, index: u64) {
+        self.finished_early.insert(index);
+        while self.finished_early.remove(&self.completed) {
+            self.completed += 1;
+        }
+    }
+}
+
+fn print_status(finished: u64, total: u64, generated: u64, elapsed: Duration) {
+    let percent = finished as f64 / total as f64 * 100.0;
+    let rate = generated as f64 / elapsed.as_secs_f64().max(0.001);
+    print!("\r{:.1}%, {:.1} chunks/s", percent, rate);
+    if generated > 0 {
+        let remaining_seconds = ((total - finished) as f64 / rate) as u64;
+        print!(
+            ", ETA {:01}m{:01}s",
+            remaining_seconds / 60,
+            remaining_seconds % 60,
+        );
+    }
+    print!("                    ");
+    io::stdout().flush().unwrap();
+}
+
+// Adds up the size of every file in the directory, including the ones in subdirectories.
+fn get_size_on_disk(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += get_size_on_disk(&entry.path())?;
+        } else if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+fn save_progress(world: &mut ChunkStorage, progress: &Progress, path: &Path) {
+    // Make sure the chunks are on disk before recording that they are done.
+    if let Err(err) = world.flush().and_then(|_| progress.save(path)) {
+        println!("\nWARNING: Failed to save progress.");
+        println!("Caused by: {}", err);
+    }
+}
+
+fn generate(world: &mut ChunkStorage, options: &GenerateOptions, progress_path: &Path) {
+    let total = options.get_num_chunks();
+    let start = Progress::load(progress_path, options);
+    if start > 0 {
+        println!("Continuing from chunk {} of {}.", start, total);
+    }
+    // Generated chunks are not needed after they have been stored.
+    world.set_cache_budget(0);
+    // Enough requests to keep every worker busy.
+    let max_requests = world.get_num_workers() * 4;
+    let mut tracker = CompletionTracker::new(start);
+    let mut requests = HashMap::new();
+    let mut next_index = start;
+    let (mut generated, mut skipped, mut failed) = (0, 0, 0);
+    let timer = Instant::now();
+    let mut save_timer = Instant::now();
+
+    while tracker.completed < total {
+        while requests.len() < max_requests && next_index < total {
+            let coord = options.index_to_coord(next_index);
+            if options.overwrite {
+                // Edits have to be saved before the chunk can be replaced.
+                if world.is_dirty(&coord) {
+                    if let Err(err) = world.flush() {
+                        println!("\nWARNING: Failed to save edited chunks.");
+                        println!("Caused by: {}", err);
+                    }
+                }
+                if world.request_regeneration(&coord) {
+                    requests.insert(coord, next_index);
+                } else {
+                    println!(
+                        "\nWARNING: Chunk {:?} has unsaved edits, it was not generated again.",
+                        coord
+                    );
+                    failed += 1;
+                    tracker.finish(next_index);
+                }
+            } else if world.has_chunk(&coord) {
+                skipped += 1;
+                tracker.finish(next_index);
+            } else {
+                world.request_chunk(&coord);
+                requests.insert(coord, next_index);
+            }
+            next_index += 1;
+        }
+        if let Some((coord, result)) = world.receive_next_chunk() {
+            if let Err(err) = result {
+                println!("\nWARNING: Failed to generate chunk {:?}.", coord);
+                println!("Caused by: {}", err);
+                failed += 1;
+            } else {
+                generated += 1;
+            }
+            tracker.finish(requests.remove(&coord).unwrap());
+        }
+        if save_timer.elapsed() > SAVE_INTERVAL {
+            save_timer = Instant::now();
+            save_progress(
+                world,
+                &Progress::new(options, tracker.completed),
+                progress_path,
             );
+            print_status(tracker.completed, total, generated, timer.elapsed());
+        }
+    }
+
+    if let Err(err) = world.flush() {
+        println!("\nWARNING: Failed to save chunks.");
+        println!("Caused by: {}", err);
+    } else if progress_path.exists() {
+        if let Err(err) = std::fs::remove_file(progress_path) {
+            println!("\nWARNING: Failed to remove {:?}.", progress_path);
+            println!("Caused by: {}", err);
         }
-        print!("                    ");
-        std::io::stdout().flush().unwrap();
+    }
+    let seconds = timer.elapsed().as_secs_f64();
+    println!(
+        "\nGenerated {} chunks in {:.1}s ({:.1} chunks/s), skipped {} that already existed.",
+        generated,
+        seconds,
+        generated as f64 / seconds.max(0.001),
+        skipped
+    );
+    if failed > 0 {
+        println!("{} chunks could not be generated.", failed);
     }
 }
 
 fn main() {
-    let mut game = game::Game::new();
-    let radius = RADIUS;
-    let num_items = (radius * 2).pow(3);
-    let mut stat_tracker = StatTracker::new(num_items);
-    println!("\nGenerating chunks...");
-    for coord in util::coord_iter_3d(radius * 2) {
-        let world_coord = coord.signed().sub((radius as isize).repeat());
-        stat_tracker.start_item();
-        game.borrow_world_mut().borrow_packed_chunk_data(&(
-            world_coord.0,
-            world_coord.1,
-            world_coord.2,
-        ));
-        stat_tracker.end_item();
-        stat_tracker.print_status();
-    }
-    println!("Done!");
+    let args = cli::parse_world_args(std::env::args().skip(1))
+        .unwrap_or_else(|message| exit_with_usage(&message));
+    let library = world::WorldLibrary::open_default().expect("Failed to open world library.");
+    if let Some(command) = args.command {
+        if let Err(err) = cli::run_world_command(&library, command) {
+            println!("ERROR: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let options = GenerateOptions::parse(args.rest.clone())
+        .unwrap_or_else(|message| exit_with_usage(&message));
+    let mut world = cli::open_world(&library, &args).unwrap_or_else(|err| {
+        println!("ERROR: Failed to open world '{}': {}", args.world, err);
+        std::process::exit(1);
+    });
+    // The main thread only waits for the workers, so there can be a worker for every processor.
+    let threads = options.threads.unwrap_or_else(|| {
+        std::thread::available_parallelism()
+            .map(|count| count.get())
+            .unwrap_or(1)
+    });
+    world.set_num_workers(threads);
+    let world_dir = library.get_world_dir(&args.world);
+    println!(
+        "Generating {} chunks from {:?} to {:?} in world '{}' with {} threads.",
+        options.get_num_chunks(),
+        options.min,
+        options.max,
+        args.world,
+        world.get_num_workers()
+    );
+    generate(&mut world, &options, &world_dir.join(PROGRESS_FILE_NAME));
+    match get_size_on_disk(&world_dir) {
+        Ok(size) => println!(
+            "The world takes up {:.1} MB on disk.",
+            size as f64 / (1024.0 * 1024.0)
+        ),
+        Err(err) => {
+            println!("WARNING: Failed to measure the size of the world.");
+            println!("Caused by: {}", err);
+        }
+    }
 }
@@ -5,8 +5,49 @@ use std::time::Instant;
 use winit::event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
 
+const USAGE: &str = "\
+Usage: main [OPTIONS] [X Y Z HEADING PITCH SUN_ANGLE]
+
+If a camera position is given, the world is viewed from there instead of from where it was last
+viewed from.";
+
+fn exit_with_usage(message: &str) -> ! {
+    println!("{}\n\n{}\n\n{}", message, USAGE, cli::WORLD_USAGE);
+    std::process::exit(1);
+}
+
 fn main() {
-    let mut game = game::Game::new();
+    let args = cli::parse_world_args(std::env::args().skip(1))
+        .unwrap_or_else(|message| exit_with_usage(&message));
+    let library = world::WorldLibrary::open_default().expect("Failed to open world library.");
+    if let Some(command) = args.command {
+        if let Err(err) = cli::run_world_command(&library, command) {
+            println!("ERROR: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+    let world = cli::open_world(&library, &args).unwrap_or_else(|err| {
+        println!("ERROR: Failed to open world '{}': {}", args.world, err);
+        std::process::exit(1);
+    });
+    let mut game = game::Game::with_world(world);
+    if !args.rest.is_empty() {
+        let values: Vec<f32> = args
+            .rest
+            .iter()
+            .map(|value| value.parse())
+            .collect::<Result<_, _>>()
+            .unwrap_or_else(|_| exit_with_usage("Camera position must be made of numbers."));
+        if values.len() != 6 {
+            exit_with_usage("Camera position must have exactly six values.");
+        }
+        let camera = game.borrow_camera_mut();
+        camera.origin = [values[0], values[1], values[2]].into();
+        camera.heading.0 = values[3];
+        camera.pitch.0 = values[4];
+        game.set_sun_angle(values[5]);
+    }
     let event_loop = EventLoop::new();
     println!("Creating renderer (and world.)");
     let instance_timer = Instant::now();
@@ -52,6 +93,14 @@ fn main() {
             pipeline.draw_frame(&mut game);
             game.borrow_controls_mut().tick();
         }
+        Event::LoopDestroyed => {
+            if let Err(err) = game.save_world_state() {
+                println!("\nWARNING: Failed to save world state.");
+                println!("Caused by: {}", err);
+            }
+            println!();
+            cli::print_cache_stats(game.borrow_world());
+        }
         _ => (),
     });
 }
@@ -0,0 +1,235 @@
+//! Command line handling shared by the binaries.
+
+use crate::world::{self, ChunkStorage, WorldLibrary, WorldManifest, DEFAULT_WORLD_NAME};
+use std::io;
+
+pub const WORLD_USAGE: &str = "\
+World options:
+    --world NAME                Use the world with the given name instead of the default world.
+    --list-worlds               List all worlds and exit.
+    --create-world NAME         Create a new world and exit.
+    --seed SEED                 The seed to use with --create-world, random if not specified.
+    --generator NAME            The generator to use with --create-world, one of biomes,
+                                mountains, worley_mountains, superflat or empty. biomes by default.
+    --generator-param KEY=VALUE Sets a parameter of the generator used with --create-world. Can be
+                                given multiple times. The noise based generators take
+                                config=PATH to use a worldgen config file like the ones in
+                                misc/worldgen.
+    --copy-world FROM TO        Copy a world and exit.
+    --delete-world NAME         Delete a world and exit.
+    --cache-size MB             How much memory to use for caching chunks, 256 MB by default.";
+
+pub enum WorldCommand {
+    List,
+    Create {
+        name: String,
+        seed: Option<u64>,
+        generator: Option<String>,
+        generator_params: toml::value::Table,
+    },
+    Copy {
+        from: String,
+        to: String,
+    },
+    Delete {
+        name: String,
+    },
+}
+
+pub struct WorldArgs {
+    /// The name of the world that should be opened.
+    pub world: String,
+    /// If set, the program should run this command instead of opening a world.
+    pub command: Option<WorldCommand>,
+    /// How much memory the chunk cache may use, in bytes.
+    pub cache_budget: Option<usize>,
+    /// Arguments that are not related to worlds, in the order they were given.
+    pub rest: Vec<String>,
+}
+
+fn next_value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String, String> {
+    args.next()
+        .ok_or_else(|| format!("{} requires a value.", flag))
+}
+
+pub fn parse_seed(value: &str) -> Result<u64, String> {
+    value
+        .parse::<u64>()
+        .ok()
+        .filter(|seed| *seed <= i64::MAX as u64)
+        .ok_or_else(|| format!("'{}' is not a valid seed.", value))
+}
+
+/// Parses a KEY=VALUE pair. The value is read as TOML, or as a string if it is not valid TOML.
+fn parse_generator_param(param: &str) -> Result<(String, toml::Value), String> {
+    let split = param.find('=').ok_or_else(|| {
+        format!(
+            "'{}' is not a valid generator parameter, expected KEY=VALUE.",
+            param
+        )
+    })?;
+    let (key, value) = (param[..split].trim(), param[split + 1..].trim());
+    let value = toml::from_str::<toml::value::Table>(&format!("value = {}", value))
+        .ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_owned()));
+    Ok((key.to_owned(), value))
+}
+
+/// Extracts all world related options from the given arguments (not including the program name.)
+pub fn parse_world_args(args: impl IntoIterator<Item = String>) -> Result<WorldArgs, String> {
+    let mut args = args.into_iter();
+    let mut world = DEFAULT_WORLD_NAME.to_owned();
+    let mut command = None;
+    let mut seed = None;
+    let mut generator = None;
+    let mut generator_params = toml::value::Table::new();
+    let mut cache_budget = None;
+    let mut rest = Vec::new();
+    while let Some(arg) = args.next() {
+        let new_command = match &arg[..] {
+            "--world" => {
+                world = next_value(&mut args, &arg)?;
+                None
+            }
+            "--seed" => {
+                seed = Some(parse_seed(&next_value(&mut args, &arg)?)?);
+                None
+            }
+            "--generator" => {
+                generator = Some(next_value(&mut args, &arg)?);
+                None
+            }
+            "--generator-param" => {
+                let (key, value) = parse_generator_param(&next_value(&mut args, &arg)?)?;
+                generator_params.insert(key, value);
+                None
+            }
+            "--cache-size" => {
+                let value = next_value(&mut args, &arg)?;
+                let megabytes: usize = value
+                    .parse()
+                    .map_err(|_| format!("'{}' is not a valid cache size.", value))?;
+                let bytes = megabytes
+                    .checked_mul(1024 * 1024)
+                    .ok_or_else(|| format!("A cache size of {} MB is too large.", value))?;
+                cache_budget = Some(bytes);
+                None
+            }
+            "--list-worlds" => Some(WorldCommand::List),
+            "--create-world" => Some(WorldCommand::Create {
+                name: next_value(&mut args, &arg)?,
+                seed: None,
+                generator: None,
+                generator_params: Default::default(),
+            }),
+            "--copy-world" => Some(WorldCommand::Copy {
+                from: next_value(&mut args, &arg)?,
+                to: next_value(&mut args, &arg)?,
+            }),
+            "--delete-world" => Some(WorldCommand::Delete {
+                name: next_value(&mut args, &arg)?,
+            }),
+            _ => {
+                rest.push(arg);
+                None
+            }
+        };
+        if new_command.is_some() {
+            if command.is_some() {
+                return Err("Only one world command can be given at a time.".to_owned());
+            }
+            command = new_command;
+        }
+    }
+    if let Some(WorldCommand::Create {
+        seed: s,
+        generator: g,
+        generator_params: p,
+        ..
+    }) = &mut command
+    {
+        *s = seed;
+        *g = generator;
+        *p = generator_params;
+    } else if seed.is_some() || generator.is_some() || !generator_params.is_empty() {
+        return Err(
+            "--seed, --generator and --generator-param can only be used with --create-world."
+                .to_owned(),
+        );
+    }
+    Ok(WorldArgs {
+        world,
+        command,
+        cache_budget,
+        rest,
+    })
+}
+
+pub fn run_world_command(library: &WorldLibrary, command: WorldCommand) -> io::Result<()> {
+    match command {
+        WorldCommand::List => {
+            let worlds = library.list()?;
+            if worlds.is_empty() {
+                println!("There are no worlds in {:?}.", library.get_dir());
+            }
+            for world in worlds {
+                println!(
+                    "{} (generator: {}, seed: {})",
+                    world.name, world.generator, world.seed
+                );
+            }
+        }
+        WorldCommand::Create {
+            name,
+            seed,
+            generator,
+            generator_params,
+        } => {
+            let seed = seed.unwrap_or_else(|| rand::random::<u64>() >> 1);
+            let mut manifest = WorldManifest::new(&name, seed);
+            if let Some(generator) = generator {
+                manifest.generator = generator;
+            }
+            manifest.generator_params = generator_params;
+            // Make sure the world can be opened before creating it.
+            world::create_generator(&manifest.generator, seed, &manifest.generator_params)?;
+            library.create(&manifest)?;
+            println!(
+                "Created world '{}' with the {} generator and seed {}.",
+                name, manifest.generator, seed
+            );
+        }
+        WorldCommand::Copy { from, to } => {
+            library.copy(&from, &to)?;
+            println!("Copied world '{}' to '{}'.", from, to);
+        }
+        WorldCommand::Delete { name } => {
+            library.delete(&name)?;
+            println!("Deleted world '{}'.", name);
+        }
+    }
+    Ok(())
+}
+
+/// Opens the world named in the arguments. The default world is created if it does not exist yet,
+/// other worlds have to be created explicitly.
+pub fn open_world(library: &WorldLibrary, args: &WorldArgs) -> io::Result<ChunkStorage> {
+    let name = &args.world;
+    if name == DEFAULT_WORLD_NAME && !library.exists(name) {
+        library.create(&WorldManifest::new(name, 0))?;
+    }
+    let mut world = ChunkStorage::open_world(library, name)?;
+    if let Some(budget) = args.cache_budget {
+        world.set_cache_budget(budget);
+    }
+    Ok(world)
+}
+
+pub fn print_cache_stats(world: &ChunkStorage) {
+    let stats = world.borrow_cache().get_stats();
+    println!(
+        "Chunk cache: {} hits, {} misses, {} evictions.",
+        stats.hits, stats.misses, stats.evictions
+    );
+}
@@ -1,22 +1,31 @@
 use cgmath::InnerSpace;
 use winit::event::VirtualKeyCode;
 
-use crate::render::Camera;
+use crate::render::{Camera, MaterialRegistry};
 use crate::util;
-use crate::world::{self, ChunkStorage};
+use crate::world::{CameraState, ChunkStorage, ChunkStore, WorldManifest};
 
-use std::env;
+use std::io;
 
 pub mod control;
 
 use control::ControlSet;
 
+/// How often edited chunks are written to disk, in seconds.
+const FLUSH_INTERVAL: f32 = 10.0;
+// How often to check if the world generator's config files and the materials file have changed,
+// in seconds.
+const RELOAD_INTERVAL: f32 = 1.0;
+
 pub struct Game {
     camera: Camera,
     world: ChunkStorage,
+    materials: MaterialRegistry,
     controls: ControlSet,
 
     sun_angle: f32,
+    time_since_flush: f32,
+    time_since_reload: f32,
 }
 
 impl Game {
@@ -34,31 +43,80 @@ impl Game {
         set
     }
 
+    /// Opens the default world.
     pub fn new() -> Game {
-        let args: Vec<_> = env::args().collect();
-        let mut result = Game {
-            camera: Camera::new(),
-            world: ChunkStorage::new(),
+        Self::with_world(ChunkStorage::new())
+    }
+
+    /// Creates a game whose world is saved to and loaded from the given store.
+    pub fn with_store(store: Box<dyn ChunkStore>, manifest: WorldManifest) -> io::Result<Game> {
+        Ok(Self::with_world(ChunkStorage::with_store(store, manifest)?))
+    }
+
+    /// Creates a game in the given world, starting where the world was last viewed from.
+    pub fn with_world(world: ChunkStorage) -> Game {
+        let manifest = world.borrow_manifest();
+        let mut camera = Camera::new();
+        camera.origin = manifest.camera.origin.into();
+        camera.heading.0 = manifest.camera.heading;
+        camera.pitch.0 = manifest.camera.pitch;
+        let sun_angle = manifest.sun_angle;
+        Game {
+            camera,
+            world,
+            materials: MaterialRegistry::load_default(),
             controls: Self::make_controls(),
-            sun_angle: 0.0,
-        };
-        if args.len() > 1 {
-            result.camera.origin.x = args[1].parse().unwrap();
-            result.camera.origin.y = args[2].parse().unwrap();
-            result.camera.origin.z = args[3].parse().unwrap();
-            result.camera.heading.0 = args[4].parse().unwrap();
-            result.camera.pitch.0 = args[5].parse().unwrap();
-            result.sun_angle = args[6].parse().unwrap();
-        } else {
-            result.camera.origin.x = -30.0;
-            result.camera.origin.y = -128.0;
-            result.camera.origin.z = 100.0;
+            sun_angle,
+            time_since_flush: 0.0,
+            time_since_reload: 0.0,
         }
-        result
+    }
+
+    /// Saves all edited chunks. Records the current camera position and sun angle in the world's
+    /// manifest and saves it, so that the world can be reopened from the same view.
+    pub fn save_world_state(&mut self) -> io::Result<()> {
+        self.world.flush()?;
+        let manifest = self.world.borrow_manifest_mut();
+        manifest.camera = CameraState {
+            origin: self.camera.origin.into(),
+            heading: self.camera.heading.0,
+            pitch: self.camera.pitch.0,
+        };
+        manifest.sun_angle = self.sun_angle;
+        self.world.save_manifest()
     }
 
     // Called after all controls have been updated.
     pub fn tick(&mut self, dt: f32) {
+        self.time_since_flush += dt;
+        if self.time_since_flush >= FLUSH_INTERVAL {
+            self.time_since_flush = 0.0;
+            if let Err(err) = self.world.flush() {
+                println!("\nWARNING: Failed to save edited chunks.");
+                println!("Caused by: {}", err);
+            }
+        }
+        self.time_since_reload += dt;
+        if self.time_since_reload >= RELOAD_INTERVAL {
+            self.time_since_reload = 0.0;
+            match self.world.reload_generator() {
+                Ok(true) => println!("\nReloaded the world generator config."),
+                Ok(false) => (),
+                Err(err) => {
+                    println!("\nWARNING: Failed to reload the world generator config.");
+                    println!("Caused by: {}", err);
+                }
+            }
+            match self.materials.reload() {
+                Ok(true) => println!("\nReloaded the materials."),
+                Ok(false) => (),
+                Err(err) => {
+                    println!("\nWARNING: Failed to reload the materials.");
+                    println!("Caused by: {}", err);
+                }
+            }
+        }
+
         if self.controls.is_held("sunup") {
             self.sun_angle += dt * 1.0;
         } else if self.controls.is_held("sundown") {
@@ -108,10 +166,18 @@ impl Game {
         &mut self.world
     }
 
+    pub fn borrow_materials(&self) -> &MaterialRegistry {
+        &self.materials
+    }
+
     pub fn borrow_camera(&self) -> &Camera {
         &self.camera
     }
 
+    pub fn borrow_camera_mut(&mut self) -> &mut Camera {
+        &mut self.camera
+    }
+
     pub fn borrow_controls(&self) -> &ControlSet {
         &self.controls
     }
@@ -123,4 +189,8 @@ impl Game {
     pub fn get_sun_angle(&self) -> f32 {
         self.sun_angle
     }
+
+    pub fn set_sun_angle(&mut self, sun_angle: f32) {
+        self.sun_angle = sun_angle;
+    }
 }
@@ -1,106 +1,175 @@
 
+/// Identifies what the values stored in voxels mean. This must be changed whenever that changes
+/// so that chunks stored with an older encoding are detected instead of misread.
+pub const MATERIAL_ENCODING_VERSION: u16 = 2;
+
+/// Voxels store the index of their material in MATERIALS. Air is always the first material, and it
+/// is the only one rays pass through without stopping.
+pub const AIR_MATERIAL: u32 = 0;
+
+/// The values match the PHASE_ constants in GEN_MATERIALS.glsl.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Phase {
+    Solid = 0,
+    /// Liquids can be seen like solid materials, but do not stop rays or movement.
+    Liquid = 1,
+    /// Gases do not stop movement. Air is the only gas that is never drawn.
+    Gas = 2,
+}
+
 #[derive(Clone, PartialEq, Debug)]
 pub struct Material {
+    /// Unique, lowercase name used to refer to the material outside of code.
+    pub name: &'static str,
+    /// From 0 to 255 for each channel.
     pub albedo: (u16, u16, u16),
+    /// From 0 to 255 for each channel, multiplied by the strength of the emission.
     pub emission: (u16, u16, u16),
-    pub solid: bool,
+    /// 0 reflects like a mirror, 1 scatters light evenly in every direction.
+    pub roughness: f32,
+    /// 1 for metals, which tint their reflections with their albedo and scatter no light.
+    pub metalness: f32,
+    /// How much of the color of the material is picked up for every block of it a ray passes
+    /// through. Rays stop at materials with an opacity of 1.
+    pub opacity: f32,
+    /// Index of refraction, which decides how much light is reflected at glancing angles.
+    pub ior: f32,
+    pub phase: Phase,
 }
 
 impl Material {
-    pub fn air() -> Self {
-        Self {
-            albedo: (0, 0, 0),
-            emission: (0, 0, 0),
-            solid: false,
-        }
+    pub fn is_solid(&self) -> bool {
+        self.phase == Phase::Solid
     }
 
-    pub fn black() -> Self {
-        Self {
-            albedo: (0, 0, 0),
-            emission: (0, 0, 0),
-            solid: true,
-        }
-    }
-
-	pub fn add(&mut self, other: &Self) {
-		self.albedo.0 += other.albedo.0;
-		self.albedo.1 += other.albedo.1;
-		self.albedo.2 += other.albedo.2;
-		self.emission.0 += other.emission.0;
-		self.emission.1 += other.emission.1;
-        self.emission.2 += other.emission.2;
-	}
-
-	pub fn divide(&mut self, factor: u16) {
-		self.albedo.0 /= factor;
-		self.albedo.1 /= factor;
-		self.albedo.2 /= factor;
-		self.emission.0 /= factor;
-		self.emission.1 /= factor;
-        self.emission.2 /= factor;
-    }
-
-    pub fn pack(&self) -> u32 {
-        let ar = (self.albedo.0) as u32;
-        let ag = (self.albedo.1) as u32;
-        let ab = (self.albedo.2) as u32;
-        let albedo = ar << 14 | ag << 7 | ab;
-        let solid = if self.solid { 1 } else { 0 };
-        (solid << 15) | albedo
+    pub fn is_liquid(&self) -> bool {
+        self.phase == Phase::Liquid
     }
+}
 
-    pub fn unpack(packed: u32) -> Self {
-        let albedo = (
-            (packed >> 14 & 0x7F) as u16,
-            (packed >> 7 & 0x7F) as u16,
-            (packed >> 0 & 0x7F) as u16,
-        );
-        let emission = (0, 0, 0);
-        let solid = packed >> 15 & 0b1 != 0;
-        Self {
-            albedo,
-            emission,
-            solid,
-        }
-    }
+/// Returns the index of the material with the given name, which is what voxels store.
+pub fn material_by_name(name: &str) -> Option<u32> {
+    MATERIALS
+        .iter()
+        .position(|material| material.name == name)
+        .map(|index| index as u32)
 }
 
 #[rustfmt::skip]
-pub const MATERIALS: [Material; 7] = [
+pub const MATERIALS: [Material; 10] = [
 	Material {
+		name: "air",
 		albedo:   (0, 0, 0),
 		emission: (0, 0, 0),
-		solid: false,
+		roughness: 1.0,
+		metalness: 0.0,
+		opacity: 0.0,
+		ior: 1.0,
+		phase: Phase::Gas,
 	},
 	Material {
-		albedo:   (127, 0, 127),
+		name: "magenta",
+		albedo:   (255, 0, 255),
 		emission: (0, 0, 0),
-		solid: true,
+		roughness: 1.0,
+		metalness: 0.0,
+		opacity: 1.0,
+		ior: 1.5,
+		phase: Phase::Solid,
 	},
 	Material {
-		albedo:   (39, 110, 61),
+		name: "grass",
+		albedo:   (79, 221, 122),
 		emission: (0, 0, 0),
-		solid: true,
+		roughness: 1.0,
+		metalness: 0.0,
+		opacity: 1.0,
+		ior: 1.5,
+		phase: Phase::Solid,
 	},
 	Material {
-		albedo:   (51, 38, 25),
-		emission: (320, 154, 76),
-		solid: true,
+		name: "glowstone",
+		albedo:   (102, 77, 51),
+		emission: (640, 308, 152),
+		roughness: 1.0,
+		metalness: 0.0,
+		opacity: 1.0,
+		ior: 1.5,
+		phase: Phase::Solid,
 	},
 	Material {
-		albedo:   (51, 51, 51),
+		name: "stone",
+		albedo:   (102, 102, 102),
 		emission: (0, 0, 0),
-		solid: true,
+		roughness: 0.9,
+		metalness: 0.0,
+		opacity: 1.0,
+		ior: 1.5,
+		phase: Phase::Solid,
 	},
 	Material {
-		albedo:   (62, 27, 22),
+		name: "dirt",
+		albedo:   (124, 54, 44),
 		emission: (0, 0, 0),
-		solid: true,
+		roughness: 1.0,
+		metalness: 0.0,
+		opacity: 1.0,
+		ior: 1.5,
+		phase: Phase::Solid,
 	},
 	Material {
-		albedo:   (110, 116, 115),
+		name: "snow",
+		albedo:   (221, 233, 231),
 		emission: (0, 0, 0),
-		solid: true,
+		roughness: 0.8,
+		metalness: 0.0,
+		opacity: 1.0,
+		ior: 1.31,
+		phase: Phase::Solid,
+	},
+	Material {
+		name: "water",
+		albedo:   (40, 96, 200),
+		emission: (0, 0, 0),
+		roughness: 0.05,
+		metalness: 0.0,
+		opacity: 0.15,
+		ior: 1.33,
+		phase: Phase::Liquid,
+	},
+	Material {
+		name: "iron",
+		albedo:   (196, 199, 199),
+		emission: (0, 0, 0),
+		roughness: 0.3,
+		metalness: 1.0,
+		opacity: 1.0,
+		ior: 2.95,
+		phase: Phase::Solid,
+	},
+	Material {
+		name: "glass",
+		albedo:   (220, 235, 240),
+		emission: (0, 0, 0),
+		roughness: 0.0,
+		metalness: 0.0,
+		opacity: 0.1,
+		ior: 1.5,
+		phase: Phase::Solid,
 	},
 ];
+
+/// The index of every material, named after it. Use these instead of numbers so that code keeps
+/// working when materials are reordered.
+pub mod materials {
+    pub const AIR: u32 = 0;
+    pub const MAGENTA: u32 = 1;
+    pub const GRASS: u32 = 2;
+    pub const GLOWSTONE: u32 = 3;
+    pub const STONE: u32 = 4;
+    pub const DIRT: u32 = 5;
+    pub const SNOW: u32 = 6;
+    pub const WATER: u32 = 7;
+    pub const IRON: u32 = 8;
+    pub const GLASS: u32 = 9;
+}
@@ -9,6 +9,7 @@ pub enum DescriptorPrototype {
     StorageImage(vk::ImageView, vk::ImageLayout),
     CombinedImageSampler(vk::ImageView, vk::ImageLayout, vk::Sampler),
     UniformBuffer(vk::Buffer, u64, u64),
+    StorageBuffer(vk::Buffer, u64, u64),
 }
 
 impl DescriptorPrototype {
@@ -35,6 +36,7 @@ impl DescriptorPrototype {
                     false
                 }
             }
+            Self::StorageBuffer(..) => matches!(other, Self::StorageBuffer(..)),
         }
     }
 
@@ -43,6 +45,7 @@ impl DescriptorPrototype {
             Self::StorageImage(..) => vk::DescriptorType::STORAGE_IMAGE,
             Self::CombinedImageSampler(..) => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
             Self::UniformBuffer(..) => vk::DescriptorType::UNIFORM_BUFFER,
+            Self::StorageBuffer(..) => vk::DescriptorType::STORAGE_BUFFER,
         }
     }
 
@@ -73,7 +76,8 @@ impl DescriptorPrototype {
                     ..Default::default()
                 })
             }
-            Self::UniformBuffer(buffer, offset, range) => {
+            Self::UniformBuffer(buffer, offset, range)
+            | Self::StorageBuffer(buffer, offset, range) => {
                 DescriptorPayload::BufferInfo(vk::DescriptorBufferInfo {
                     buffer,
                     offset,
@@ -115,10 +115,6 @@ pub struct BufferView<'a, ItemType> {
 }
 
 impl<'a, ItemType> BufferView<'a, ItemType> {
-    pub fn iter(&self) -> std::slice::Iter<ItemType> {
-        self.ptr.iter()
-    }
-
     pub fn iter_mut(&mut self) -> std::slice::IterMut<ItemType> {
         self.ptr.iter_mut()
     }
@@ -211,6 +207,11 @@ impl<ItemType> Buffer<ItemType> {
         DescriptorPrototype::UniformBuffer(self.buffer, 0, self.size)
     }
 
+    /// Like create_dp, for buffers created with the STORAGE_BUFFER usage.
+    pub fn create_storage_dp(&self) -> DescriptorPrototype {
+        DescriptorPrototype::StorageBuffer(self.buffer, 0, self.size)
+    }
+
     pub fn bind_all(&mut self) -> BufferView<ItemType> {
         let slice = unsafe {
             let ptr = self
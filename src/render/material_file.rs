@@ -0,0 +1,531 @@
+//! Reads materials files like misc/materials.csv. build.rs also includes this file to generate
+//! the Rust and GLSL code describing the built in materials, so it must not use anything from the
+//! rest of the crate.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// The columns every materials file must have, in order. Some names appear more than once because
+/// colors are split into one column per channel.
+const COLUMNS: [&str; 14] = [
+    "id",
+    "name",
+    "albedo rrr",
+    "ggg",
+    "bbb",
+    "emission rrr",
+    "ggg",
+    "bbb",
+    "strength",
+    "roughness",
+    "metalness",
+    "opacity",
+    "ior",
+    "phase",
+];
+
+/// The phases a material can be in, as written in materials files and as named in the generated
+/// Phase enum. A material's phase is its index in this list.
+const PHASES: [(&str, &str); 3] = [("solid", "Solid"), ("liquid", "Liquid"), ("gas", "Gas")];
+const PHASE_GAS: u32 = 2;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaterialDefinition {
+    pub name: String,
+    pub albedo: (u16, u16, u16),
+    /// Already multiplied by the strength column.
+    pub emission: (u16, u16, u16),
+    pub roughness: f32,
+    pub metalness: f32,
+    pub opacity: f32,
+    pub ior: f32,
+    /// The same value as the matching variant of the generated Phase enum.
+    pub phase: u32,
+}
+
+/// Collects problems with a materials file so that all of them can be reported at once, each one
+/// pointing at the line it was found on.
+struct Diagnostics<'a> {
+    file_name: &'a str,
+    errors: Vec<String>,
+}
+
+impl<'a> Diagnostics<'a> {
+    fn error(&mut self, line: u64, message: String) {
+        self.errors
+            .push(format!("{}:{}: {}", self.file_name, line, message));
+    }
+}
+
+/// Drops the empty field left behind by a trailing comma.
+fn trim_trailing_comma(record: &csv::StringRecord) -> Vec<&str> {
+    let mut fields: Vec<&str> = record.iter().collect();
+    if fields.len() > 1 && fields.last() == Some(&"") {
+        fields.pop();
+    }
+    fields
+}
+
+fn parse_integer(from: &str, column: &str, max: u16) -> Result<u16, String> {
+    let value: u16 = from
+        .parse()
+        .map_err(|_| format!("{} must be a whole number, got {:?}.", column, from))?;
+    if value > max {
+        return Err(format!(
+            "{} must be between 0 and {}, got {}.",
+            column, max, value
+        ));
+    }
+    Ok(value)
+}
+
+fn parse_float(from: &str, column: &str, min: f32, max: f32) -> Result<f32, String> {
+    let value: f32 = from
+        .parse()
+        .map_err(|_| format!("{} must be a number, got {:?}.", column, from))?;
+    // Written so that NaN is rejected as well.
+    if !(value >= min && value <= max) {
+        return Err(format!(
+            "{} must be between {} and {}, got {}.",
+            column, min, max, value
+        ));
+    }
+    Ok(value)
+}
+
+fn parse_phase(from: &str) -> Result<u32, String> {
+    PHASES
+        .iter()
+        .position(|(name, _)| *name == from)
+        .map(|index| index as u32)
+        .ok_or_else(|| format!("phase must be one of solid, liquid or gas, got {:?}.", from))
+}
+
+/// Names end up in code, so they have to be valid identifiers once uppercased.
+fn check_name(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_ascii_lowercase())
+        && chars.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "name must start with a lowercase letter and only contain lowercase letters, digits \
+            and underscores, got {:?}.",
+            name
+        ))
+    }
+}
+
+fn parse_material(fields: &[&str]) -> Result<MaterialDefinition, String> {
+    let rgb = |start: usize, name: &str| -> Result<(u16, u16, u16), String> {
+        Ok((
+            parse_integer(fields[start], &format!("{} red", name), 0xFF)?,
+            parse_integer(fields[start + 1], &format!("{} green", name), 0xFF)?,
+            parse_integer(fields[start + 2], &format!("{} blue", name), 0xFF)?,
+        ))
+    };
+    let albedo = rgb(2, "albedo")?;
+    let emission = {
+        let (r, g, b) = rgb(5, "emission")?;
+        let strength = parse_integer(fields[8], "strength", 9)?;
+        (r * strength, g * strength, b * strength)
+    };
+    check_name(fields[1])?;
+    Ok(MaterialDefinition {
+        name: fields[1].to_owned(),
+        albedo,
+        emission,
+        roughness: parse_float(fields[9], "roughness", 0.0, 1.0)?,
+        metalness: parse_float(fields[10], "metalness", 0.0, 1.0)?,
+        opacity: parse_float(fields[11], "opacity", 0.0, 1.0)?,
+        ior: parse_float(fields[12], "ior", 1.0, 4.0)?,
+        phase: parse_phase(fields[13])?,
+    })
+}
+
+/// Parses the contents of a materials file, returning the materials ordered by id. If anything is
+/// wrong with the file, every problem found is returned as a "file:line: message" string instead.
+pub fn parse_materials(
+    file_name: &str,
+    contents: &str,
+) -> Result<Vec<MaterialDefinition>, Vec<String>> {
+    let mut diagnostics = Diagnostics {
+        file_name,
+        errors: Vec::new(),
+    };
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(contents.as_bytes());
+
+    // Material id -> (line it was defined on, material). Materials that failed to parse are kept as
+    // None so that they are not reported as missing as well.
+    let mut by_id: HashMap<u16, (u64, Option<MaterialDefinition>)> = HashMap::new();
+    let mut lines_by_name: HashMap<String, u64> = HashMap::new();
+    let mut header_checked = false;
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(err) => {
+                let line = err.position().map_or(0, |position| position.line());
+                diagnostics.error(line, format!("Failed to read row: {}", err));
+                continue;
+            }
+        };
+        let line = record.position().map_or(0, |position| position.line());
+        let fields = trim_trailing_comma(&record);
+        if !header_checked {
+            header_checked = true;
+            for (index, column) in fields.iter().enumerate() {
+                match COLUMNS.get(index) {
+                    Some(expected) if expected == column => (),
+                    Some(expected) => diagnostics.error(
+                        line,
+                        format!("Unknown column {:?}, expected {:?}.", column, expected),
+                    ),
+                    None => diagnostics.error(line, format!("Unknown column {:?}.", column)),
+                }
+            }
+            for missing in COLUMNS.iter().skip(fields.len()) {
+                diagnostics.error(line, format!("Missing column {:?}.", missing));
+            }
+            continue;
+        }
+        if fields.len() != COLUMNS.len() {
+            diagnostics.error(
+                line,
+                format!(
+                    "Expected {} columns, found {}.",
+                    COLUMNS.len(),
+                    fields.len()
+                ),
+            );
+            continue;
+        }
+        let id = match parse_integer(fields[0], "id", 0xFFFF) {
+            Ok(id) => id,
+            Err(message) => {
+                diagnostics.error(line, message);
+                continue;
+            }
+        };
+        let material = match parse_material(&fields) {
+            Ok(material) => Some(material),
+            Err(message) => {
+                diagnostics.error(line, format!("Material {}: {}", id, message));
+                None
+            }
+        };
+        if let Some(material) = &material {
+            if let Some(&first_line) = lines_by_name.get(&material.name) {
+                diagnostics.error(
+                    line,
+                    format!(
+                        "The name {:?} is already used by the material on line {}.",
+                        material.name, first_line
+                    ),
+                );
+            } else {
+                lines_by_name.insert(material.name.clone(), line);
+            }
+        }
+        if let Some((first_line, _)) = by_id.get(&id) {
+            diagnostics.error(
+                line,
+                format!("Material {} is already defined on line {}.", id, first_line),
+            );
+            continue;
+        }
+        by_id.insert(id, (line, material));
+    }
+    if !header_checked {
+        diagnostics.error(1, "The file is empty.".to_owned());
+    }
+
+    let count = by_id.keys().max().map_or(0, |max| *max as usize + 1);
+    let mut materials = Vec::with_capacity(count);
+    for id in 0..count as u16 {
+        match by_id.remove(&id) {
+            Some((_, None)) => (),
+            Some((line, Some(material))) => {
+                if id == 0 && (material.phase != PHASE_GAS || material.opacity != 0.0) {
+                    diagnostics.error(
+                        line,
+                        "Material 0 is air, so it must be a gas with an opacity of 0.".to_owned(),
+                    );
+                }
+                materials.push(material);
+            }
+            None => {
+                // Point at the line of the next material that does exist.
+                let line = (id..count as u16)
+                    .filter_map(|later| by_id.get(&later))
+                    .map(|(line, _)| *line)
+                    .next()
+                    .unwrap_or(0);
+                diagnostics.error(
+                    line,
+                    format!("Material {} is missing, ids must not have gaps.", id),
+                );
+            }
+        }
+    }
+    if header_checked && count == 0 && diagnostics.errors.is_empty() {
+        diagnostics.error(1, "At least the air material must be defined.".to_owned());
+    }
+
+    if diagnostics.errors.is_empty() {
+        Ok(materials)
+    } else {
+        Err(diagnostics.errors)
+    }
+}
+
+// Only used by build.rs.
+#[allow(dead_code)]
+pub fn generate_glsl() -> String {
+    String::from(
+        r#"const uint PHASE_SOLID = 0;
+const uint PHASE_LIQUID = 1;
+const uint PHASE_GAS = 2;
+
+// Matches MaterialData on the CPU side, see GEN_MATERIALS.rs for what each field means.
+struct Material {
+    vec3 albedo;
+    uint phase;
+    vec3 emission;
+    float roughness;
+    float metalness;
+    float opacity;
+    float ior;
+};
+"#,
+    )
+}
+
+// Only used by build.rs.
+#[allow(dead_code)]
+pub fn generate_rust(materials: &[MaterialDefinition]) -> String {
+    let mut code = String::from(
+        r#"
+/// Identifies what the values stored in voxels mean. This must be changed whenever that changes
+/// so that chunks stored with an older encoding are detected instead of misread.
+pub const MATERIAL_ENCODING_VERSION: u16 = 2;
+
+/// Voxels store the index of their material in MATERIALS. Air is always the first material, and it
+/// is the only one rays pass through without stopping.
+pub const AIR_MATERIAL: u32 = 0;
+
+/// The values match the PHASE_ constants in GEN_MATERIALS.glsl.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Phase {
+    Solid = 0,
+    /// Liquids can be seen like solid materials, but do not stop rays or movement.
+    Liquid = 1,
+    /// Gases do not stop movement. Air is the only gas that is never drawn.
+    Gas = 2,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Material {
+    /// Unique, lowercase name used to refer to the material outside of code.
+    pub name: &'static str,
+    /// From 0 to 255 for each channel.
+    pub albedo: (u16, u16, u16),
+    /// From 0 to 255 for each channel, multiplied by the strength of the emission.
+    pub emission: (u16, u16, u16),
+    /// 0 reflects like a mirror, 1 scatters light evenly in every direction.
+    pub roughness: f32,
+    /// 1 for metals, which tint their reflections with their albedo and scatter no light.
+    pub metalness: f32,
+    /// How much of the color of the material is picked up for every block of it a ray passes
+    /// through. Rays stop at materials with an opacity of 1.
+    pub opacity: f32,
+    /// Index of refraction, which decides how much light is reflected at glancing angles.
+    pub ior: f32,
+    pub phase: Phase,
+}
+
+impl Material {
+    pub fn is_solid(&self) -> bool {
+        self.phase == Phase::Solid
+    }
+
+    pub fn is_liquid(&self) -> bool {
+        self.phase == Phase::Liquid
+    }
+}
+
+/// Returns the index of the material with the given name, which is what voxels store.
+pub fn material_by_name(name: &str) -> Option<u32> {
+    MATERIALS
+        .iter()
+        .position(|material| material.name == name)
+        .map(|index| index as u32)
+}
+
+#[rustfmt::skip]
+"#,
+    );
+    writeln!(
+        code,
+        "pub const MATERIALS: [Material; {}] = [",
+        materials.len()
+    )
+    .unwrap();
+    for material in materials {
+        writeln!(
+            code,
+            concat!(
+                "\tMaterial {{\n",
+                "\t\tname: {:?},\n",
+                "\t\talbedo:   ({}, {}, {}),\n",
+                "\t\temission: ({}, {}, {}),\n",
+                "\t\troughness: {:?},\n",
+                "\t\tmetalness: {:?},\n",
+                "\t\topacity: {:?},\n",
+                "\t\tior: {:?},\n",
+                "\t\tphase: Phase::{},\n",
+                "\t}},",
+            ),
+            material.name,
+            material.albedo.0,
+            material.albedo.1,
+            material.albedo.2,
+            material.emission.0,
+            material.emission.1,
+            material.emission.2,
+            material.roughness,
+            material.metalness,
+            material.opacity,
+            material.ior,
+            PHASES[material.phase as usize].1,
+        )
+        .unwrap();
+    }
+    writeln!(code, "];").unwrap();
+
+    writeln!(
+        code,
+        "\n/// The index of every material, named after it. Use these instead of numbers so that code \
+        keeps\n/// working when materials are reordered.\npub mod materials {{"
+    )
+    .unwrap();
+    for (index, material) in materials.iter().enumerate() {
+        writeln!(
+            code,
+            "    pub const {}: u32 = {};",
+            material.name.to_uppercase(),
+            index
+        )
+        .unwrap();
+    }
+    writeln!(code, "}}").unwrap();
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_fixture(name: &str) -> Result<Vec<MaterialDefinition>, Vec<String>> {
+        let path = format!(
+            "{}/misc/material_fixtures/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            name
+        );
+        let contents = std::fs::read_to_string(&path).unwrap();
+        parse_materials(name, &contents)
+    }
+
+    fn assert_errors(name: &str, expected: &[&str]) {
+        let errors = parse_fixture(name).unwrap_err();
+        for expected in expected {
+            assert!(
+                errors.iter().any(|error| error.starts_with(expected)),
+                "Expected an error starting with {:?}, got {:?}",
+                expected,
+                errors
+            );
+        }
+        assert_eq!(errors.len(), expected.len(), "{:?}", errors);
+    }
+
+    #[test]
+    fn real_materials() {
+        let path = format!("{}/misc/materials.csv", env!("CARGO_MANIFEST_DIR"));
+        let contents = std::fs::read_to_string(&path).unwrap();
+        parse_materials("materials.csv", &contents).unwrap();
+    }
+
+    #[test]
+    fn good() {
+        let materials = parse_fixture("good.csv").unwrap();
+        let names: Vec<_> = materials.iter().map(|m| m.name.as_str()).collect();
+        // Rows are allowed to be out of order.
+        assert_eq!(names, ["air", "rock", "lava", "water"]);
+        assert_eq!(materials[2].emission, (255 * 3, 64 * 3, 0));
+        assert_eq!(materials[3].phase, 1);
+
+        let rust = generate_rust(&materials);
+        assert!(rust.contains("pub const MATERIALS: [Material; 4] = ["));
+        assert!(rust.contains("\t\tname: \"lava\",\n\t\talbedo:   (200, 40, 0),\n"));
+        assert!(rust.contains("\t\tior: 1.33,\n\t\tphase: Phase::Liquid,\n"));
+        assert!(rust.contains("    pub const LAVA: u32 = 2;\n"));
+    }
+
+    #[test]
+    fn bad_columns() {
+        assert_errors(
+            "bad_columns.csv",
+            &[
+                "bad_columns.csv:1: Unknown column \"shininess\", expected \"roughness\".",
+                "bad_columns.csv:1: Missing column \"phase\".",
+                "bad_columns.csv:3: Expected 14 columns, found 13.",
+            ],
+        );
+    }
+
+    #[test]
+    fn bad_ids() {
+        assert_errors(
+            "bad_ids.csv",
+            &[
+                "bad_ids.csv:4: Material 1 is already defined on line 3.",
+                "bad_ids.csv:5: id must be a whole number",
+                "bad_ids.csv:6: Material 2 is missing",
+                "bad_ids.csv:7: Material 4 is missing",
+            ],
+        );
+    }
+
+    #[test]
+    fn bad_values() {
+        assert_errors(
+            "bad_values.csv",
+            &[
+                "bad_values.csv:2: Material 0 is air",
+                "bad_values.csv:3: Material 1: albedo green must be between 0 and 255, got 256.",
+                "bad_values.csv:4: Material 2: strength must be between 0 and 9, got 10.",
+                "bad_values.csv:5: Material 3: roughness must be between 0 and 1, got 1.5.",
+                "bad_values.csv:6: Material 4: ior must be between 1 and 4, got 0.5.",
+                "bad_values.csv:7: Material 5: phase must be one of solid, liquid or gas",
+                "bad_values.csv:8: Material 6: opacity must be a number",
+            ],
+        );
+    }
+
+    #[test]
+    fn bad_names() {
+        assert_errors(
+            "bad_names.csv",
+            &[
+                "bad_names.csv:3: Material 1: name must start with a lowercase letter",
+                "bad_names.csv:4: Material 2: name must start with a lowercase letter",
+                "bad_names.csv:5: The name \"air\" is already used by the material on line 2.",
+            ],
+        );
+    }
+}
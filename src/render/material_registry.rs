@@ -0,0 +1,232 @@
+use super::material_file::{self, MaterialDefinition};
+use super::MATERIALS;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Where the materials file is, relative to the directory the game is run from. It is watched for
+/// changes while the game runs.
+pub const MATERIALS_FILE_PATH: &str = "misc/materials.csv";
+
+/// Looks for MATERIALS_FILE_PATH in the working directory and then in the directories containing
+/// the executable, so that the file is found both when running from the repository and when
+/// running an executable inside target/.
+pub fn find_materials_file() -> Option<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Ok(dir) = std::env::current_dir() {
+        dirs.push(dir);
+    }
+    if let Ok(exe) = std::env::current_exe() {
+        dirs.extend(exe.ancestors().skip(1).map(Path::to_owned));
+    }
+    dirs.into_iter()
+        .map(|dir| dir.join(MATERIALS_FILE_PATH))
+        .find(|path| path.is_file())
+}
+
+struct MaterialsFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+/// The materials used for rendering, loaded from a materials file that can be edited while the game
+/// is running. Voxels and worldgen configs store the ids of materials, so the file must define the
+/// same materials as the one the game was built with, but everything about how they look can
+/// change.
+pub struct MaterialRegistry {
+    // None if the registry only uses the built in materials.
+    file: Option<MaterialsFile>,
+    materials: Vec<MaterialDefinition>,
+    version: u32,
+}
+
+impl MaterialRegistry {
+    /// The materials the game was built with.
+    pub fn builtin() -> MaterialRegistry {
+        let materials = MATERIALS
+            .iter()
+            .map(|material| MaterialDefinition {
+                name: material.name.to_owned(),
+                albedo: material.albedo,
+                emission: material.emission,
+                roughness: material.roughness,
+                metalness: material.metalness,
+                opacity: material.opacity,
+                ior: material.ior,
+                phase: material.phase as u32,
+            })
+            .collect();
+        MaterialRegistry {
+            file: None,
+            materials,
+            version: 0,
+        }
+    }
+
+    /// Loads the materials from the given file, which is checked for changes by reload.
+    pub fn load(path: &Path) -> io::Result<MaterialRegistry> {
+        let modified = fs::metadata(path)?.modified().ok();
+        let materials = Self::read(path)?;
+        Ok(MaterialRegistry {
+            file: Some(MaterialsFile {
+                path: path.to_owned(),
+                modified,
+            }),
+            materials,
+            version: 0,
+        })
+    }
+
+    /// Loads the materials from the file found by find_materials_file. Until that file is valid,
+    /// the built in materials are used instead. If there is no such file, only the built in
+    /// materials are used.
+    pub fn load_default() -> MaterialRegistry {
+        let mut registry = Self::builtin();
+        let path = match find_materials_file() {
+            Some(path) => path,
+            None => {
+                println!(
+                    "WARNING: Could not find {} in the working directory or next to the \
+                    executable, using the built in materials instead.",
+                    MATERIALS_FILE_PATH
+                );
+                return registry;
+            }
+        };
+        registry.file = Some(MaterialsFile {
+            path,
+            modified: None,
+        });
+        if let Err(err) = registry.reload() {
+            println!("WARNING: Failed to load materials, using the built in ones instead.");
+            println!("Caused by: {}", err);
+        }
+        registry
+    }
+
+    fn read(path: &Path) -> io::Result<Vec<MaterialDefinition>> {
+        let contents = fs::read_to_string(path)?;
+        let materials = material_file::parse_materials(&path.to_string_lossy(), &contents)
+            .map_err(|errors| io::Error::new(io::ErrorKind::InvalidData, errors.join("\n")))?;
+        if materials.len() != MATERIALS.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} defines {} materials, but the game was built with {}. Adding or removing \
+                    materials requires rebuilding the game.",
+                    path.display(),
+                    materials.len(),
+                    MATERIALS.len()
+                ),
+            ));
+        }
+        for (id, (material, builtin)) in materials.iter().zip(MATERIALS.iter()).enumerate() {
+            if material.name != builtin.name {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Material {} is called {:?} in {}, but the game was built with it being \
+                        {:?}. Changing which material an id refers to requires rebuilding the game.",
+                        id,
+                        material.name,
+                        path.display(),
+                        builtin.name
+                    ),
+                ));
+            }
+        }
+        Ok(materials)
+    }
+
+    /// Loads the materials file again if it has changed since it was last loaded. Returns true if
+    /// the materials changed. If the file is invalid the old materials are kept.
+    pub fn reload(&mut self) -> io::Result<bool> {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return Ok(false),
+        };
+        let modified = fs::metadata(&file.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified == file.modified {
+            return Ok(false);
+        }
+        // Remember the new time even if loading fails, so that the same error is not reported
+        // over and over again.
+        file.modified = modified;
+        self.materials = Self::read(&file.path)?;
+        self.version += 1;
+        Ok(true)
+    }
+
+    /// Changes every time the materials are reloaded.
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    /// Indexed by material id.
+    pub fn borrow_materials(&self) -> &[MaterialDefinition] {
+        &self.materials
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn repo_materials_path() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join(MATERIALS_FILE_PATH)
+    }
+
+    #[test]
+    fn builtin_matches_file() {
+        let registry = MaterialRegistry::load(&repo_materials_path()).unwrap();
+        assert!(registry.borrow_materials() == MaterialRegistry::builtin().borrow_materials());
+    }
+
+    #[test]
+    fn find_file_from_executable() {
+        // Test executables live in target/, below the repository.
+        assert_eq!(find_materials_file(), Some(repo_materials_path()));
+    }
+
+    #[test]
+    fn reload() {
+        let original = fs::read_to_string(repo_materials_path()).unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "raytrace-materials-{:08X}.csv",
+            rand::thread_rng().next_u32()
+        ));
+        let write = |contents: &str, seconds| {
+            fs::write(&path, contents).unwrap();
+            let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(seconds);
+            let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+            file.set_modified(time).unwrap();
+        };
+        write(&original, 1000);
+        let mut registry = MaterialRegistry::load(&path).unwrap();
+        assert!(!registry.reload().unwrap());
+
+        let stone = original
+            .lines()
+            .find(|line| line.starts_with("04,"))
+            .unwrap();
+        let red_stone = stone.replacen("102, 102, 102", "200, 000, 000", 1);
+        write(&original.replace(stone, &red_stone), 2000);
+        assert!(registry.reload().unwrap());
+        assert_eq!(registry.get_version(), 1);
+        assert_eq!(registry.borrow_materials()[4].albedo, (200, 0, 0));
+
+        // Invalid files and files whose ids mean something else keep the old materials.
+        write(&original.replace(" stone,", " granite,"), 3000);
+        assert!(registry.reload().is_err());
+        write(&original.replace(stone, ""), 4000);
+        assert!(registry.reload().is_err());
+        assert!(!registry.reload().unwrap());
+        assert_eq!(registry.get_version(), 1);
+        assert_eq!(registry.borrow_materials()[4].albedo, (200, 0, 0));
+        fs::remove_file(&path).unwrap();
+    }
+}
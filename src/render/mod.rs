@@ -4,10 +4,14 @@ use winit::event_loop::EventLoop;
 mod GEN_MATERIALS;
 pub mod constants;
 pub(self) mod general;
+mod material_file;
+mod material_registry;
 pub(self) mod pipeline;
 pub(self) mod util;
 
 pub use general::core::Core;
+pub use material_file::MaterialDefinition;
+pub use material_registry::{find_materials_file, MaterialRegistry, MATERIALS_FILE_PATH};
 pub use pipeline::Pipeline;
 pub use GEN_MATERIALS::*;
 
@@ -80,6 +80,7 @@ fn generate_raytrace_ds_prototypes(
         //
         render_data.blue_noise.create_dp(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
         render_data.raytrace_uniform_data_buffer.create_dp(),
+        render_data.material_data_buffer.create_storage_dp(),
     ]]
 }
 
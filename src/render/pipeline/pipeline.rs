@@ -26,6 +26,8 @@ pub struct Pipeline {
     render_data: RenderData,
     descriptor_collection: DescriptorCollection,
     tum: TerrainUploadManager,
+    // The version of the material registry that was last uploaded.
+    material_version: u32,
 
     denoise_stage: Stage,
     finalize_stage: Stage,
@@ -48,6 +50,7 @@ impl Pipeline {
         render_data.initialize(game);
         let descriptor_collection = DescriptorCollection::create(core.clone(), &render_data);
         let tum = TerrainUploadManager::new(Rc::clone(&core));
+        let material_version = game.borrow_materials().get_version();
 
         let denoise_stage = shaders::create_denoise_stage(core.clone(), &descriptor_collection);
         let finalize_stage = shaders::create_finalize_stage(core.clone(), &descriptor_collection);
@@ -66,6 +69,7 @@ impl Pipeline {
             render_data,
             descriptor_collection,
             tum,
+            material_version,
 
             denoise_stage,
             finalize_stage,
@@ -171,12 +175,15 @@ impl Pipeline {
                 .expect("Failed to reset fence.");
         }
 
+        let materials = game.borrow_materials();
+        if materials.get_version() != self.material_version {
+            self.material_version = materials.get_version();
+            self.render_data.upload_materials(materials);
+        }
+
         let camera = game.borrow_camera();
-        self.tum.request_move_towards((
-            camera.origin.x as isize,
-            0,
-            camera.origin.z as isize,
-        ));
+        self.tum
+            .request_move_towards((camera.origin.x as isize, 0, camera.origin.z as isize));
 
         let mut upload_commands = CommandBuffer::create_single(Rc::clone(&self.core));
         upload_commands.begin_one_time_submit();
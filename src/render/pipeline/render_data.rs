@@ -1,14 +1,14 @@
-use super::structs::RaytraceUniformData;
+use super::structs::{MaterialData, RaytraceUniformData};
 use crate::game::Game;
-use crate::render::constants::*;
 use crate::render::general::command_buffer::CommandBuffer;
 use crate::render::general::core::Core;
 use crate::render::general::structures::{
     Buffer, BufferWrapper, DataDestination, ExtentWrapper, ImageOptions, ImageWrapper,
     SampledImage, SamplerOptions, StorageImage,
 };
+use crate::render::{constants::*, MaterialRegistry, MATERIALS};
 use crate::util::{self, prelude::*};
-use crate::world::ChunkStorage;
+use crate::world::{ChunkStorage, PackedChunk};
 use ash::vk;
 use std::rc::Rc;
 
@@ -32,6 +32,7 @@ pub struct RenderData {
 
     pub raytrace_uniform_data: RaytraceUniformData,
     pub raytrace_uniform_data_buffer: Buffer<RaytraceUniformData>,
+    pub material_data_buffer: Buffer<MaterialData>,
 }
 
 impl RenderData {
@@ -162,6 +163,37 @@ impl RenderData {
         }
     }
 
+    // Material registries always have as many materials as the game was built with.
+    fn create_material_data_buffer(core: Rc<Core>) -> Buffer<MaterialData> {
+        Buffer::create(
+            core,
+            "material_data",
+            MATERIALS.len() as u64,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+        )
+    }
+
+    /// Must only be called while no frame that uses the material buffer is being rendered.
+    pub fn upload_materials(&mut self, registry: &MaterialRegistry) {
+        let mut buffer_content = self.material_data_buffer.bind_all();
+        let materials = registry.borrow_materials().iter();
+        for (data, material) in buffer_content.iter_mut().zip(materials) {
+            let to_color = |(r, g, b): (u16, u16, u16)| {
+                [r as f32 / 255.0, g as f32 / 255.0, b as f32 / 255.0].into()
+            };
+            *data = MaterialData {
+                albedo: to_color(material.albedo),
+                phase: material.phase,
+                emission: to_color(material.emission),
+                roughness: material.roughness,
+                metalness: material.metalness,
+                opacity: material.opacity,
+                ior: material.ior,
+                _padding0: 0,
+            };
+        }
+    }
+
     pub fn create(core: Rc<Core>) -> RenderData {
         let rgba16_unorm = vk::Format::R16G16B16A16_UNORM;
         let rgba8_unorm = vk::Format::R8G8B8A8_UNORM;
@@ -197,6 +229,7 @@ impl RenderData {
                 1,
                 vk::BufferUsageFlags::UNIFORM_BUFFER,
             ),
+            material_data_buffer: Self::create_material_data_buffer(core.clone()),
         }
     }
 
@@ -218,6 +251,16 @@ impl RenderData {
         let mut minefield_buffer_data = minefield_buffer.bind_all();
         let mut gen_time = 0;
         let mut copy_time = 0;
+        // Start loading all the chunks at once so that they are loaded in parallel.
+        for chunk_coord in util::coord_iter_3d(ROOT_CHUNK_SIZE) {
+            world.request_chunk(
+                &chunk_coord
+                    .signed()
+                    .sub((ROOT_CHUNK_SIZE as isize / 2).repeat()),
+            );
+        }
+        // Uploaded in place of chunks that failed to load, the same way TerrainUploadManager does.
+        let empty_chunk = PackedChunk::Empty;
         for chunk_coord in util::coord_iter_3d(ROOT_CHUNK_SIZE) {
             let world_coord = chunk_coord.signed().sub((
                 (ROOT_CHUNK_SIZE as isize / 2),
@@ -225,8 +268,14 @@ impl RenderData {
                 (ROOT_CHUNK_SIZE as isize / 2),
             ));
             let timer = std::time::Instant::now();
-            let chunk =
-                world.borrow_packed_chunk_data(&(world_coord.0, world_coord.1, world_coord.2));
+            let chunk = match world.borrow_packed_chunk(&world_coord) {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    println!("WARNING: Failed to load chunk {:?}.", world_coord);
+                    println!("Caused by: {}", err);
+                    &empty_chunk
+                }
+            };
             gen_time += timer.elapsed().as_millis();
             let timer = std::time::Instant::now();
             chunk.copy_materials(
@@ -267,6 +316,7 @@ impl RenderData {
     }
 
     pub fn initialize(&mut self, game: &mut Game) {
+        self.upload_materials(game.borrow_materials());
         let world = game.borrow_world_mut();
         let (material_buffer, minefield_buffer) = self.make_world_upload_buffers(world);
 
@@ -30,6 +30,21 @@ pub struct RaytraceUniformData {
     pub _padding11: u32,
 }
 
+/// How a material from the MaterialRegistry is laid out in the material buffer. Voxels store the
+/// index of their material in it.
+#[repr(C)]
+#[derive(Clone, Debug)]
+pub struct MaterialData {
+    pub albedo: Vector3<f32>,
+    pub phase: u32,
+    pub emission: Vector3<f32>,
+    pub roughness: f32,
+    pub metalness: f32,
+    pub opacity: f32,
+    pub ior: f32,
+    pub _padding0: u32,
+}
+
 #[repr(C)]
 #[derive(Clone, Debug)]
 pub struct DenoisePushData {
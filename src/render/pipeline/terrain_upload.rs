@@ -4,10 +4,13 @@ use crate::render::general::core::Core;
 use crate::render::general::structures::Buffer;
 use crate::render::pipeline::render_data::RenderData;
 use crate::util::{self, prelude::*};
-use crate::world::ChunkStorage;
+use crate::world::{ChunkStorage, PackedChunk};
 use ash::vk;
 use std::rc::Rc;
 
+// How many slices ahead of the camera to load chunks for.
+const PREFETCH_SLICES: usize = SLICES_PER_CHUNK * 2;
+
 /// Upon consuming this request, the next slice along the specified axis will be uploaded.
 struct TerrainUploadRequest {
     origin: SignedCoord3D,
@@ -18,6 +21,30 @@ struct TerrainUploadRequest {
     new_position: Position,
 }
 
+impl TerrainUploadRequest {
+    /// Which pieces the slice is made of, one for every chunk it touches.
+    fn piece_offsets(&self) -> impl Iterator<Item = Coord3D> {
+        let axis = self.axis;
+        util::coord_iter_2d(ROOT_CHUNK_SIZE + 1).map(move |(d1, d2)| match axis {
+            Axis::X => (0, d1, d2),
+            Axis::Y => (d1, 0, d2),
+            Axis::Z => (d1, d2, 0),
+        })
+    }
+
+    /// Returns which chunk the given piece of the slice is copied from.
+    fn get_chunk_coord(&self, piece_offset: Coord3D) -> SignedCoord3D {
+        let chunk_offset = self.num_slices.shrink(SLICES_PER_CHUNK);
+        piece_offset.add(chunk_offset).signed().add(self.origin)
+    }
+
+    /// Returns the coordinates of all the chunks needed to complete the request.
+    fn get_required_chunks(&self) -> impl Iterator<Item = SignedCoord3D> + '_ {
+        self.piece_offsets()
+            .map(move |piece_offset| self.get_chunk_coord(piece_offset))
+    }
+}
+
 // This stores the origin of the current region and how many slices of the next region have been
 // loaded.
 #[derive(Clone)]
@@ -27,6 +54,66 @@ struct Position {
 }
 
 impl Position {
+    /// Returns the request that loads the next slice along the given axis.
+    fn next_increase(&self, axis: Axis) -> TerrainUploadRequest {
+        // Load the next slice then increment the number of loaded slices.
+        let mut new_position = self.clone();
+        let num_slices = match axis {
+            Axis::X => &mut new_position.num_loaded_slices.0,
+            Axis::Y => &mut new_position.num_loaded_slices.1,
+            Axis::Z => &mut new_position.num_loaded_slices.2,
+        };
+        let coord = match axis {
+            Axis::X => &mut new_position.origin.0,
+            Axis::Y => &mut new_position.origin.1,
+            Axis::Z => &mut new_position.origin.2,
+        };
+        *num_slices += 1;
+        if *num_slices == ROOT_BLOCK_SIZE / SLICE_SIZE {
+            *num_slices = 0;
+            *coord += (ROOT_BLOCK_SIZE / CHUNK_SIZE) as isize;
+        }
+        // This makes it load the data from the next region instead of the current region.
+        let origin_offset = match axis {
+            Axis::X => (ROOT_CHUNK_SIZE, 0, 0),
+            Axis::Y => (0, ROOT_CHUNK_SIZE, 0),
+            Axis::Z => (0, 0, ROOT_CHUNK_SIZE),
+        };
+        TerrainUploadRequest {
+            origin: self.origin.add(origin_offset.signed()),
+            num_slices: self.num_loaded_slices,
+            axis,
+            new_position,
+        }
+    }
+
+    /// Returns the request that loads the previous slice along the given axis.
+    fn next_decrease(&self, axis: Axis) -> TerrainUploadRequest {
+        // Rewind the coordinate to the previous slice and then load it from the current region.
+        let mut new_position = self.clone();
+        let num_slices = match axis {
+            Axis::X => &mut new_position.num_loaded_slices.0,
+            Axis::Y => &mut new_position.num_loaded_slices.1,
+            Axis::Z => &mut new_position.num_loaded_slices.2,
+        };
+        let coord = match axis {
+            Axis::X => &mut new_position.origin.0,
+            Axis::Y => &mut new_position.origin.1,
+            Axis::Z => &mut new_position.origin.2,
+        };
+        if *num_slices == 0 {
+            *num_slices = ROOT_BLOCK_SIZE / SLICE_SIZE;
+            *coord -= (ROOT_BLOCK_SIZE / CHUNK_SIZE) as isize;
+        }
+        *num_slices -= 1;
+        TerrainUploadRequest {
+            origin: new_position.origin,
+            num_slices: new_position.num_loaded_slices,
+            axis,
+            new_position,
+        }
+    }
+
     fn render_offset(&self) -> SignedCoord3D {
         const COORD: isize = ROOT_CHUNK_SIZE as isize / 2;
         self.origin
@@ -51,8 +138,13 @@ pub struct TerrainUploadManager {
     minefield_upload_buffer: Buffer<u8>,
     material_upload_buffer: Buffer<u32>,
     request_queue: Vec<TerrainUploadRequest>,
+    // Chunks that have been edited since they were uploaded.
+    edited_chunks: Vec<SignedCoord3D>,
     cpu_position: Position,
     gpu_position: Position,
+    last_desired_center: Option<SignedCoord3D>,
+    // The sign of the most recent movement along each axis.
+    motion_direction: SignedCoord3D,
 }
 
 impl TerrainUploadManager {
@@ -76,8 +168,11 @@ impl TerrainUploadManager {
             minefield_upload_buffer,
             material_upload_buffer,
             request_queue: Vec::new(),
+            edited_chunks: Vec::new(),
             cpu_position: Position::default(),
             gpu_position: Position::default(),
+            last_desired_center: None,
+            motion_direction: (0, 0, 0),
         }
     }
 
@@ -111,17 +206,24 @@ impl TerrainUploadManager {
             .num_slices
             .wrap(SLICES_PER_CHUNK.repeat())
             .scale(SLICE_SIZE);
-        for (d1, d2) in util::coord_iter_2d(ROOT_CHUNK_SIZE + 1) {
-            // Which piece of the slice we are currently copying.
-            let piece_offset = match request.axis {
-                Axis::X => (0, d1, d2),
-                Axis::Y => (d1, 0, d2),
-                Axis::Z => (d1, d2, 0),
-            };
+        // Uploaded in place of chunks that failed to load, so that the rest of the world can still
+        // be streamed in. ChunkStorage has already reported why they failed.
+        let empty_chunk = PackedChunk::Empty;
+        for piece_offset in request.piece_offsets() {
             // Which chunk we are loading from.
-            let world_coord = piece_offset.add(chunk_offset).signed().add(request.origin);
-            let chunk =
-                chunks.borrow_packed_chunk_data(&(world_coord.0, world_coord.1, world_coord.2));
+            let world_coord = request.get_chunk_coord(piece_offset);
+            let chunk = if chunks.is_chunk_failed(&world_coord) {
+                &empty_chunk
+            } else {
+                match chunks.borrow_packed_chunk(&world_coord) {
+                    Ok(chunk) => chunk,
+                    Err(err) => {
+                        println!("WARNING: Failed to load chunk {:?}.", world_coord);
+                        println!("Caused by: {}", err);
+                        &empty_chunk
+                    }
+                }
+            };
             // The coordinate inside the chunk to start copying from.
             let mut copy_start = (0, 0, 0);
             // Basically if we are copying from a chunk at the start of a particular axis, the
@@ -183,19 +285,15 @@ impl TerrainUploadManager {
                 Axis::Z => (copy_start.0, copy_start.1, 0),
             };
             let target_start = target_start.add(target_offset.signed());
-            util::copy_3d_bounded_auto_clip(
+            chunk.copy_materials_bounded(
                 copy_size,
-                &chunk.materials,
-                (CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE),
                 copy_start,
                 mat_data.as_slice_mut(),
                 data_shape,
                 target_start,
             );
-            util::copy_3d_bounded_auto_clip(
+            chunk.copy_minefield_bounded(
                 copy_size,
-                &chunk.minefield,
-                (CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE),
                 copy_start,
                 min_data.as_slice_mut(),
                 data_shape,
@@ -227,6 +325,20 @@ impl TerrainUploadManager {
                 z: axis_offset as i32,
             },
         };
+        self.copy_to_images(commands, data, target_offset, data_shape);
+
+        self.gpu_position = request.new_position;
+    }
+
+    // Copies data of the given shape from the upload buffers into the terrain images, starting at
+    // the given offset in the images.
+    fn copy_to_images(
+        &self,
+        commands: &mut CommandBuffer,
+        data: &RenderData,
+        target_offset: vk::Offset3D,
+        data_shape: Coord3D,
+    ) {
         let data_shape = vk::Extent3D {
             width: data_shape.0 as u32,
             height: data_shape.1 as u32,
@@ -270,17 +382,139 @@ impl TerrainUploadManager {
             vk::ImageLayout::TRANSFER_DST_OPTIMAL,
             vk::ImageLayout::GENERAL,
         );
+    }
 
-        self.gpu_position = request.new_position;
+    // Uploads the part of an edited chunk that is currently on the GPU, if any of it is. Returns
+    // false if none of it is, in which case nothing is uploaded.
+    fn upload_chunk(
+        &mut self,
+        commands: &mut CommandBuffer,
+        chunks: &mut ChunkStorage,
+        data: &RenderData,
+        coord: SignedCoord3D,
+    ) -> bool {
+        // The GPU holds ROOT_BLOCK_SIZE blocks along each axis, starting this many slices into the
+        // region at the origin.
+        let region_start = self.gpu_position.origin.scale(CHUNK_SIZE as isize);
+        let loaded_start = region_start.add(
+            self.gpu_position
+                .num_loaded_slices
+                .scale(SLICE_SIZE)
+                .signed(),
+        );
+        let loaded_end = loaded_start.add(ROOT_BLOCK_SIZE.repeat().signed());
+        let chunk_start = coord.scale(CHUNK_SIZE as isize);
+        let copy_start = chunk_start.ewmax(loaded_start);
+        let copy_end = chunk_start
+            .add(CHUNK_SIZE.repeat().signed())
+            .ewmin(loaded_end);
+        if !copy_start.add((1, 1, 1)).inside(copy_end) {
+            return false;
+        }
+        let unsigned =
+            |coord: SignedCoord3D| (coord.0 as usize, coord.1 as usize, coord.2 as usize);
+        let copy_size = unsigned(copy_end.sub(copy_start));
+        let source_start = unsigned(copy_start.sub(chunk_start));
+
+        let empty_chunk = PackedChunk::Empty;
+        let chunk = match chunks.borrow_packed_chunk(&coord) {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                println!("WARNING: Failed to load chunk {:?}.", coord);
+                println!("Caused by: {}", err);
+                &empty_chunk
+            }
+        };
+        let mut mat_data = self.material_upload_buffer.bind_all();
+        let mut min_data = self.minefield_upload_buffer.bind_all();
+        chunk.copy_materials_bounded(
+            copy_size,
+            source_start,
+            mat_data.as_slice_mut(),
+            copy_size,
+            (0, 0, 0),
+        );
+        chunk.copy_minefield_bounded(
+            copy_size,
+            source_start,
+            min_data.as_slice_mut(),
+            copy_size,
+            (0, 0, 0),
+        );
+        drop(mat_data);
+        drop(min_data);
+
+        // The images wrap around every ROOT_BLOCK_SIZE blocks, and chunks never straddle the wrap.
+        let target = unsigned(copy_start.sub(region_start)).wrap(ROOT_BLOCK_SIZE.repeat());
+        let target_offset = vk::Offset3D {
+            x: target.0 as i32,
+            y: target.1 as i32,
+            z: target.2 as i32,
+        };
+        self.copy_to_images(commands, data, target_offset, copy_size);
+        true
     }
 
+    /// Starts loading chunks that will probably be needed soon, judging by the direction the
+    /// camera has been moving in.
+    fn prefetch(&self, chunks: &mut ChunkStorage) {
+        for (axis, direction) in [
+            (Axis::X, self.motion_direction.0),
+            (Axis::Y, self.motion_direction.1),
+            (Axis::Z, self.motion_direction.2),
+        ]
+        .iter()
+        {
+            let mut position = self.cpu_position.clone();
+            for _ in 0..PREFETCH_SLICES {
+                let request = match direction {
+                    1 => position.next_increase(*axis),
+                    -1 => position.next_decrease(*axis),
+                    _ => break,
+                };
+                for coord in request.get_required_chunks() {
+                    chunks.request_chunk(&coord);
+                }
+                position = request.new_position;
+            }
+        }
+    }
+
+    /// Uploads the next slice if all the chunks it needs have been loaded. Chunks that are missing
+    /// are loaded in the background so that rendering does not have to wait for them. Chunks that
+    /// failed to load are uploaded as empty chunks instead of holding up the queue. Edited chunks
+    /// are uploaded again before any more slices.
     pub fn setup_next_request(
         &mut self,
         commands: &mut CommandBuffer,
         chunks: &mut ChunkStorage,
         data: &RenderData,
     ) {
-        if self.request_queue.len() == 0 {
+        chunks.receive_ready_chunks();
+        for request in &self.request_queue {
+            for coord in request.get_required_chunks() {
+                chunks.request_chunk(&coord);
+            }
+        }
+        self.prefetch(chunks);
+        for coord in chunks.take_edited_chunks() {
+            if !self.edited_chunks.contains(&coord) {
+                self.edited_chunks.push(coord);
+            }
+        }
+        // The upload buffers can only hold one upload at a time.
+        while let Some(coord) = self.edited_chunks.pop() {
+            if self.upload_chunk(commands, chunks, data, coord) {
+                return;
+            }
+        }
+        if self.request_queue.is_empty() {
+            return;
+        }
+        let ready = self.request_queue[0]
+            .get_required_chunks()
+            .all(|coord| chunks.is_chunk_ready(&coord) || chunks.is_chunk_failed(&coord));
+        if !ready {
             return;
         }
         let request = self.request_queue.remove(0);
@@ -292,63 +526,34 @@ impl TerrainUploadManager {
     }
 
     pub fn request_increase(&mut self, axis: Axis) {
-        // Load the next slice then increment the number of loaded slices.
-        let old_position = self.cpu_position.clone();
-        let num_slices = match axis {
-            Axis::X => &mut self.cpu_position.num_loaded_slices.0,
-            Axis::Y => &mut self.cpu_position.num_loaded_slices.1,
-            Axis::Z => &mut self.cpu_position.num_loaded_slices.2,
-        };
-        let coord = match axis {
-            Axis::X => &mut self.cpu_position.origin.0,
-            Axis::Y => &mut self.cpu_position.origin.1,
-            Axis::Z => &mut self.cpu_position.origin.2,
-        };
-        *num_slices += 1;
-        if *num_slices == ROOT_BLOCK_SIZE / SLICE_SIZE {
-            *num_slices = 0;
-            *coord += (ROOT_BLOCK_SIZE / CHUNK_SIZE) as isize;
-        }
-        // This makes it load the data from the next region instead of the current region.
-        let origin_offset = match axis {
-            Axis::X => (ROOT_CHUNK_SIZE, 0, 0),
-            Axis::Y => (0, ROOT_CHUNK_SIZE, 0),
-            Axis::Z => (0, 0, ROOT_CHUNK_SIZE),
-        };
-        self.request_queue.push(TerrainUploadRequest {
-            origin: old_position.origin.add(origin_offset.signed()),
-            num_slices: old_position.num_loaded_slices,
-            axis,
-            new_position: self.cpu_position.clone(),
-        });
+        let request = self.cpu_position.next_increase(axis);
+        self.cpu_position = request.new_position.clone();
+        self.request_queue.push(request);
     }
 
     pub fn request_decrease(&mut self, axis: Axis) {
-        // Rewind the coordinate to the previous slice and then load it from the current region.
-        let num_slices = match axis {
-            Axis::X => &mut self.cpu_position.num_loaded_slices.0,
-            Axis::Y => &mut self.cpu_position.num_loaded_slices.1,
-            Axis::Z => &mut self.cpu_position.num_loaded_slices.2,
-        };
-        let coord = match axis {
-            Axis::X => &mut self.cpu_position.origin.0,
-            Axis::Y => &mut self.cpu_position.origin.1,
-            Axis::Z => &mut self.cpu_position.origin.2,
-        };
-        if *num_slices == 0 {
-            *num_slices = ROOT_BLOCK_SIZE / SLICE_SIZE;
-            *coord -= (ROOT_BLOCK_SIZE / CHUNK_SIZE) as isize;
-        }
-        *num_slices -= 1;
-        self.request_queue.push(TerrainUploadRequest {
-            origin: self.cpu_position.origin,
-            num_slices: self.cpu_position.num_loaded_slices,
-            axis,
-            new_position: self.cpu_position.clone(),
-        });
+        let request = self.cpu_position.next_decrease(axis);
+        self.cpu_position = request.new_position.clone();
+        self.request_queue.push(request);
     }
 
     pub fn request_move_towards(&mut self, desired_center: SignedCoord3D) {
+        if let Some(last_center) = self.last_desired_center {
+            let delta = desired_center.sub(last_center);
+            // Only change direction along axes that actually moved, so that the direction is
+            // remembered while standing still.
+            let direction = &mut self.motion_direction;
+            if delta.0 != 0 {
+                direction.0 = delta.0.signum();
+            }
+            if delta.1 != 0 {
+                direction.1 = delta.1.signum();
+            }
+            if delta.2 != 0 {
+                direction.2 = delta.2.signum();
+            }
+        }
+        self.last_desired_center = Some(desired_center);
         let current_pos = &self.cpu_position;
         let delta = desired_center.sub(current_pos.render_offset());
         if delta.0 > SLICE_SIZE as _ {
@@ -602,6 +602,77 @@ fn test_copy_3d_bounded_auto_clip() {
     assert!(target[coord_to_index_3d(&(3, 3, 2), 4)] == 0);
 }
 
+/// Fills a region of size `size` in target with a single value, assuming target is a 3D array.
+/// Like copy_3d_bounded_auto_clip, target_start can be negative and size can be too large, only
+/// the part of the region that is inside the target is filled.
+pub fn fill_3d_bounded_auto_clip<T: Copy>(
+    value: T,
+    size: Coord3D,
+    target: &mut [T],
+    target_dims: Coord3D,
+    target_start: SignedCoord3D,
+) {
+    fill_3d_bounded_auto_clip_with(size, target, target_dims, target_start, |_| value);
+}
+
+/// Like fill_3d_bounded_auto_clip, but every item gets the value returned by get_value for its
+/// coordinate relative to target_start.
+pub fn fill_3d_bounded_auto_clip_with<T>(
+    size: Coord3D,
+    target: &mut [T],
+    target_dims: Coord3D,
+    target_start: SignedCoord3D,
+    mut get_value: impl FnMut(Coord3D) -> T,
+) {
+    // Ensure the dimensions provided are correct.
+    assert!(target.len() == target_dims.0 * target_dims.1 * target_dims.2);
+    // Returns the range of coordinates to fill along one axis.
+    let clip = |start: isize, size: usize, dim: usize| {
+        let end = (start + size as isize).clamp(0, dim as isize) as usize;
+        (start.clamp(0, end as isize) as usize, end)
+    };
+    let x = clip(target_start.0, size.0, target_dims.0);
+    let y = clip(target_start.1, size.1, target_dims.1);
+    let z = clip(target_start.2, size.2, target_dims.2);
+    // The coordinate relative to target_start of a coordinate in the target.
+    let relative = |coord: isize, start: isize| (coord - start) as usize;
+    for z in z.0..z.1 {
+        for y in y.0..y.1 {
+            let row_start = (z * target_dims.1 + y) * target_dims.0;
+            for (x, item) in (x.0..x.1).zip(&mut target[row_start + x.0..row_start + x.1]) {
+                *item = get_value((
+                    relative(x as isize, target_start.0),
+                    relative(y as isize, target_start.1),
+                    relative(z as isize, target_start.2),
+                ));
+            }
+        }
+    }
+}
+
+#[test]
+fn test_fill_3d_bounded_auto_clip() {
+    let mut target = [0; 4 * 3 * 2];
+    fill_3d_bounded_auto_clip(7, (2, 5, 1), &mut target[..], (4, 3, 2), (-1, 1, 1));
+    for z in 0..2 {
+        for y in 0..3 {
+            for x in 0..4 {
+                let filled = x == 0 && y >= 1 && z == 1;
+                assert_eq!(target[(z * 3 + y) * 4 + x], if filled { 7 } else { 0 });
+            }
+        }
+    }
+}
+
+#[test]
+fn test_fill_3d_bounded_auto_clip_with() {
+    let mut target = [(0, 0, 0); 4 * 4 * 4];
+    fill_3d_bounded_auto_clip_with((3, 3, 3), &mut target[..], (4, 4, 4), (-1, 2, 0), |c| c);
+    assert_eq!(target[coord_to_index_3d(&(0, 2, 0), 4)], (1, 0, 0));
+    assert_eq!(target[coord_to_index_3d(&(1, 3, 2), 4)], (2, 1, 2));
+    assert_eq!(target[coord_to_index_3d(&(2, 2, 0), 4)], (0, 0, 0));
+}
+
 pub fn fill_slice_3d<T: Copy>(
     value: T,
     target: &mut [T],
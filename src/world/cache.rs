@@ -0,0 +1,181 @@
+use super::{ChunkStorageCoord, PackedChunk};
+use std::collections::{BTreeMap, HashMap};
+
+/// How much memory the chunk cache may use unless configured otherwise, in bytes.
+pub const DEFAULT_CACHE_BUDGET: usize = 256 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// How many chunks were removed to stay within the memory budget.
+    pub evictions: u64,
+}
+
+struct CacheEntry {
+    data: PackedChunk,
+    last_used: u64,
+}
+
+fn get_size(chunk: &PackedChunk) -> usize {
+    std::mem::size_of::<PackedChunk>() + chunk.get_size()
+}
+
+/// Keeps recently used chunks in memory. When the chunks take up more memory than the budget
+/// allows, the chunks that were used least recently are removed first.
+pub struct ChunkCache {
+    entries: HashMap<ChunkStorageCoord, CacheEntry>,
+    // The coordinate of every entry, ordered by when it was last used.
+    usage_order: BTreeMap<u64, ChunkStorageCoord>,
+    // Increases every time an entry is used.
+    clock: u64,
+    budget: usize,
+    size: usize,
+    stats: CacheStats,
+}
+
+impl ChunkCache {
+    pub fn new(budget: usize) -> ChunkCache {
+        ChunkCache {
+            entries: HashMap::new(),
+            usage_order: BTreeMap::new(),
+            clock: 0,
+            budget,
+            size: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn get_budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Changes the budget, removing chunks if they no longer fit.
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.evict_until_size(budget);
+    }
+
+    /// How much memory the cached chunks take up, in bytes.
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+
+    pub fn get_stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn contains(&self, coord: &ChunkStorageCoord) -> bool {
+        self.entries.contains_key(coord)
+    }
+
+    /// Marks a chunk as recently used and returns whether it is cached. Counts as a hit or a miss
+    /// in the statistics.
+    pub fn touch(&mut self, coord: &ChunkStorageCoord) -> bool {
+        let entry = if let Some(entry) = self.entries.get_mut(coord) {
+            entry
+        } else {
+            self.stats.misses += 1;
+            return false;
+        };
+        self.stats.hits += 1;
+        self.usage_order.remove(&entry.last_used);
+        self.clock += 1;
+        entry.last_used = self.clock;
+        self.usage_order.insert(self.clock, *coord);
+        true
+    }
+
+    /// Returns the cached data for a chunk without counting it as used.
+    pub fn borrow(&self, coord: &ChunkStorageCoord) -> Option<&PackedChunk> {
+        self.entries.get(coord).map(|entry| &entry.data)
+    }
+
+    /// Adds data for a chunk, replacing any data that was already cached for it. The new data is
+    /// never evicted right away, even if it is larger than the budget by itself.
+    pub fn insert(&mut self, coord: ChunkStorageCoord, data: PackedChunk) -> &PackedChunk {
+        self.remove(&coord);
+        let size = get_size(&data);
+        self.evict_until_size(self.budget.saturating_sub(size));
+        self.size += size;
+        self.clock += 1;
+        self.usage_order.insert(self.clock, coord);
+        let entry = CacheEntry {
+            data,
+            last_used: self.clock,
+        };
+        &self.entries.entry(coord).or_insert(entry).data
+    }
+
+    pub fn remove(&mut self, coord: &ChunkStorageCoord) -> Option<PackedChunk> {
+        let entry = self.entries.remove(coord)?;
+        self.usage_order.remove(&entry.last_used);
+        self.size -= get_size(&entry.data);
+        Some(entry.data)
+    }
+
+    fn evict_until_size(&mut self, target_size: usize) {
+        while self.size > target_size {
+            let coord = match self.usage_order.values().next() {
+                Some(coord) => *coord,
+                None => break,
+            };
+            self.remove(&coord);
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::PaletteChunk;
+
+    fn make_data(value: u32) -> PackedChunk {
+        let mut chunk = PaletteChunk::filled(1234);
+        chunk.set_material(0, value);
+        PackedChunk::NonEmpty(chunk)
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let chunk_size = get_size(&make_data(0));
+        let mut cache = ChunkCache::new(chunk_size * 2);
+        cache.insert((0, 0, 0), make_data(0));
+        cache.insert((1, 0, 0), make_data(1));
+        assert_eq!(cache.get_size(), chunk_size * 2);
+        // Using the first chunk makes the second one the least recently used.
+        assert!(cache.touch(&(0, 0, 0)));
+        cache.insert((2, 0, 0), make_data(2));
+        assert!(cache.contains(&(0, 0, 0)));
+        assert!(!cache.touch(&(1, 0, 0)));
+        assert!(cache.borrow(&(1, 0, 0)).is_none());
+        assert!(cache.touch(&(2, 0, 0)));
+        assert_eq!(cache.borrow(&(2, 0, 0)).unwrap().get_material(0), 2);
+        assert_eq!(
+            cache.get_stats(),
+            CacheStats {
+                hits: 2,
+                misses: 1,
+                evictions: 1,
+            }
+        );
+
+        // Replacing a chunk should not change the size of the cache.
+        cache.insert((2, 0, 0), make_data(3));
+        assert_eq!(cache.get_size(), chunk_size * 2);
+        assert_eq!(cache.borrow(&(2, 0, 0)).unwrap().get_material(0), 3);
+
+        cache.set_budget(chunk_size);
+        assert!(!cache.contains(&(0, 0, 0)));
+        assert!(cache.contains(&(2, 0, 0)));
+        assert_eq!(cache.get_size(), chunk_size);
+
+        // Chunks made of a single material take up next to nothing.
+        cache.set_budget(chunk_size + 1000);
+        cache.insert((3, 0, 0), PackedChunk::Empty);
+        cache.insert((4, 0, 0), PackedChunk::Uniform(5));
+        assert!(cache.contains(&(2, 0, 0)));
+        assert!(cache.get_size() < chunk_size + 1000);
+    }
+}
@@ -0,0 +1,355 @@
+use super::generate::hash_coord;
+use super::worldgen_config::{CaveConfig, WormConfig};
+use crate::render::constants::*;
+use crate::util::{self, prelude::*};
+use noise::{Fbm, MultiFractal, NoiseFn, Seedable};
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::f64::consts::PI;
+
+/// Noise is only sampled every this many blocks and interpolated in between, which is much faster
+/// than sampling every block and still smooth enough for caves.
+const GRID_STEP: usize = 4;
+/// Blocks are checked one layer past the top of the chunk, to find the floors of caves.
+pub const CARVED_HEIGHT: usize = CHUNK_SIZE + 1;
+
+// Added to the seed so that different features do not line up with each other.
+const OVERHANG_SALT: u32 = 3;
+const CAVERN_SALT: u32 = 4;
+const WORM_SALT: u64 = 0x5752_4F4D;
+const GLOW_SALT: u64 = 0x474C_4F57;
+
+/// 3D noise sampled in a grid covering a chunk.
+struct NoiseGrid {
+    values: Vec<f64>,
+    // How many samples there are along each axis.
+    size: (usize, usize, usize),
+}
+
+impl NoiseGrid {
+    /// Samples noise for the blocks of a chunk, plus the extra layer above it. scale divides the
+    /// coordinates of each block before they are passed to the noise.
+    fn sample(noise: &Fbm, origin: &util::SignedCoord3D, scale: (f64, f64, f64)) -> NoiseGrid {
+        let size = (
+            CHUNK_SIZE / GRID_STEP + 1,
+            CHUNK_SIZE / GRID_STEP + 1,
+            CARVED_HEIGHT / GRID_STEP + 2,
+        );
+        let mut values = Vec::with_capacity(size.0 * size.1 * size.2);
+        for z in 0..size.2 {
+            for y in 0..size.1 {
+                for x in 0..size.0 {
+                    let block = origin.add((x, y, z).scale(GRID_STEP).signed());
+                    values.push(noise.get([
+                        block.0 as f64 / scale.0,
+                        block.1 as f64 / scale.1,
+                        block.2 as f64 / scale.2,
+                    ]));
+                }
+            }
+        }
+        NoiseGrid { values, size }
+    }
+
+    /// Interpolates the noise at a block inside the chunk.
+    fn get(&self, coord: &util::Coord3D) -> f64 {
+        let cell = coord.shrink(GRID_STEP);
+        let step = GRID_STEP as f64;
+        let fraction = (
+            (coord.0 % GRID_STEP) as f64 / step,
+            (coord.1 % GRID_STEP) as f64 / step,
+            (coord.2 % GRID_STEP) as f64 / step,
+        );
+        let sample = |offset: util::Coord3D| {
+            let coord = cell.add(offset);
+            self.values[(coord.2 * self.size.1 + coord.1) * self.size.0 + coord.0]
+        };
+        let lerp = |a: f64, b: f64, amount: f64| a + (b - a) * amount;
+        let along_x = |y, z| lerp(sample((0, y, z)), sample((1, y, z)), fraction.0);
+        let along_y = |z| lerp(along_x(0, z), along_x(1, z), fraction.1);
+        lerp(along_y(0), along_y(1), fraction.2)
+    }
+}
+
+/// The parts of caves that affect a single chunk.
+pub struct ChunkCaves {
+    overhangs: Option<(NoiseGrid, f64)>,
+    // Which blocks are carved out, including the extra layer above the chunk.
+    carved: Vec<bool>,
+    any_carved: bool,
+}
+
+impl ChunkCaves {
+    /// How far overhangs move the surface at a block of the chunk.
+    pub fn get_surface_offset(&self, coord: &util::Coord3D) -> f64 {
+        match &self.overhangs {
+            Some((grid, amplitude)) => grid.get(coord) * amplitude,
+            None => 0.0,
+        }
+    }
+
+    /// The coordinate can be one layer above the top of the chunk.
+    pub fn is_carved(&self, coord: &util::Coord3D) -> bool {
+        self.any_carved && self.carved[util::coord_to_index_3d(coord, CHUNK_SIZE)]
+    }
+}
+
+/// Carves caves, arches and overhangs into terrain.
+pub struct Caves {
+    config: CaveConfig,
+    seed: u64,
+    overhang_noise: Fbm,
+    cavern_noise: Fbm,
+}
+
+impl Caves {
+    pub fn new(config: &CaveConfig, seed: u64, noise_seed: u32) -> Caves {
+        let make_noise = |salt: u32| {
+            Fbm::new()
+                .set_seed(noise_seed.wrapping_add(salt))
+                .set_octaves(3)
+        };
+        Caves {
+            config: config.clone(),
+            seed,
+            overhang_noise: make_noise(OVERHANG_SALT),
+            cavern_noise: make_noise(CAVERN_SALT),
+        }
+    }
+
+    /// How far overhangs can move the surface away from the heightmap, in blocks.
+    pub fn get_max_surface_offset(&self) -> isize {
+        match &self.config.overhangs {
+            // Fbm noise with 3 octaves stays within -1.75 to 1.75.
+            Some(overhangs) => (overhangs.amplitude.abs() * 1.75).ceil() as isize,
+            None => 0,
+        }
+    }
+
+    pub fn get_glow_material(&self) -> Option<u32> {
+        self.config.glow_material.map(|material| material.0)
+    }
+
+    /// True if the block at the given position on the floor of a cave should glow.
+    pub fn is_glowing(&self, coord: &util::SignedCoord3D) -> bool {
+        let value = hash_coord(self.seed.wrapping_add(GLOW_SALT), coord);
+        let value = (value >> 11) as f64 / (1u64 << 53) as f64;
+        value < self.config.glow_chance
+    }
+
+    pub fn prepare_chunk(&self, chunk_coord: &util::SignedCoord3D) -> ChunkCaves {
+        let origin = chunk_coord.scale(CHUNK_SIZE as isize);
+        let overhangs = self.config.overhangs.as_ref().map(|overhangs| {
+            let scale = overhangs.scale;
+            let grid = NoiseGrid::sample(&self.overhang_noise, &origin, (scale, scale, scale));
+            (grid, overhangs.amplitude)
+        });
+        let mut caves = ChunkCaves {
+            overhangs,
+            carved: vec![false; CHUNK_SIZE * CHUNK_SIZE * CARVED_HEIGHT],
+            any_carved: false,
+        };
+        self.carve_caverns(&origin, &mut caves);
+        if let Some(worms) = &self.config.worms {
+            self.carve_worms(worms, &origin, &mut caves);
+        }
+        caves
+    }
+
+    fn carve_caverns(&self, origin: &util::SignedCoord3D, caves: &mut ChunkCaves) {
+        let caverns = if let Some(caverns) = &self.config.caverns {
+            caverns
+        } else {
+            return;
+        };
+        if origin.2 > caverns.max_height {
+            return;
+        }
+        let scale = (caverns.scale, caverns.scale, caverns.scale / caverns.squash);
+        let grid = NoiseGrid::sample(&self.cavern_noise, origin, scale);
+        for z in 0..CARVED_HEIGHT {
+            if origin.2 + z as isize > caverns.max_height {
+                break;
+            }
+            for (x, y) in util::coord_iter_2d(CHUNK_SIZE) {
+                if grid.get(&(x, y, z)) > caverns.threshold {
+                    caves.carved[util::coord_to_index_3d(&(x, y, z), CHUNK_SIZE)] = true;
+                    caves.any_carved = true;
+                }
+            }
+        }
+    }
+
+    /// Tunnels are random walks that start in regions around the chunk. Every chunk a tunnel
+    /// passes through follows the whole tunnel from its start, so the tunnel lines up across the
+    /// borders of chunks.
+    fn carve_worms(
+        &self,
+        worms: &WormConfig,
+        origin: &util::SignedCoord3D,
+        caves: &mut ChunkCaves,
+    ) {
+        let reach = (worms.length as f64 + worms.max_radius).ceil() as isize;
+        let size = worms.region_size;
+        let min_region = region_of(origin.sub(reach.repeat()), size);
+        let max_region = region_of(
+            origin
+                .add((CHUNK_SIZE, CHUNK_SIZE, CARVED_HEIGHT).signed())
+                .add(reach.repeat()),
+            size,
+        );
+        for rz in min_region.2..=max_region.2 {
+            // Skip regions where no tunnels can start.
+            if rz * size > worms.max_height || (rz + 1) * size <= worms.min_height {
+                continue;
+            }
+            for ry in min_region.1..=max_region.1 {
+                for rx in min_region.0..=max_region.0 {
+                    let region = (rx, ry, rz);
+                    let seed = hash_coord(self.seed.wrapping_add(WORM_SALT), &region);
+                    let mut random = StdRng::seed_from_u64(seed);
+                    for _ in 0..worms.per_region {
+                        let worm_seed = random.next_u64();
+                        self.carve_worm(worms, worm_seed, &region, origin, caves);
+                    }
+                }
+            }
+        }
+    }
+
+    fn carve_worm(
+        &self,
+        worms: &WormConfig,
+        seed: u64,
+        region: &util::SignedCoord3D,
+        origin: &util::SignedCoord3D,
+        caves: &mut ChunkCaves,
+    ) {
+        let mut random = StdRng::seed_from_u64(seed);
+        let size = worms.region_size as f64;
+        let start = region.scale(worms.region_size);
+        let mut position = (
+            start.0 as f64 + random.gen::<f64>() * size,
+            start.1 as f64 + random.gen::<f64>() * size,
+            start.2 as f64 + random.gen::<f64>() * size,
+        );
+        if position.2 < worms.min_height as f64 || position.2 > worms.max_height as f64 {
+            return;
+        }
+        let radius = worms.min_radius + random.gen::<f64>() * (worms.max_radius - worms.min_radius);
+        let mut yaw = random.gen::<f64>() * PI * 2.0;
+        let mut pitch = (random.gen::<f64>() - 0.5) * 0.5;
+        for _ in 0..worms.length {
+            carve_sphere(position, radius, origin, caves);
+            position.0 += yaw.cos() * pitch.cos();
+            position.1 += yaw.sin() * pitch.cos();
+            position.2 += pitch.sin();
+            yaw += (random.gen::<f64>() - 0.5) * 2.0 * worms.turn;
+            pitch += (random.gen::<f64>() - 0.5) * worms.turn;
+            // Keep tunnels from going straight up or down.
+            pitch = pitch.clamp(-0.8, 0.8);
+        }
+    }
+}
+
+fn region_of(coord: util::SignedCoord3D, region_size: isize) -> util::SignedCoord3D {
+    (
+        coord.0.div_euclid(region_size),
+        coord.1.div_euclid(region_size),
+        coord.2.div_euclid(region_size),
+    )
+}
+
+fn carve_sphere(
+    center: (f64, f64, f64),
+    radius: f64,
+    origin: &util::SignedCoord3D,
+    caves: &mut ChunkCaves,
+) {
+    let end = origin.add((CHUNK_SIZE, CHUNK_SIZE, CARVED_HEIGHT).signed());
+    let min = (
+        (center.0 - radius).floor() as isize,
+        (center.1 - radius).floor() as isize,
+        (center.2 - radius).floor() as isize,
+    );
+    let max = (
+        (center.0 + radius).ceil() as isize,
+        (center.1 + radius).ceil() as isize,
+        (center.2 + radius).ceil() as isize,
+    );
+    if max.0 < origin.0 || max.1 < origin.1 || max.2 < origin.2 {
+        return;
+    }
+    if min.0 >= end.0 || min.1 >= end.1 || min.2 >= end.2 {
+        return;
+    }
+    for z in min.2.max(origin.2)..=max.2.min(end.2 - 1) {
+        for y in min.1.max(origin.1)..=max.1.min(end.1 - 1) {
+            for x in min.0.max(origin.0)..=max.0.min(end.0 - 1) {
+                let offset = (
+                    x as f64 + 0.5 - center.0,
+                    y as f64 + 0.5 - center.1,
+                    z as f64 + 0.5 - center.2,
+                );
+                let distance = offset.0 * offset.0 + offset.1 * offset.1 + offset.2 * offset.2;
+                if distance <= radius * radius {
+                    let local = (x - origin.0, y - origin.1, z - origin.2);
+                    let local = (local.0 as usize, local.1 as usize, local.2 as usize);
+                    caves.carved[util::coord_to_index_3d(&local, CHUNK_SIZE)] = true;
+                    caves.any_carved = true;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::worldgen_config::CavernConfig;
+
+    fn make_caves() -> Caves {
+        let config = CaveConfig {
+            caverns: Some(CavernConfig {
+                threshold: 0.2,
+                ..Default::default()
+            }),
+            worms: Some(WormConfig {
+                region_size: 32,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        Caves::new(&config, 3, 3)
+    }
+
+    #[test]
+    fn carve_across_chunks() {
+        let caves = make_caves();
+        let mut total_carved = 0;
+        for chunk_coord in &[(0, 0, -1), (1, 0, -1), (0, -1, 0), (-2, 3, -2)] {
+            let lower = caves.prepare_chunk(chunk_coord);
+            let upper = caves.prepare_chunk(&chunk_coord.add((0, 0, 1)));
+            for (x, y) in util::coord_iter_2d(CHUNK_SIZE) {
+                // The extra layer above a chunk must match the bottom of the chunk above it.
+                assert_eq!(
+                    lower.is_carved(&(x, y, CHUNK_SIZE)),
+                    upper.is_carved(&(x, y, 0))
+                );
+            }
+            for z in 0..CHUNK_SIZE {
+                for (x, y) in util::coord_iter_2d(CHUNK_SIZE) {
+                    if lower.is_carved(&(x, y, z)) {
+                        total_carved += 1;
+                    }
+                }
+            }
+        }
+        assert!(total_carved > 0);
+
+        // Chunks do not depend on what was carved before them.
+        let first = caves.prepare_chunk(&(1, 0, -1));
+        let again = make_caves().prepare_chunk(&(1, 0, -1));
+        assert_eq!(first.carved, again.carved);
+    }
+}
@@ -1,65 +1,102 @@
-use crate::render::{constants::*, Material};
-use crate::util;
+use super::PaletteChunk;
+use crate::render::{constants::*, AIR_MATERIAL};
+use crate::util::{self, prelude::*};
 
+// The minefield value of blocks in a chunk without any visible blocks. Rays can skip to the edge
+// of the chunk from anywhere inside it.
+const EMPTY_CHUNK_DISTANCE: u8 = MAX_CHUNK_LOD as u8 + 1;
+
+/// A chunk ready to be uploaded to the GPU. Chunks made of a single material are kept as just that
+/// material instead of a full copy of every block.
+#[derive(Clone, PartialEq)]
 pub enum PackedChunk {
-    Empty { scale: u8 },
-    NonEmpty(PackedChunkData),
+    /// Every block is air.
+    Empty,
+    /// Every block is the given material, which is not air.
+    Uniform(u32),
+    NonEmpty(PaletteChunk),
 }
 
 impl PackedChunk {
-    pub fn copy_materials(
-        &self,
-        source_offset: util::SignedCoord3D,
-        target: &mut [u32],
-        target_stride: usize,
-    ) {
-        if let Self::NonEmpty(data) = self {
-            data.copy_materials(source_offset, target, target_stride);
+    /// Creates a chunk made of nothing but the given material.
+    pub fn uniform(material: u32) -> PackedChunk {
+        if material == AIR_MATERIAL {
+            PackedChunk::Empty
         } else {
-            util::fill_slice_3d_auto_clip(
-                0,
-                target,
-                target_stride,
-                source_offset,
-                (CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE),
-            );
+            PackedChunk::Uniform(material)
         }
     }
 
-    pub fn copy_minefield(
-        &self,
-        source_offset: util::SignedCoord3D,
-        target: &mut [u8],
-        target_stride: usize,
-    ) {
+    /// Creates the most compact kind of chunk that holds the given material of every block.
+    pub fn from_materials(materials: &[u32]) -> PackedChunk {
+        Self::from_palette(PaletteChunk::from_materials(materials))
+    }
+
+    /// Turns full chunk data into the most compact kind of chunk that holds the same blocks. The
+    /// minefield of the data is ignored, it is worked out again from the materials.
+    pub fn from_data(data: PackedChunkData) -> PackedChunk {
+        Self::from_materials(&data.materials)
+    }
+
+    fn from_palette(chunk: PaletteChunk) -> PackedChunk {
+        match chunk.get_uniform_material() {
+            Some(material) => Self::uniform(material),
+            None => PackedChunk::NonEmpty(chunk),
+        }
+    }
+
+    /// Returns the material of the block at the given index.
+    pub fn get_material(&self, index: usize) -> u32 {
         match self {
-            Self::NonEmpty(data) => {
-                data.copy_minefield(source_offset, target, target_stride);
-            }
-            Self::Empty { scale } => {
-                util::fill_slice_3d_auto_clip(
-                    MAX_CHUNK_LOD as u8 + scale,
-                    target,
-                    target_stride,
-                    source_offset,
-                    (CHUNK_SIZE, CHUNK_SIZE, CHUNK_SIZE),
-                );
-            }
+            Self::Empty => AIR_MATERIAL,
+            Self::Uniform(material) => *material,
+            Self::NonEmpty(chunk) => chunk.get_material(index),
         }
     }
-}
 
-#[derive(Clone, PartialEq)]
-pub struct PackedChunkData {
-    pub minefield: Vec<u8>,
-    pub materials: Vec<u32>,
-}
+    /// Returns the minefield value of the block at the given index.
+    pub fn get_distance(&self, index: usize) -> u8 {
+        match self {
+            Self::NonEmpty(chunk) => chunk.get_distance(index),
+            _ => Self::get_uniform_distance(self.get_material(0)),
+        }
+    }
 
-impl PackedChunkData {
-    pub fn new() -> PackedChunkData {
-        PackedChunkData {
-            minefield: vec![0; CHUNK_VOLUME],
-            materials: vec![0; CHUNK_VOLUME],
+    // The minefield value of every block of a chunk made of a single material.
+    fn get_uniform_distance(material: u32) -> u8 {
+        if material != AIR_MATERIAL {
+            0
+        } else {
+            EMPTY_CHUNK_DISTANCE
+        }
+    }
+
+    /// Changes the material of the block at the given index. The chunk turns into whichever kind
+    /// of chunk holds its new blocks most compactly.
+    pub fn set_material(&mut self, index: usize, material: u32) {
+        let coord = util::index_to_coord_3d(index, CHUNK_SIZE);
+        self.fill_box(coord, (1, 1, 1), material);
+    }
+
+    /// Changes the material of every block in a box inside the chunk, see PaletteChunk::fill_box.
+    pub fn fill_box(&mut self, start: util::Coord3D, size: util::Coord3D, material: u32) {
+        if let Self::NonEmpty(chunk) = self {
+            chunk.fill_box(start, size, material);
+            if let Some(material) = chunk.get_uniform_material() {
+                *self = Self::uniform(material);
+            }
+        } else if self.get_material(0) != material {
+            let mut chunk = PaletteChunk::filled(self.get_material(0));
+            chunk.fill_box(start, size, material);
+            *self = Self::from_palette(chunk);
+        }
+    }
+
+    /// How much memory the blocks of the chunk take up, in bytes.
+    pub fn get_size(&self) -> usize {
+        match self {
+            Self::NonEmpty(chunk) => chunk.get_size(),
+            _ => 0,
         }
     }
 
@@ -69,12 +106,13 @@ impl PackedChunkData {
         target: &mut [u32],
         target_stride: usize,
     ) {
-        util::copy_3d_auto_clip(
-            &self.materials,
-            CHUNK_SIZE,
-            source_offset,
+        let target_dims = target_stride.repeat();
+        self.copy_materials_bounded(
+            CHUNK_SIZE.repeat(),
+            (0, 0, 0),
             target,
-            target_stride,
+            target_dims,
+            source_offset,
         );
     }
 
@@ -84,102 +122,172 @@ impl PackedChunkData {
         target: &mut [u8],
         target_stride: usize,
     ) {
-        util::copy_3d_auto_clip(
-            &self.minefield,
-            CHUNK_SIZE,
-            source_offset,
+        let target_dims = target_stride.repeat();
+        self.copy_minefield_bounded(
+            CHUNK_SIZE.repeat(),
+            (0, 0, 0),
             target,
-            target_stride,
+            target_dims,
+            source_offset,
         );
     }
 
-    pub fn unpack_into(&self, unpacked_data: &mut UnpackedChunkData) {
-        for index in 0..CHUNK_VOLUME {
-            unpacked_data.materials[index] = Material::unpack(self.materials[index]);
+    /// Copies part of the materials of the chunk, see util::copy_3d_bounded_auto_clip.
+    pub fn copy_materials_bounded(
+        &self,
+        size: util::Coord3D,
+        source_start: util::Coord3D,
+        target: &mut [u32],
+        target_dims: util::Coord3D,
+        target_start: util::SignedCoord3D,
+    ) {
+        let size = size.ewmin(CHUNK_SIZE.repeat().sub(source_start));
+        if let Self::NonEmpty(chunk) = self {
+            util::fill_3d_bounded_auto_clip_with(
+                size,
+                target,
+                target_dims,
+                target_start,
+                |offset| {
+                    chunk.get_material(util::coord_to_index_3d(
+                        &source_start.add(offset),
+                        CHUNK_SIZE,
+                    ))
+                },
+            );
+        } else {
+            let value = self.get_material(0);
+            util::fill_3d_bounded_auto_clip(value, size, target, target_dims, target_start);
+        }
+    }
+
+    /// Copies part of the minefield of the chunk, see util::copy_3d_bounded_auto_clip.
+    pub fn copy_minefield_bounded(
+        &self,
+        size: util::Coord3D,
+        source_start: util::Coord3D,
+        target: &mut [u8],
+        target_dims: util::Coord3D,
+        target_start: util::SignedCoord3D,
+    ) {
+        let size = size.ewmin(CHUNK_SIZE.repeat().sub(source_start));
+        if let Self::NonEmpty(chunk) = self {
+            util::fill_3d_bounded_auto_clip_with(
+                size,
+                target,
+                target_dims,
+                target_start,
+                |offset| {
+                    chunk.get_distance(util::coord_to_index_3d(
+                        &source_start.add(offset),
+                        CHUNK_SIZE,
+                    ))
+                },
+            );
+        } else {
+            let value = Self::get_uniform_distance(self.get_material(0));
+            util::fill_3d_bounded_auto_clip(value, size, target, target_dims, target_start);
+        }
+    }
+}
+
+/// The material and minefield value of every block of a chunk, which is how chunks used to be
+/// stored.
+#[derive(Clone, PartialEq)]
+pub struct PackedChunkData {
+    pub minefield: Vec<u8>,
+    pub materials: Vec<u32>,
+}
+
+impl PackedChunkData {
+    pub fn new() -> PackedChunkData {
+        PackedChunkData {
+            minefield: vec![0; CHUNK_VOLUME],
+            materials: vec![0; CHUNK_VOLUME],
         }
     }
 }
 
 #[derive(Clone, PartialEq)]
 pub struct UnpackedChunkData {
-    pub materials: Vec<Material>,
+    pub materials: Vec<u32>,
 }
 
 impl UnpackedChunkData {
     pub fn new() -> UnpackedChunkData {
         UnpackedChunkData {
-            materials: vec![Material::air(); CHUNK_VOLUME],
+            materials: vec![AIR_MATERIAL; CHUNK_VOLUME],
         }
     }
 
-    pub fn set_block(&mut self, coord: &util::Coord3D, value: Material) {
+    pub fn set_block(&mut self, coord: &util::Coord3D, value: u32) {
         self.materials[util::coord_to_index_3d(coord, CHUNK_SIZE)] = value;
     }
 
-    pub fn fill(&mut self, value: &Material) {
+    pub fn fill(&mut self, value: u32) {
         for index in 0..CHUNK_VOLUME {
-            self.materials[index] = value.clone();
+            self.materials[index] = value;
         }
     }
 
-    pub fn pack_into(&self, packed_data: &mut PackedChunkData) {
-        let mut lods = Vec::with_capacity(MAX_CHUNK_LOD);
-        let mut lod_volume = CHUNK_VOLUME / 8;
-        while lod_volume > 0 {
-            lods.push(vec![false; lod_volume]);
-            lod_volume /= 8;
-        }
+    /// Packs the chunk into the most compact kind of chunk that holds the same blocks.
+    pub fn pack(&self) -> PackedChunk {
+        PackedChunk::from_materials(&self.materials)
+    }
+}
 
-        for index in 0..CHUNK_VOLUME {
-            // If there is a non-empty material at the index, mark the whole chunk as non-empty and
-            // modify the LODs accordingly.
-            if self.materials[index].solid {
-                let coord = util::index_to_coord_3d(index, CHUNK_SIZE);
-                let mut lod_coord = util::shrink_coord_3d(&coord, 2);
-                let mut lod_stride = CHUNK_SIZE / 2;
-                for lod in &mut lods {
-                    let index = util::coord_to_index_3d(&lod_coord, lod_stride);
-                    if lod[index] {
-                        break; // The LOD is already set, no need to continue.
-                    } else {
-                        lod[index] = true;
-                        lod_coord = util::shrink_coord_3d(&lod_coord, 2);
-                        lod_stride /= 2;
-                    }
-                }
-            }
-            packed_data.materials[index] = self.materials[index].pack();
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{materials, MATERIALS};
 
-        // If the whole chunk is empty, just fill the data.
-        if !lods[MAX_CHUNK_LOD - 1][0] {
-            for index in 0..CHUNK_VOLUME {
-                packed_data.materials[index] = Material::air().pack();
-                packed_data.minefield[index] = MAX_CHUNK_LOD as u8;
-            }
-            return;
-        }
+    #[test]
+    fn pack_uniform() {
+        let mut unpacked = UnpackedChunkData::new();
+        let mut minefield = vec![0; CHUNK_VOLUME];
+        let empty = unpacked.pack();
+        assert!(empty == PackedChunk::Empty);
+        empty.copy_minefield((0, 0, 0), &mut minefield, CHUNK_SIZE);
+        assert!(minefield
+            .iter()
+            .all(|&distance| distance == EMPTY_CHUNK_DISTANCE));
 
-        // Pack the LODs into the minefield.
-        for index in 0..CHUNK_VOLUME {
-            let coord = util::index_to_coord_3d(index, CHUNK_SIZE);
-            if self.materials[index].solid {
-                packed_data.minefield[index] = 0;
-                continue;
-            }
-            let mut lod_coord = util::shrink_coord_3d(&coord, 2);
-            let mut lod_stride = CHUNK_SIZE / 2;
-            let mut current_lod = 1;
-            for lod in &lods {
-                let lod_index = util::coord_to_index_3d(&lod_coord, lod_stride);
-                if lod[lod_index] {
-                    packed_data.minefield[index] = current_lod;
-                    break;
-                }
-                lod_coord = util::shrink_coord_3d(&lod_coord, 2);
-                lod_stride /= 2;
-                current_lod += 1;
-            }
-        }
+        let stone = materials::STONE;
+        unpacked.fill(stone);
+        let mut uniform = unpacked.pack();
+        assert!(uniform == PackedChunk::Uniform(stone));
+        uniform.copy_minefield((0, 0, 0), &mut minefield, CHUNK_SIZE);
+        assert!(minefield.iter().all(|&distance| distance == 0));
+        let mut materials = vec![0; 8 * 8 * 8];
+        uniform.copy_materials((-3, 5, 0), &mut materials, 8);
+        assert_eq!(materials[util::coord_to_index_3d(&(0, 5, 0), 8)], stone);
+        assert_eq!(materials[util::coord_to_index_3d(&(0, 4, 0), 8)], 0);
+
+        // Editing a block of a uniform chunk turns it into a palette, and back once it is undone.
+        let index = util::coord_to_index_3d(&(1, 2, 3), CHUNK_SIZE);
+        uniform.set_material(index, AIR_MATERIAL);
+        assert!(matches!(uniform, PackedChunk::NonEmpty(..)));
+        assert_eq!(uniform.get_material(index), AIR_MATERIAL);
+        assert_eq!(uniform.get_distance(index), 1);
+        uniform.set_material(index, stone);
+        assert!(uniform == PackedChunk::Uniform(stone));
+    }
+
+    #[test]
+    fn pack_liquid() {
+        let water = materials::WATER;
+        assert!(!MATERIALS[water as usize].is_solid());
+
+        let mut unpacked = UnpackedChunkData::new();
+        let coord = (3, 4, 5);
+        unpacked.set_block(&coord, water);
+        let packed = unpacked.pack();
+        let mut minefield = vec![0; CHUNK_VOLUME];
+        packed.copy_minefield((0, 0, 0), &mut minefield, CHUNK_SIZE);
+        // Rays have to stop at liquids to pick up their color, so they are not skipped over.
+        let index = util::coord_to_index_3d(&coord, CHUNK_SIZE);
+        assert_eq!(minefield[index], 0);
+        assert_eq!(minefield[index - 1], 1);
+        assert_eq!(packed.get_material(index), water);
     }
 }
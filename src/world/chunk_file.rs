@@ -0,0 +1,446 @@
+use super::{PackedChunk, PackedChunkData, PaletteChunk};
+use crate::render::{constants::*, MATERIAL_ENCODING_VERSION};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use lz4::{Decoder, EncoderBuilder};
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, Read, Write};
+
+const MAGIC: [u8; 4] = *b"RTCK";
+/// Increase this whenever the layout of the header or the data after it changes.
+pub const FORMAT_VERSION: u16 = 3;
+// The oldest format version that can still be read. Version 1 only had full bodies, version 2 added
+// empty and uniform ones.
+const MIN_FORMAT_VERSION: u16 = 1;
+pub const HEADER_SIZE: usize = 32;
+// Chunks written before headers existed are a bare LZ4 frame, which always starts with this.
+const LZ4_FRAME_MAGIC: [u8; 4] = [0x04, 0x22, 0x4D, 0x18];
+// The material encoding used by chunks written before headers existed.
+const LEGACY_MATERIAL_ENCODING_VERSION: u16 = 1;
+
+/// Describes the data that follows it, so that data written by an incompatible version of the
+/// program is detected instead of being misread.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChunkHeader {
+    pub format_version: u16,
+    pub chunk_size: u16,
+    pub max_chunk_lod: u8,
+    pub body_kind: BodyKind,
+    pub material_encoding_version: u16,
+    pub seed: u64,
+    /// Length of the compressed data following the header.
+    pub body_length: u32,
+    /// CRC32 of the compressed data following the header.
+    pub checksum: u32,
+}
+
+/// What the data following the header holds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BodyKind {
+    /// The LZ4 compressed materials and minefield of every block. Only written by versions 1 and 2.
+    Full = 0,
+    /// Nothing, every block is air.
+    Empty = 1,
+    /// A single packed material that every block is made of.
+    Uniform = 2,
+    /// The LZ4 compressed palette of the chunk and the index into it of every block, as written by
+    /// PaletteChunk::write_to.
+    Palette = 3,
+}
+
+impl ChunkHeader {
+    fn current(seed: u64, body_kind: BodyKind, body: &[u8]) -> ChunkHeader {
+        ChunkHeader {
+            format_version: FORMAT_VERSION,
+            chunk_size: CHUNK_SIZE as u16,
+            max_chunk_lod: MAX_CHUNK_LOD as u8,
+            body_kind,
+            material_encoding_version: MATERIAL_ENCODING_VERSION,
+            seed,
+            body_length: body.len() as u32,
+            checksum: checksum(body),
+        }
+    }
+
+    fn write_to(&self, target: &mut impl Write) -> io::Result<()> {
+        target.write_all(&MAGIC)?;
+        target.write_u16::<LittleEndian>(self.format_version)?;
+        target.write_u16::<LittleEndian>(self.chunk_size)?;
+        target.write_u8(self.max_chunk_lod)?;
+        target.write_u8(self.body_kind as u8)?;
+        target.write_u16::<LittleEndian>(self.material_encoding_version)?;
+        target.write_u64::<LittleEndian>(self.seed)?;
+        target.write_u32::<LittleEndian>(self.body_length)?;
+        target.write_u32::<LittleEndian>(self.checksum)?;
+        target.write_u32::<LittleEndian>(0)?;
+        Ok(())
+    }
+
+    fn read_from(mut source: &[u8]) -> Result<ChunkHeader, ChunkFileError> {
+        if source.len() < HEADER_SIZE {
+            return Err(ChunkFileError::Truncated);
+        }
+        if source[0..4] != MAGIC {
+            return Err(ChunkFileError::BadMagic);
+        }
+        source = &source[4..];
+        let format_version = source.read_u16::<LittleEndian>()?;
+        if !(MIN_FORMAT_VERSION..=FORMAT_VERSION).contains(&format_version) {
+            return Err(ChunkFileError::UnsupportedVersion(format_version));
+        }
+        let chunk_size = source.read_u16::<LittleEndian>()?;
+        let max_chunk_lod = source.read_u8()?;
+        // Always zero in version 1, which is the same as a full body.
+        let body_kind = match source.read_u8()? {
+            0 => BodyKind::Full,
+            1 => BodyKind::Empty,
+            2 => BodyKind::Uniform,
+            3 => BodyKind::Palette,
+            kind => return Err(ChunkFileError::UnknownBodyKind(kind)),
+        };
+        let material_encoding_version = source.read_u16::<LittleEndian>()?;
+        let seed = source.read_u64::<LittleEndian>()?;
+        let body_length = source.read_u32::<LittleEndian>()?;
+        let checksum = source.read_u32::<LittleEndian>()?;
+        Ok(ChunkHeader {
+            format_version,
+            chunk_size,
+            max_chunk_lod,
+            body_kind,
+            material_encoding_version,
+            seed,
+            body_length,
+            checksum,
+        })
+    }
+
+    /// Returns an error if data described by this header cannot be used by this version of the
+    /// program in a world with the given seed.
+    fn check_compatible(&self, seed: u64) -> Result<(), ChunkFileError> {
+        if self.chunk_size as usize != CHUNK_SIZE {
+            return Err(ChunkFileError::ChunkSizeMismatch {
+                found: self.chunk_size,
+                expected: CHUNK_SIZE as u16,
+            });
+        }
+        if self.max_chunk_lod as usize != MAX_CHUNK_LOD {
+            return Err(ChunkFileError::LodMismatch {
+                found: self.max_chunk_lod,
+                expected: MAX_CHUNK_LOD as u8,
+            });
+        }
+        if self.material_encoding_version != MATERIAL_ENCODING_VERSION {
+            return Err(ChunkFileError::MaterialEncodingMismatch {
+                found: self.material_encoding_version,
+                expected: MATERIAL_ENCODING_VERSION,
+            });
+        }
+        if self.seed != seed {
+            return Err(ChunkFileError::SeedMismatch {
+                found: self.seed,
+                expected: seed,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum ChunkFileError {
+    Io(io::Error),
+    /// The data is shorter than its header says it should be.
+    Truncated,
+    /// The data does not start with a chunk header at all.
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnknownBodyKind(u8),
+    ChunkSizeMismatch {
+        found: u16,
+        expected: u16,
+    },
+    LodMismatch {
+        found: u8,
+        expected: u8,
+    },
+    MaterialEncodingMismatch {
+        found: u16,
+        expected: u16,
+    },
+    SeedMismatch {
+        found: u64,
+        expected: u64,
+    },
+    ChecksumMismatch {
+        found: u32,
+        expected: u32,
+    },
+}
+
+impl Display for ChunkFileError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Io(err) => write!(f, "{}", err),
+            Self::Truncated => write!(f, "Chunk data is truncated."),
+            Self::BadMagic => write!(f, "Chunk data does not start with a chunk header."),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "Chunk data uses format version {}, but only versions {} to {} are supported.",
+                version, MIN_FORMAT_VERSION, FORMAT_VERSION
+            ),
+            Self::UnknownBodyKind(kind) => {
+                write!(f, "Chunk data has an unknown body kind {}.", kind)
+            }
+            Self::ChunkSizeMismatch { found, expected } => write!(
+                f,
+                "Chunk data has a chunk size of {}, but the current chunk size is {}.",
+                found, expected
+            ),
+            Self::LodMismatch { found, expected } => write!(
+                f,
+                "Chunk data has a maximum LOD of {}, but the current maximum LOD is {}.",
+                found, expected
+            ),
+            Self::MaterialEncodingMismatch { found, expected } => write!(
+                f,
+                "Chunk data uses material encoding {}, but the current encoding is {}.",
+                found, expected
+            ),
+            Self::SeedMismatch { found, expected } => write!(
+                f,
+                "Chunk data was generated with seed {}, but the world uses seed {}.",
+                found, expected
+            ),
+            Self::ChecksumMismatch { found, expected } => write!(
+                f,
+                "Chunk data is corrupt, its checksum is {:08X} instead of {:08X}.",
+                found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ChunkFileError {}
+
+impl From<io::Error> for ChunkFileError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+fn checksum(body: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(body);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+fn write_body(data: &PackedChunkData) -> io::Result<Vec<u8>> {
+    let mut writer = EncoderBuilder::new().level(4).build(Vec::new())?;
+    unsafe {
+        let mat_slice = &data.materials[..];
+        let mat_slice_u8 =
+            std::slice::from_raw_parts(mat_slice.as_ptr() as *const u8, mat_slice.len() * 4);
+        writer.write_all(mat_slice_u8)?;
+    }
+    writer.write_all(&data.minefield)?;
+    let (bytes, result) = writer.finish();
+    result?;
+    Ok(bytes)
+}
+
+fn read_body(body: &[u8], data: &mut PackedChunkData) -> io::Result<()> {
+    let mut reader = Decoder::new(body)?;
+    unsafe {
+        let mat_slice = &mut data.materials[..];
+        let mat_slice_u8 =
+            std::slice::from_raw_parts_mut(mat_slice.as_ptr() as *mut u8, mat_slice.len() * 4);
+        reader.read_exact(mat_slice_u8)?;
+    }
+    reader.read_exact(&mut data.minefield[..])?;
+    Ok(())
+}
+
+fn write_palette_body(chunk: &PaletteChunk) -> io::Result<Vec<u8>> {
+    let mut writer = EncoderBuilder::new().level(4).build(Vec::new())?;
+    chunk.write_to(&mut writer)?;
+    let (bytes, result) = writer.finish();
+    result?;
+    Ok(bytes)
+}
+
+fn read_palette_body(body: &[u8]) -> io::Result<PaletteChunk> {
+    PaletteChunk::read_from(&mut Decoder::new(body)?)
+}
+
+/// Converts a chunk to bytes that can later be read by read_packed_chunk. Chunks made of a single
+/// material only take up a few bytes.
+pub fn write_packed_chunk(chunk: &PackedChunk, seed: u64) -> io::Result<Vec<u8>> {
+    let (body_kind, body) = match chunk {
+        PackedChunk::Empty => (BodyKind::Empty, Vec::new()),
+        PackedChunk::Uniform(material) => (BodyKind::Uniform, material.to_le_bytes().to_vec()),
+        PackedChunk::NonEmpty(chunk) => (BodyKind::Palette, write_palette_body(chunk)?),
+    };
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + body.len());
+    ChunkHeader::current(seed, body_kind, &body).write_to(&mut bytes)?;
+    bytes.extend_from_slice(&body);
+    Ok(bytes)
+}
+
+/// True if the bytes were written before chunk data had headers.
+fn is_legacy_data(bytes: &[u8]) -> bool {
+    bytes.len() >= 4 && bytes[0..4] == LZ4_FRAME_MAGIC
+}
+
+/// Reads data written by write_packed_chunk, checking that it is intact and compatible with the
+/// current version of the program and a world with the given seed. Full chunks written by older
+/// versions are turned into palettes, or a single material if that is all they are made of.
+pub fn read_packed_chunk(bytes: &[u8], seed: u64) -> Result<PackedChunk, ChunkFileError> {
+    if is_legacy_data(bytes) {
+        // Voxels stored packed colors instead of material ids back then, so the data can't be used.
+        return Err(ChunkFileError::MaterialEncodingMismatch {
+            found: LEGACY_MATERIAL_ENCODING_VERSION,
+            expected: MATERIAL_ENCODING_VERSION,
+        });
+    }
+    let header = ChunkHeader::read_from(bytes)?;
+    header.check_compatible(seed)?;
+    let body = &bytes[HEADER_SIZE..];
+    if body.len() < header.body_length as usize {
+        return Err(ChunkFileError::Truncated);
+    }
+    let body = &body[..header.body_length as usize];
+    let checksum = checksum(body);
+    if checksum != header.checksum {
+        return Err(ChunkFileError::ChecksumMismatch {
+            found: checksum,
+            expected: header.checksum,
+        });
+    }
+    match header.body_kind {
+        BodyKind::Empty => Ok(PackedChunk::Empty),
+        BodyKind::Uniform => {
+            let mut body = body;
+            Ok(PackedChunk::Uniform(body.read_u32::<LittleEndian>()?))
+        }
+        BodyKind::Full => {
+            let mut data = PackedChunkData::new();
+            read_body(body, &mut data)?;
+            Ok(PackedChunk::from_data(data))
+        }
+        BodyKind::Palette => Ok(PackedChunk::NonEmpty(read_palette_body(body)?)),
+    }
+}
+
+/// Produces data in the format used before chunk data had headers.
+#[cfg(test)]
+pub fn write_legacy_packed_chunk_data(data: &PackedChunkData) -> io::Result<Vec<u8>> {
+    write_body(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_test_data() -> PackedChunkData {
+        let mut data = PackedChunkData::new();
+        data.minefield[7] = 3;
+        data.materials[9] = 1234;
+        data
+    }
+
+    fn make_test_chunk() -> PackedChunk {
+        PackedChunk::from_data(make_test_data())
+    }
+
+    // Writes a chunk with a full body, the way versions 1 and 2 did.
+    fn write_full_chunk(data: &PackedChunkData, format_version: u16) -> Vec<u8> {
+        let body = write_body(data).unwrap();
+        let mut header = ChunkHeader::current(42, BodyKind::Full, &body);
+        header.format_version = format_version;
+        let mut bytes = Vec::new();
+        header.write_to(&mut bytes).unwrap();
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let chunk = make_test_chunk();
+        let bytes = write_packed_chunk(&chunk, 42).unwrap();
+        let header = ChunkHeader::read_from(&bytes).unwrap();
+        assert_eq!(header.seed, 42);
+        assert_eq!(header.chunk_size as usize, CHUNK_SIZE);
+        assert_eq!(header.body_kind, BodyKind::Palette);
+        assert_eq!(bytes.len(), HEADER_SIZE + header.body_length as usize);
+        assert!(read_packed_chunk(&bytes, 42).unwrap() == chunk);
+
+        for chunk in &[PackedChunk::Empty, PackedChunk::Uniform(1234)] {
+            let bytes = write_packed_chunk(chunk, 42).unwrap();
+            assert!(bytes.len() <= HEADER_SIZE + 4);
+            assert!(&read_packed_chunk(&bytes, 42).unwrap() == chunk);
+        }
+    }
+
+    #[test]
+    fn read_old_versions() {
+        for &format_version in &[1, 2] {
+            let bytes = write_full_chunk(&make_test_data(), format_version);
+            assert!(read_packed_chunk(&bytes, 42).unwrap() == make_test_chunk());
+        }
+        // Version 1 always wrote every block, even if they were all the same.
+        let mut data = PackedChunkData::new();
+        for material in data.materials.iter_mut() {
+            *material = 1234;
+        }
+        let bytes = write_full_chunk(&data, 1);
+        assert!(read_packed_chunk(&bytes, 42).unwrap() == PackedChunk::Uniform(1234));
+    }
+
+    #[test]
+    fn detect_problems() {
+        let bytes = write_packed_chunk(&make_test_chunk(), 42).unwrap();
+        match read_packed_chunk(&bytes, 43).err() {
+            Some(ChunkFileError::SeedMismatch { .. }) => (),
+            other => panic!("Expected a seed mismatch, got {:?}", other),
+        }
+        match read_packed_chunk(&bytes[..HEADER_SIZE + 5], 42).err() {
+            Some(ChunkFileError::Truncated) => (),
+            other => panic!("Expected truncated data, got {:?}", other),
+        }
+        let mut corrupt = bytes.clone();
+        *corrupt.last_mut().unwrap() ^= 0xFF;
+        match read_packed_chunk(&corrupt, 42).err() {
+            Some(ChunkFileError::ChecksumMismatch { .. }) => (),
+            other => panic!("Expected a checksum mismatch, got {:?}", other),
+        }
+        let mut old_version = bytes.clone();
+        old_version[4] = 0;
+        match read_packed_chunk(&old_version, 42).err() {
+            Some(ChunkFileError::UnsupportedVersion(0)) => (),
+            other => panic!("Expected an unsupported version, got {:?}", other),
+        }
+        let mut unknown_kind = bytes.clone();
+        unknown_kind[9] = 9;
+        match read_packed_chunk(&unknown_kind, 42).err() {
+            Some(ChunkFileError::UnknownBodyKind(9)) => (),
+            other => panic!("Expected an unknown body kind, got {:?}", other),
+        }
+        let mut other_lod = bytes.clone();
+        other_lod[8] = MAX_CHUNK_LOD as u8 + 1;
+        match read_packed_chunk(&other_lod, 42).err() {
+            Some(ChunkFileError::LodMismatch { found, expected }) => {
+                assert_eq!(found as usize, MAX_CHUNK_LOD + 1);
+                assert_eq!(expected as usize, MAX_CHUNK_LOD);
+            }
+            other => panic!("Expected a LOD mismatch, got {:?}", other),
+        }
+        let legacy = write_legacy_packed_chunk_data(&make_test_data()).unwrap();
+        assert!(is_legacy_data(&legacy));
+        match read_packed_chunk(&legacy, 42).err() {
+            Some(ChunkFileError::MaterialEncodingMismatch { found, expected }) => {
+                assert_eq!(found, LEGACY_MATERIAL_ENCODING_VERSION);
+                assert_eq!(expected, MATERIAL_ENCODING_VERSION);
+            }
+            other => panic!("Expected a material encoding mismatch, got {:?}", other),
+        }
+    }
+}
@@ -1,183 +1,631 @@
-use super::{Heightmap, PackedChunkData, UnpackedChunkData};
-use array_macro::array;
-use lz4::{Decoder, EncoderBuilder};
-use std::fs::File;
-use std::io::{self, Read, Write};
+use super::chunk_file::ChunkFileError;
+use super::library::{WorldLibrary, DEFAULT_WORLD_NAME};
+use super::loader::{ChunkLoader, ChunkSource, LoadResult};
+use super::{create_generator, WorldManifest};
+use super::{ChunkCache, ChunkStore, DirectoryStore, DEFAULT_CACHE_BUDGET};
+use super::{PackedChunk, UnpackedChunkData};
+use crate::render::constants::*;
+use crate::util::{self, prelude::*};
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 pub type ChunkStorageCoord = (isize, isize, isize);
 
-const HEADER_SIZE: u64 = 16;
-const NUM_BUFFERS: usize = 256;
+/// Splits a world coordinate into the coordinate of the chunk containing it and the coordinate of
+/// the block inside that chunk.
+pub fn locate_block(coord: &util::SignedCoord3D) -> (ChunkStorageCoord, util::Coord3D) {
+    let size = CHUNK_SIZE as isize;
+    (
+        (
+            coord.0.div_euclid(size),
+            coord.1.div_euclid(size),
+            coord.2.div_euclid(size),
+        ),
+        (
+            coord.0.rem_euclid(size) as usize,
+            coord.1.rem_euclid(size) as usize,
+            coord.2.rem_euclid(size) as usize,
+        ),
+    )
+}
 
 pub struct ChunkStorage {
-    storage_dir: PathBuf,
-    uc_buffers: [UnpackedChunkData; NUM_BUFFERS],
-    available_uc_buffers: Vec<usize>,
-    pc_buffers: [PackedChunkData; NUM_BUFFERS],
-    available_pc_buffers: Vec<usize>,
+    source: Arc<ChunkSource>,
+    loader: ChunkLoader,
+    manifest: WorldManifest,
+    // Where to save the manifest, None if the world is not saved anywhere.
+    manifest_path: Option<PathBuf>,
+    // Edited chunks are kept here until they are flushed, reads of them are served from here too.
+    dirty_chunks: HashMap<ChunkStorageCoord, PackedChunk>,
+    // Chunks that could not be loaded, so that they are not requested again and again.
+    failed_chunks: HashSet<ChunkStorageCoord>,
+    // Chunks whose blocks have changed since take_edited_chunks was last called.
+    edited_chunks: HashSet<ChunkStorageCoord>,
+    cache: ChunkCache,
+    // Used while generating chunks on the calling thread.
+    uc_buffer: UnpackedChunkData,
 }
 
 impl ChunkStorage {
+    /// Opens the default world in the user's world library, creating it if it does not exist.
     pub fn new() -> ChunkStorage {
-        let storage_dir = dirs::config_dir()
-            .expect("System somehow doesn't have a config dir?")
-            .join("raytrace")
-            .join("world");
-        std::fs::create_dir_all(&storage_dir).expect("Failed to create chunk storage directory.");
-        ChunkStorage {
-            storage_dir,
-            uc_buffers: array![UnpackedChunkData::new(); NUM_BUFFERS],
-            available_uc_buffers: (0..NUM_BUFFERS).collect(),
-            pc_buffers: array![PackedChunkData::new(); NUM_BUFFERS],
-            available_pc_buffers: (0..NUM_BUFFERS).collect(),
-        }
-    }
-
-    fn get_path_for(base: &PathBuf, coord: &ChunkStorageCoord) -> PathBuf {
-        let filename = format!("{:016X}{:016X}{:016X}", coord.0, coord.1, coord.2);
-        base.join(filename)
-    }
-
-    fn write_packed_chunk_data(path: &PathBuf, data: &PackedChunkData) -> io::Result<()> {
-        let file = File::create(path)?;
-        let mut writer = EncoderBuilder::new().level(4).build(file)?;
-        unsafe {
-            let mat_slice = &data.materials[..];
-            let mat_slice_u8 =
-                std::slice::from_raw_parts(mat_slice.as_ptr() as *const u8, mat_slice.len() * 4);
-            writer.write_all(mat_slice_u8)?;
-        }
-        writer.write_all(&data.minefield)?;
-        writer.finish().1?;
-        Ok(())
+        let library = WorldLibrary::open_default().expect("Failed to open world library.");
+        if !library.exists(DEFAULT_WORLD_NAME) {
+            library
+                .create(&WorldManifest::new(DEFAULT_WORLD_NAME, 0))
+                .expect("Failed to create default world.");
+        }
+        Self::open_world(&library, DEFAULT_WORLD_NAME).expect("Failed to open default world.")
+    }
+
+    /// Opens a world from a library. The world must already exist.
+    pub fn open_world(library: &WorldLibrary, name: &str) -> io::Result<ChunkStorage> {
+        let manifest = library.load_manifest(name)?;
+        let store = DirectoryStore::open(library.get_world_dir(name))?;
+        let mut storage = Self::with_store(Box::new(store), manifest)?;
+        storage.manifest_path = Some(library.get_manifest_path(name));
+        Ok(storage)
+    }
+
+    /// Creates storage for a world that keeps its chunks in the given store. The manifest will not
+    /// be saved anywhere. Fails if the manifest asks for a generator that does not exist.
+    pub fn with_store(
+        store: Box<dyn ChunkStore>,
+        manifest: WorldManifest,
+    ) -> io::Result<ChunkStorage> {
+        let generator = create_generator(
+            &manifest.generator,
+            manifest.seed,
+            &manifest.generator_params,
+        )?;
+        let source = Arc::new(ChunkSource::new(store, generator, manifest.seed));
+        Ok(ChunkStorage {
+            loader: ChunkLoader::new(Arc::clone(&source)),
+            source,
+            manifest,
+            manifest_path: None,
+            dirty_chunks: HashMap::new(),
+            failed_chunks: HashSet::new(),
+            edited_chunks: HashSet::new(),
+            cache: ChunkCache::new(DEFAULT_CACHE_BUDGET),
+            uc_buffer: UnpackedChunkData::new(),
+        })
     }
 
-    fn read_into_packed_chunk_data(path: &PathBuf, data: &mut PackedChunkData) -> io::Result<()> {
-        let file = File::open(path)?;
-        let mut reader = Decoder::new(file)?;
+    pub fn borrow_manifest(&self) -> &WorldManifest {
+        &self.manifest
+    }
 
-        unsafe {
-            let mat_slice = &mut data.materials[..];
-            let mat_slice_u8 =
-                std::slice::from_raw_parts_mut(mat_slice.as_ptr() as *mut u8, mat_slice.len() * 4);
-            reader.read_exact(mat_slice_u8)?;
+    /// The seed and generator in the manifest can not be changed once the world has been opened.
+    pub fn borrow_manifest_mut(&mut self) -> &mut WorldManifest {
+        &mut self.manifest
+    }
+
+    /// Writes the manifest back to the world directory, if the world has one.
+    pub fn save_manifest(&self) -> io::Result<()> {
+        if let Some(path) = &self.manifest_path {
+            self.manifest.save(path)
+        } else {
+            Ok(())
         }
-        reader.read_exact(&mut data.minefield[..])?;
-        Ok(())
     }
 
-    fn has_chunk(&self, coord: &ChunkStorageCoord) -> bool {
-        Self::get_path_for(&self.storage_dir, coord).exists()
+    /// True if the chunk has been stored before. Edits that have not been flushed yet don't count.
+    pub fn has_chunk(&self, coord: &ChunkStorageCoord) -> bool {
+        self.source.lock_store().has_chunk(coord)
     }
 
-    fn generate_and_store_chunk(&mut self, coord: &ChunkStorageCoord) -> (usize, usize) {
-        let pc_buffer_index = self.available_pc_buffers.pop().unwrap();
-        let uc_buffer_index = self.available_uc_buffers.pop().unwrap();
+    /// The cache holds chunks that have recently been loaded, so that they do not have to be read
+    /// from the store again.
+    pub fn borrow_cache(&self) -> &ChunkCache {
+        &self.cache
+    }
 
-        let mut heightmap = Heightmap::new();
-        super::generate_heightmap(&mut heightmap, &(coord.0, coord.1));
-        let unpacked_data = &mut self.uc_buffers[uc_buffer_index];
-        super::generate_chunk(unpacked_data, &(coord.0, coord.1, coord.2), &heightmap);
-        let packed_data = &mut self.pc_buffers[pc_buffer_index];
-        unpacked_data.pack_into(packed_data);
-        if let Err(err) = Self::write_packed_chunk_data(
-            &Self::get_path_for(&self.storage_dir, coord),
-            &self.pc_buffers[pc_buffer_index],
-        ) {
-            println!("WARNING: Failed to write chunk data for {:?}.", coord);
-            println!("Caused by: {}", err);
+    /// Loads the generator's config files again if they have changed. Returns true if they did.
+    /// Chunks that have already been generated keep their old terrain.
+    pub fn reload_generator(&self) -> io::Result<bool> {
+        self.source.reload_generator()
+    }
+
+    /// Sets how much memory cached chunks may take up, in bytes.
+    pub fn set_cache_budget(&mut self, budget: usize) {
+        self.cache.set_budget(budget);
+    }
+
+    /// True if borrowing the chunk will not have to wait for it to be loaded or generated.
+    pub fn is_chunk_ready(&self, coord: &ChunkStorageCoord) -> bool {
+        self.dirty_chunks.contains_key(coord) || self.cache.contains(coord)
+    }
+
+    /// True if the chunk could not be loaded, for example because its stored data is corrupt.
+    /// Such chunks are not loaded again until they are regenerated.
+    pub fn is_chunk_failed(&self, coord: &ChunkStorageCoord) -> bool {
+        self.failed_chunks.contains(coord)
+    }
+
+    /// Starts loading or generating the chunk in the background, unless it is already available
+    /// or has failed to load before. Call receive_ready_chunks to collect chunks once they are
+    /// done.
+    pub fn request_chunk(&mut self, coord: &ChunkStorageCoord) {
+        if !self.is_chunk_ready(coord) && !self.is_chunk_failed(coord) {
+            self.loader.request(coord, false);
         }
+    }
 
-        (pc_buffer_index, uc_buffer_index)
+    /// Generates the chunk again in the background and stores the result, replacing whatever was
+    /// stored before. Chunks with edits that have not been flushed are left alone, in which case
+    /// false is returned and nothing will be received for the chunk.
+    pub fn request_regeneration(&mut self, coord: &ChunkStorageCoord) -> bool {
+        if self.dirty_chunks.contains_key(coord) {
+            return false;
+        }
+        self.cache.remove(coord);
+        self.failed_chunks.remove(coord);
+        self.loader.request(coord, true);
+        true
+    }
+
+    pub fn get_num_workers(&self) -> usize {
+        self.loader.get_num_workers()
     }
 
-    fn load_chunk_data(&mut self, coord: &ChunkStorageCoord) -> (usize, usize) {
-        if self.has_chunk(coord) {
-            let pc_buffer_index = self.available_pc_buffers.pop().unwrap();
-            let uc_buffer_index = self.available_uc_buffers.pop().unwrap();
+    /// Changes how many threads load chunks in the background. Waits for chunks that are currently
+    /// being loaded to finish first.
+    pub fn set_num_workers(&mut self, num_workers: usize) {
+        while let Some(result) = self.loader.receive() {
+            self.add_loaded_chunk(result);
+        }
+        self.loader = ChunkLoader::with_workers(Arc::clone(&self.source), num_workers.max(1));
+    }
+
+    /// How many requested chunks have not been received yet.
+    pub fn get_num_requested_chunks(&self) -> usize {
+        self.loader.get_num_pending()
+    }
 
-            match Self::read_into_packed_chunk_data(
-                &Self::get_path_for(&self.storage_dir, coord),
-                &mut self.pc_buffers[pc_buffer_index],
-            ) {
-                Ok(..) => {
-                    self.pc_buffers[pc_buffer_index]
-                        .unpack_into(&mut self.uc_buffers[uc_buffer_index]);
-                    (pc_buffer_index, uc_buffer_index)
+    fn add_loaded_chunk(&mut self, (coord, result): LoadResult) {
+        match result {
+            Ok(data) => {
+                self.failed_chunks.remove(&coord);
+                // Chunks edited in the meantime are newer than what was loaded.
+                if !self.dirty_chunks.contains_key(&coord) {
+                    self.cache.insert(coord, data);
                 }
-                Err(err) => {
-                    println!("WARNING: Failed to read chunk data for {:?}.", coord);
+            }
+            Err(err) => {
+                if self.failed_chunks.insert(coord) {
+                    println!("WARNING: Failed to load chunk {:?}.", coord);
                     println!("Caused by: {}", err);
-                    self.available_pc_buffers.push(pc_buffer_index);
-                    self.available_uc_buffers.push(uc_buffer_index);
-                    self.generate_and_store_chunk(coord)
                 }
             }
-        } else {
-            self.generate_and_store_chunk(coord)
         }
     }
 
-    fn load_packed_chunk_data(&mut self, coord: &ChunkStorageCoord) -> usize {
-        if self.has_chunk(coord) {
-            let pc_buffer_index = self.available_pc_buffers.pop().unwrap();
+    /// Waits until a requested chunk has finished loading and adds it to the cache. Returns the
+    /// coordinate of the chunk and whether it loaded successfully, or None if no chunks have been
+    /// requested.
+    pub fn receive_next_chunk(
+        &mut self,
+    ) -> Option<(ChunkStorageCoord, Result<(), ChunkFileError>)> {
+        let (coord, result) = self.loader.receive()?;
+        match result {
+            Ok(data) => {
+                self.add_loaded_chunk((coord, Ok(data)));
+                Some((coord, Ok(())))
+            }
+            Err(err) => {
+                // The caller reports the error.
+                self.failed_chunks.insert(coord);
+                Some((coord, Err(err)))
+            }
+        }
+    }
 
-            match Self::read_into_packed_chunk_data(
-                &Self::get_path_for(&self.storage_dir, coord),
-                &mut self.pc_buffers[pc_buffer_index],
-            ) {
-                Ok(..) => pc_buffer_index,
-                Err(err) => {
-                    println!("WARNING: Failed to read chunk data for {:?}.", coord);
-                    println!("Caused by: {}", err);
-                    self.available_pc_buffers.push(pc_buffer_index);
-                    let (pc_index, unused) = self.generate_and_store_chunk(coord);
-                    self.available_uc_buffers.push(unused);
-                    pc_index
+    /// Adds all chunks that have finished loading in the background to the cache. Does not wait.
+    pub fn receive_ready_chunks(&mut self) {
+        while let Some(result) = self.loader.try_receive() {
+            self.add_loaded_chunk(result);
+        }
+    }
+
+    fn load_packed_chunk(
+        &mut self,
+        coord: &ChunkStorageCoord,
+    ) -> Result<PackedChunk, ChunkFileError> {
+        if self.loader.is_pending(coord) {
+            // A worker is already on it, doing it here as well would write the chunk twice.
+            loop {
+                let result = self.loader.receive().unwrap();
+                if result.0 == *coord {
+                    return result.1;
                 }
+                self.add_loaded_chunk(result);
             }
-        } else {
-            let (pc_index, unused) = self.generate_and_store_chunk(coord);
-            self.available_uc_buffers.push(unused);
-            pc_index
         }
+        self.source.load_chunk(coord, &mut self.uc_buffer)
+    }
+
+    /// Loads the chunk at the given coordinate, generating it if it has never been stored before.
+    /// Blocks until the chunk is available, use request_chunk to load chunks in the background.
+    /// Stored chunks that are corrupt or were written by an incompatible version of the program
+    /// produce an error instead of being regenerated, and are remembered as failed.
+    pub fn borrow_packed_chunk(
+        &mut self,
+        coord: &ChunkStorageCoord,
+    ) -> Result<&PackedChunk, ChunkFileError> {
+        if self.dirty_chunks.contains_key(coord) {
+            return Ok(&self.dirty_chunks[coord]);
+        }
+        if self.cache.touch(coord) {
+            return Ok(self.cache.borrow(coord).unwrap());
+        }
+        match self.load_packed_chunk(coord) {
+            Ok(data) => {
+                self.failed_chunks.remove(coord);
+                Ok(self.cache.insert(*coord, data))
+            }
+            Err(err) => {
+                self.failed_chunks.insert(*coord);
+                Err(err)
+            }
+        }
+    }
+
+    /// Returns the material of the block at the given world coordinate, as an index into
+    /// MATERIALS.
+    pub fn get_block(&mut self, coord: &util::SignedCoord3D) -> Result<u32, ChunkFileError> {
+        let (chunk_coord, block_coord) = locate_block(coord);
+        let index = util::coord_to_index_3d(&block_coord, CHUNK_SIZE);
+        let packed = self.borrow_packed_chunk(&chunk_coord)?;
+        Ok(packed.get_material(index))
+    }
+
+    // Moves the chunk into the dirty chunks, loading it first if needed, and returns it.
+    fn borrow_dirty_chunk(
+        &mut self,
+        chunk_coord: &ChunkStorageCoord,
+    ) -> Result<&mut PackedChunk, ChunkFileError> {
+        if !self.dirty_chunks.contains_key(chunk_coord) {
+            self.borrow_packed_chunk(chunk_coord)?;
+            let packed = self.cache.remove(chunk_coord).unwrap();
+            self.dirty_chunks.insert(*chunk_coord, packed);
+        }
+        Ok(self.dirty_chunks.get_mut(chunk_coord).unwrap())
+    }
+
+    /// Changes the material of the block at the given world coordinate. The change is kept in
+    /// memory until flush is called.
+    pub fn set_block(
+        &mut self,
+        coord: &util::SignedCoord3D,
+        value: u32,
+    ) -> Result<(), ChunkFileError> {
+        let (chunk_coord, block_coord) = locate_block(coord);
+        let index = util::coord_to_index_3d(&block_coord, CHUNK_SIZE);
+        self.borrow_dirty_chunk(&chunk_coord)?
+            .set_material(index, value);
+        self.edited_chunks.insert(chunk_coord);
+        Ok(())
+    }
+
+    /// Changes the material of every block in the box of the given size starting at the given
+    /// world coordinate, which may span several chunks. Like set_block, the change is kept in
+    /// memory until flush is called. The minefield of a chunk never depends on the blocks of its
+    /// neighbors, so only the chunks the box overlaps change.
+    pub fn fill_box(
+        &mut self,
+        start: &util::SignedCoord3D,
+        size: util::Coord3D,
+        value: u32,
+    ) -> Result<(), ChunkFileError> {
+        if size.0 == 0 || size.1 == 0 || size.2 == 0 {
+            return Ok(());
+        }
+        let end = start.add(size.signed());
+        let (first_chunk, _) = locate_block(start);
+        let (last_chunk, _) = locate_block(&end.sub((1, 1, 1)));
+        let mut chunk_coords = Vec::new();
+        for z in first_chunk.2..=last_chunk.2 {
+            for y in first_chunk.1..=last_chunk.1 {
+                for x in first_chunk.0..=last_chunk.0 {
+                    chunk_coords.push((x, y, z));
+                }
+            }
+        }
+        // Load every chunk before changing any, so that a chunk failing to load leaves the blocks
+        // of the others alone.
+        for chunk_coord in &chunk_coords {
+            self.borrow_dirty_chunk(chunk_coord)?;
+        }
+        let unsigned =
+            |coord: util::SignedCoord3D| (coord.0 as usize, coord.1 as usize, coord.2 as usize);
+        for chunk_coord in chunk_coords {
+            let origin = chunk_coord.scale(CHUNK_SIZE as isize);
+            let chunk_start = start.sub(origin).ewmax((0, 0, 0));
+            let chunk_end = end.sub(origin).ewmin(CHUNK_SIZE.repeat().signed());
+            let chunk = self.dirty_chunks.get_mut(&chunk_coord).unwrap();
+            chunk.fill_box(
+                unsigned(chunk_start),
+                unsigned(chunk_end.sub(chunk_start)),
+                value,
+            );
+            self.edited_chunks.insert(chunk_coord);
+        }
+        Ok(())
+    }
+
+    pub fn is_dirty(&self, coord: &ChunkStorageCoord) -> bool {
+        self.dirty_chunks.contains_key(coord)
     }
 
-    pub fn borrow_packed_chunk_data(&mut self, coord: &ChunkStorageCoord) -> &PackedChunkData {
-        let index = self.load_packed_chunk_data(coord);
-        self.available_pc_buffers.push(index);
-        &self.pc_buffers[index]
+    /// Returns the chunks whose blocks have been changed by set_block or fill_box since this was
+    /// last called, so that copies of them (like the ones on the GPU) can be updated.
+    pub fn take_edited_chunks(&mut self) -> Vec<ChunkStorageCoord> {
+        self.edited_chunks.drain().collect()
+    }
+
+    /// Writes all edited chunks to the store and waits until they are on disk. Chunks that fail to
+    /// write stay dirty so that writing them can be tried again later.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        let coords: Vec<_> = self.dirty_chunks.keys().cloned().collect();
+        for coord in coords {
+            match self.source.write_chunk(&coord, &self.dirty_chunks[&coord]) {
+                Ok(()) => {
+                    let chunk = self.dirty_chunks.remove(&coord).unwrap();
+                    self.cache.insert(coord, chunk);
+                }
+                Err(err) => result = result.and(Err(err)),
+            }
+        }
+        result?;
+        self.source.lock_store().flush()
+    }
+}
+
+impl Drop for ChunkStorage {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            println!("WARNING: Failed to save edited chunks.");
+            println!("Caused by: {}", err);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use rand::RngCore;
+    use crate::render::AIR_MATERIAL;
+    use crate::world::chunk_file;
+    use crate::world::{MemoryStore, PackedChunkData};
 
-    fn make_temp_dir() -> PathBuf {
-        let path = std::env::temp_dir().join(format!(
-            "raytraceTestDir{:08X}",
-            rand::thread_rng().next_u32()
-        ));
-        std::fs::create_dir(&path).unwrap();
-        path
+    use crate::render::materials::STONE;
+
+    fn make_storage() -> ChunkStorage {
+        ChunkStorage::with_store(Box::new(MemoryStore::new()), WorldManifest::new("test", 0))
+            .unwrap()
     }
 
-    fn cleanup(dir: PathBuf) {
-        std::fs::remove_dir_all(dir).unwrap();
+    fn clear_cache(storage: &mut ChunkStorage) {
+        storage.set_cache_budget(0);
+        storage.set_cache_budget(DEFAULT_CACHE_BUDGET);
     }
 
     #[test]
     fn generate() {
-        let mut storage = ChunkStorage {
-            storage_dir: make_temp_dir(),
-            ..ChunkStorage::new()
-        };
+        let mut storage = make_storage();
+
+        storage.borrow_packed_chunk(&(0, 0, 0)).unwrap();
+    }
+
+    #[test]
+    fn reload() {
+        let mut storage = make_storage();
+        let generated = storage.borrow_packed_chunk(&(0, -1, 0)).unwrap().clone();
+        assert!(storage.has_chunk(&(0, -1, 0)));
+        assert!(!storage.has_chunk(&(0, -2, 0)));
+        clear_cache(&mut storage);
+        assert!(storage.borrow_packed_chunk(&(0, -1, 0)).unwrap() == &generated);
+    }
+
+    #[test]
+    fn cache_reads() {
+        let mut storage = make_storage();
+        storage.borrow_packed_chunk(&(0, 0, -1)).unwrap();
+        // Changing the stored data should not matter while the chunk is cached.
+        storage
+            .source
+            .lock_store()
+            .write_chunk(&(0, 0, -1), &[1, 2, 3])
+            .unwrap();
+        storage.borrow_packed_chunk(&(0, 0, -1)).unwrap();
+        let stats = storage.borrow_cache().get_stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+
+        storage.set_cache_budget(0);
+        assert!(storage.borrow_packed_chunk(&(0, 0, -1)).is_err());
+        assert_eq!(storage.borrow_cache().get_stats().evictions, 1);
+    }
+
+    #[test]
+    fn load_in_background() {
+        let mut storage = make_storage();
+        storage.request_chunk(&(2, 0, 0));
+        storage.request_chunk(&(3, 0, 0));
+        // Borrowing a requested chunk should wait for the worker instead of loading it again.
+        storage.borrow_packed_chunk(&(3, 0, 0)).unwrap();
+        assert!(storage.is_chunk_ready(&(3, 0, 0)));
+        while storage.get_num_requested_chunks() > 0 {
+            storage.receive_ready_chunks();
+        }
+        assert!(storage.is_chunk_ready(&(2, 0, 0)));
+        assert!(storage.has_chunk(&(2, 0, 0)));
+    }
+
+    #[test]
+    fn corrupt_chunk_in_background() {
+        let mut storage = make_storage();
+        let coord = (4, -1, 0);
+        storage
+            .source
+            .lock_store()
+            .write_chunk(&coord, &[1, 2, 3])
+            .unwrap();
+        storage.request_chunk(&coord);
+        while storage.get_num_requested_chunks() > 0 {
+            storage.receive_ready_chunks();
+        }
+        assert!(!storage.is_chunk_ready(&coord));
+        assert!(storage.is_chunk_failed(&coord));
+        // The chunk should not be loaded again every time it is requested.
+        storage.request_chunk(&coord);
+        assert_eq!(storage.get_num_requested_chunks(), 0);
+
+        // Regenerating the chunk replaces the corrupt data.
+        assert!(storage.request_regeneration(&coord));
+        assert!(!storage.is_chunk_failed(&coord));
+        while storage.get_num_requested_chunks() > 0 {
+            storage.receive_ready_chunks();
+        }
+        assert!(storage.is_chunk_ready(&coord));
+        assert!(!storage.is_chunk_failed(&coord));
+    }
+
+    #[test]
+    fn edit_and_flush() {
+        let mut storage = make_storage();
+        let coord = (-1, 5, 70);
+        let (chunk_coord, block_coord) = locate_block(&coord);
+        assert_eq!(chunk_coord, (-1, 0, 1));
+        assert_eq!(block_coord, (CHUNK_SIZE - 1, 5, 6));
+        let index = util::coord_to_index_3d(&block_coord, CHUNK_SIZE);
+
+        storage.set_block(&coord, STONE).unwrap();
+        assert!(storage.is_dirty(&chunk_coord));
+        assert_eq!(storage.get_block(&coord).unwrap(), STONE);
+        // The edit should be visible before it is flushed.
+        let packed = storage.borrow_packed_chunk(&chunk_coord).unwrap();
+        assert!(matches!(packed, PackedChunk::NonEmpty(..)));
+        assert_eq!(packed.get_material(index), STONE);
+        assert_eq!(packed.get_distance(index), 0);
+        let edited = packed.clone();
+
+        storage.flush().unwrap();
+        assert!(!storage.is_dirty(&chunk_coord));
+        assert!(storage.borrow_packed_chunk(&chunk_coord).unwrap() == &edited);
+        assert_eq!(storage.get_block(&coord).unwrap(), STONE);
+    }
+
+    #[test]
+    fn store_uniform_chunks() {
+        let mut storage = make_storage();
+        let coord = (0, 0, 20);
+        assert!(storage.borrow_packed_chunk(&coord).unwrap() == &PackedChunk::Empty);
+        let bytes = storage.source.lock_store().read_chunk(&coord).unwrap();
+        assert_eq!(bytes.unwrap().len(), chunk_file::HEADER_SIZE);
+
+        // Filling a chunk with a single material through edits turns it into a uniform chunk.
+        let origin = coord.scale(CHUNK_SIZE as isize);
+        for block_coord in util::coord_iter_3d(CHUNK_SIZE) {
+            let block_coord = origin.add(block_coord.signed());
+            storage.set_block(&block_coord, STONE).unwrap();
+        }
+        let uniform = PackedChunk::Uniform(STONE);
+        assert!(storage.borrow_packed_chunk(&coord).unwrap() == &uniform);
+        storage.flush().unwrap();
+        clear_cache(&mut storage);
+        assert!(storage.borrow_packed_chunk(&coord).unwrap() == &uniform);
+        assert_eq!(storage.get_block(&origin).unwrap(), STONE);
+    }
 
-        storage.borrow_packed_chunk_data(&(0, 0, 0));
+    #[test]
+    fn fill_box_across_chunks() {
+        let mut storage = make_storage();
+        let start = (-2, 60, 100);
+        storage.fill_box(&start, (4, 8, 1), STONE).unwrap();
+        for coord in &[(-2, 60, 100), (-1, 63, 100), (0, 64, 100), (1, 67, 100)] {
+            assert_eq!(storage.get_block(coord).unwrap(), STONE);
+        }
+        for coord in &[(-3, 60, 100), (2, 60, 100), (0, 68, 100), (0, 64, 101)] {
+            assert_eq!(storage.get_block(coord).unwrap(), AIR_MATERIAL);
+        }
+        for chunk_coord in &[(-1, 0, 1), (0, 0, 1), (-1, 1, 1), (0, 1, 1)] {
+            assert!(storage.is_dirty(chunk_coord));
+        }
+        assert!(!storage.is_dirty(&(1, 0, 1)));
+        let mut edited = storage.take_edited_chunks();
+        edited.sort_unstable();
+        assert_eq!(edited, vec![(-1, 0, 1), (-1, 1, 1), (0, 0, 1), (0, 1, 1)]);
+        assert!(storage.take_edited_chunks().is_empty());
+        // Every chunk the box overlaps has a block in it that rays now stop at.
+        let (chunk_coord, block_coord) = locate_block(&(0, 64, 100));
+        let index = util::coord_to_index_3d(&block_coord, CHUNK_SIZE);
+        let chunk = storage.borrow_packed_chunk(&chunk_coord).unwrap();
+        assert_eq!(chunk.get_distance(index), 0);
+        assert_eq!(chunk.get_distance(index + 2), 2);
+    }
 
-        cleanup(storage.storage_dir);
+    #[test]
+    fn reject_legacy_chunks() {
+        let mut storage = make_storage();
+        let mut data = PackedChunkData::new();
+        data.minefield[7] = 3;
+        data.materials[9] = 1234;
+        let coord = (-3, 2, -1);
+        let bytes = chunk_file::write_legacy_packed_chunk_data(&data).unwrap();
+        storage
+            .source
+            .lock_store()
+            .write_chunk(&coord, &bytes)
+            .unwrap();
+
+        // Legacy chunks store packed colors where material ids are expected now.
+        match storage.borrow_packed_chunk(&coord) {
+            Err(ChunkFileError::MaterialEncodingMismatch { found: 1, .. }) => (),
+            _ => panic!("Expected a material encoding mismatch."),
+        }
+        // The chunk must be left alone instead of being rewritten in the current format.
+        let stored = storage
+            .source
+            .lock_store()
+            .read_chunk(&coord)
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored, bytes);
+    }
+
+    #[test]
+    fn corrupt_chunk_is_not_regenerated() {
+        let mut storage = make_storage();
+        storage.borrow_packed_chunk(&(1, 1, 0)).unwrap();
+        let mut bytes = storage
+            .source
+            .lock_store()
+            .read_chunk(&(1, 1, 0))
+            .unwrap()
+            .unwrap();
+        *bytes.last_mut().unwrap() ^= 0xFF;
+        storage
+            .source
+            .lock_store()
+            .write_chunk(&(1, 1, 0), &bytes)
+            .unwrap();
+        clear_cache(&mut storage);
+        match storage.borrow_packed_chunk(&(1, 1, 0)) {
+            Err(ChunkFileError::ChecksumMismatch { .. }) => (),
+            _ => panic!("Expected a checksum mismatch."),
+        }
+        let bytes = chunk_file::write_packed_chunk(&PackedChunk::Empty, 1).unwrap();
+        storage
+            .source
+            .lock_store()
+            .write_chunk(&(1, 1, 1), &bytes)
+            .unwrap();
+        match storage.borrow_packed_chunk(&(1, 1, 1)) {
+            Err(ChunkFileError::SeedMismatch { .. }) => (),
+            _ => panic!("Expected a seed mismatch."),
+        }
     }
 }
@@ -0,0 +1,420 @@
+use super::generate::{hash_coord, smoothstep};
+use super::worldgen_config::{ErosionConfig, HydraulicConfig, ThermalConfig};
+use crate::render::constants::*;
+use crate::util;
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// Added to the seed so that erosion does not line up with other features.
+const EROSION_SALT: u64 = 0x4552_4F44;
+/// How many eroded tiles are kept in memory. Generating a chunk needs at most four of them, and
+/// chunks near each other use the same ones.
+const MAX_CACHED_TILES: usize = 64;
+
+/// The eroded heights of a tile and the overlap around it.
+struct Tile {
+    heights: Vec<f32>,
+}
+
+// Other threads wait on the mutex while a tile is being eroded, instead of eroding it again.
+type TileSlot = Arc<Mutex<Option<Arc<Tile>>>>;
+
+struct TileCache {
+    // Each slot along with when it was last used.
+    slots: HashMap<util::SignedCoord2D, (TileSlot, u64)>,
+    clock: u64,
+}
+
+/// Runs erosion simulations on large tiles of the heightmap. The simulation of a tile reaches
+/// into the tiles around it, and where tiles overlap their heights are blended, so that there are
+/// no seams. Eroded tiles are cached since every chunk above them needs them.
+pub struct Erosion {
+    config: ErosionConfig,
+    seed: u64,
+    cache: Mutex<TileCache>,
+}
+
+impl Erosion {
+    /// Returns None if the config does not enable any kind of erosion.
+    pub fn new(config: &ErosionConfig, seed: u64) -> Option<Erosion> {
+        if config.hydraulic.is_none() && config.thermal.is_none() {
+            return None;
+        }
+        Some(Erosion {
+            config: config.clone(),
+            seed,
+            cache: Mutex::new(TileCache {
+                slots: HashMap::new(),
+                clock: 0,
+            }),
+        })
+    }
+
+    // How many columns wide a tile is including the overlap on both sides.
+    fn get_tile_width(&self) -> isize {
+        self.config.tile_size + self.config.overlap * 2
+    }
+
+    /// Returns the eroded height of a single column. base_height gives the height of any column
+    /// before erosion.
+    pub fn get_height(&self, x: isize, y: isize, base_height: &dyn Fn(isize, isize) -> f64) -> f64 {
+        let tiles = self.get_tiles_around((x, y), (x + 1, y + 1), base_height);
+        self.blend((x, y), &tiles)
+    }
+
+    /// Fills heights with the eroded height of every column of a chunk, in the same order as a
+    /// Heightmap.
+    pub fn get_chunk_heights(
+        &self,
+        chunk_coord: &util::SignedCoord2D,
+        heights: &mut [f64],
+        base_height: &dyn Fn(isize, isize) -> f64,
+    ) {
+        let origin = util::scale_signed_coord_2d(chunk_coord, CHUNK_SIZE as isize);
+        let end = (
+            origin.0 + CHUNK_SIZE as isize,
+            origin.1 + CHUNK_SIZE as isize,
+        );
+        let tiles = self.get_tiles_around(origin, end, base_height);
+        for (index, (x, y)) in util::coord_iter_2d(CHUNK_SIZE).enumerate() {
+            heights[index] = self.blend((origin.0 + x as isize, origin.1 + y as isize), &tiles);
+        }
+    }
+
+    /// Returns every tile that reaches into the area from start up to but not including end.
+    fn get_tiles_around(
+        &self,
+        start: util::SignedCoord2D,
+        end: util::SignedCoord2D,
+        base_height: &dyn Fn(isize, isize) -> f64,
+    ) -> Vec<(util::SignedCoord2D, Arc<Tile>)> {
+        let (size, overlap) = (self.config.tile_size, self.config.overlap);
+        let mut tiles = Vec::new();
+        for ty in (start.1 - overlap).div_euclid(size)..=(end.1 - 1 + overlap).div_euclid(size) {
+            for tx in (start.0 - overlap).div_euclid(size)..=(end.0 - 1 + overlap).div_euclid(size)
+            {
+                let tile = self.get_tile((tx, ty), base_height);
+                tiles.push(((tx, ty), tile));
+            }
+        }
+        tiles
+    }
+
+    fn get_tile(
+        &self,
+        tile_coord: util::SignedCoord2D,
+        base_height: &dyn Fn(isize, isize) -> f64,
+    ) -> Arc<Tile> {
+        let slot = {
+            let mut cache = self.cache.lock().unwrap();
+            cache.clock += 1;
+            let clock = cache.clock;
+            let slot = Arc::clone(
+                &cache
+                    .slots
+                    .entry(tile_coord)
+                    .and_modify(|(_, last_used)| *last_used = clock)
+                    .or_insert_with(|| (Arc::new(Mutex::new(None)), clock))
+                    .0,
+            );
+            if cache.slots.len() > MAX_CACHED_TILES {
+                let oldest = *cache
+                    .slots
+                    .iter()
+                    .min_by_key(|(_, (_, last_used))| *last_used)
+                    .unwrap()
+                    .0;
+                cache.slots.remove(&oldest);
+            }
+            slot
+        };
+        let mut slot = slot.lock().unwrap();
+        if slot.is_none() {
+            *slot = Some(Arc::new(self.erode_tile(tile_coord, base_height)));
+        }
+        Arc::clone(slot.as_ref().unwrap())
+    }
+
+    fn erode_tile(
+        &self,
+        tile_coord: util::SignedCoord2D,
+        base_height: &dyn Fn(isize, isize) -> f64,
+    ) -> Tile {
+        let width = self.get_tile_width();
+        let start = self.get_tile_start(tile_coord);
+        let mut heights = Vec::with_capacity((width * width) as usize);
+        for y in 0..width {
+            for x in 0..width {
+                heights.push(base_height(start.0 + x, start.1 + y) as f32);
+            }
+        }
+        let mut map = HeightGrid {
+            heights,
+            width: width as usize,
+        };
+        let seed = hash_coord(
+            self.seed.wrapping_add(EROSION_SALT),
+            &(tile_coord.0, tile_coord.1, 0),
+        );
+        let mut random = StdRng::seed_from_u64(seed);
+        if let Some(hydraulic) = &self.config.hydraulic {
+            for _ in 0..hydraulic.droplets {
+                map.run_droplet(hydraulic, &mut random);
+            }
+        }
+        if let Some(thermal) = &self.config.thermal {
+            map.slide(thermal);
+        }
+        Tile {
+            heights: map.heights,
+        }
+    }
+
+    // The coordinate of the first column of a tile, including its overlap.
+    fn get_tile_start(&self, tile_coord: util::SignedCoord2D) -> util::SignedCoord2D {
+        let (size, overlap) = (self.config.tile_size, self.config.overlap);
+        (tile_coord.0 * size - overlap, tile_coord.1 * size - overlap)
+    }
+
+    /// How much a tile counts towards the height of a column along one axis. Fades in and out over
+    /// the overlap on each side, so that the weights of neighboring tiles always add up to 1.
+    fn get_axis_weight(&self, offset: isize) -> f64 {
+        let width = self.get_tile_width();
+        if offset < 0 || offset >= width {
+            return 0.0;
+        }
+        let from_edge = (offset.min(width - 1 - offset)) as f64 + 0.5;
+        smoothstep((from_edge / (self.config.overlap * 2) as f64).min(1.0))
+    }
+
+    fn blend(&self, coord: util::SignedCoord2D, tiles: &[(util::SignedCoord2D, Arc<Tile>)]) -> f64 {
+        let width = self.get_tile_width();
+        let (mut height, mut total_weight) = (0.0, 0.0);
+        for (tile_coord, tile) in tiles {
+            let start = self.get_tile_start(*tile_coord);
+            let offset = (coord.0 - start.0, coord.1 - start.1);
+            let weight = self.get_axis_weight(offset.0) * self.get_axis_weight(offset.1);
+            if weight > 0.0 {
+                height += tile.heights[(offset.1 * width + offset.0) as usize] as f64 * weight;
+                total_weight += weight;
+            }
+        }
+        height / total_weight
+    }
+}
+
+struct HeightGrid {
+    heights: Vec<f32>,
+    width: usize,
+}
+
+impl HeightGrid {
+    /// Returns the height at a point between columns along with the direction of the slope there.
+    fn get_height_and_gradient(&self, position: (f64, f64)) -> (f64, (f64, f64)) {
+        let (x, y) = (position.0 as usize, position.1 as usize);
+        let (fx, fy) = (position.0 - x as f64, position.1 - y as f64);
+        let index = y * self.width + x;
+        let nw = self.heights[index] as f64;
+        let ne = self.heights[index + 1] as f64;
+        let sw = self.heights[index + self.width] as f64;
+        let se = self.heights[index + self.width + 1] as f64;
+        let gradient = (
+            (ne - nw) * (1.0 - fy) + (se - sw) * fy,
+            (sw - nw) * (1.0 - fx) + (se - ne) * fx,
+        );
+        let height = nw * (1.0 - fx) * (1.0 - fy)
+            + ne * fx * (1.0 - fy)
+            + sw * (1.0 - fx) * fy
+            + se * fx * fy;
+        (height, gradient)
+    }
+
+    /// Adds amount to the four columns around a point, split up by how close they are.
+    fn add_around(&mut self, position: (f64, f64), amount: f64) {
+        let (x, y) = (position.0 as usize, position.1 as usize);
+        let (fx, fy) = (position.0 - x as f64, position.1 - y as f64);
+        let index = y * self.width + x;
+        self.heights[index] += (amount * (1.0 - fx) * (1.0 - fy)) as f32;
+        self.heights[index + 1] += (amount * fx * (1.0 - fy)) as f32;
+        self.heights[index + self.width] += (amount * (1.0 - fx) * fy) as f32;
+        self.heights[index + self.width + 1] += (amount * fx * fy) as f32;
+    }
+
+    /// Simulates a drop of rain that runs downhill, picking up sediment where it speeds up and
+    /// dropping it where it slows down.
+    fn run_droplet(&mut self, config: &HydraulicConfig, random: &mut StdRng) {
+        let limit = (self.width - 1) as f64;
+        let mut position = (random.gen::<f64>() * limit, random.gen::<f64>() * limit);
+        let mut direction = (0.0, 0.0);
+        let (mut speed, mut water, mut sediment) = (1.0, 1.0, 0.0);
+        for _ in 0..config.lifetime {
+            let (height, gradient) = self.get_height_and_gradient(position);
+            direction = (
+                direction.0 * config.inertia - gradient.0 * (1.0 - config.inertia),
+                direction.1 * config.inertia - gradient.1 * (1.0 - config.inertia),
+            );
+            let length = (direction.0 * direction.0 + direction.1 * direction.1).sqrt();
+            if length == 0.0 {
+                break;
+            }
+            direction = (direction.0 / length, direction.1 / length);
+            let new_position = (position.0 + direction.0, position.1 + direction.1);
+            if !(0.0..limit).contains(&new_position.0) || !(0.0..limit).contains(&new_position.1) {
+                break;
+            }
+            let height_change = self.get_height_and_gradient(new_position).0 - height;
+            let capacity = (-height_change * speed * water * config.capacity).max(0.01);
+            if height_change > 0.0 || sediment > capacity {
+                // Fill in the pit the droplet is climbing out of, or drop what it can not carry.
+                let amount = if height_change > 0.0 {
+                    height_change.min(sediment)
+                } else {
+                    (sediment - capacity) * config.deposition
+                };
+                sediment -= amount;
+                self.add_around(position, amount);
+            } else {
+                // Never dig deeper than the point the droplet is moving to.
+                let amount = ((capacity - sediment) * config.erosion).min(-height_change);
+                sediment += amount;
+                self.add_around(position, -amount);
+            }
+            speed = (speed * speed - height_change * config.gravity)
+                .max(0.0)
+                .sqrt();
+            water *= 1.0 - config.evaporation;
+            position = new_position;
+        }
+    }
+
+    /// Moves material from columns down to their neighbors wherever the slope between them is
+    /// steeper than the talus.
+    fn slide(&mut self, config: &ThermalConfig) {
+        let width = self.width;
+        let mut changes = vec![0.0f32; self.heights.len()];
+        for _ in 0..config.iterations {
+            for y in 0..width {
+                for x in 0..width {
+                    let index = y * width + x;
+                    let height = self.heights[index];
+                    let mut neighbors = [None; 4];
+                    if x + 1 < width {
+                        neighbors[0] = Some(index + 1);
+                    }
+                    if y + 1 < width {
+                        neighbors[1] = Some(index + width);
+                    }
+                    if x > 0 {
+                        neighbors[2] = Some(index - 1);
+                    }
+                    if y > 0 {
+                        neighbors[3] = Some(index - width);
+                    }
+                    for neighbor in neighbors.iter().flatten() {
+                        let difference = (height - self.heights[*neighbor]) as f64;
+                        if difference > config.talus {
+                            // Each column can slide towards four neighbors at once, so only a
+                            // quarter of the difference moves towards each of them.
+                            let amount = ((difference - config.talus) * config.rate / 4.0) as f32;
+                            changes[index] -= amount;
+                            changes[*neighbor] += amount;
+                        }
+                    }
+                }
+            }
+            for (height, change) in self.heights.iter_mut().zip(changes.iter_mut()) {
+                *height += *change;
+                *change = 0.0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rolling hills on a slope, so that there is something to erode.
+    fn base_height(x: isize, y: isize) -> f64 {
+        let (x, y) = (x as f64, y as f64);
+        x * 0.3 + (x / 9.0).sin() * 6.0 + (y / 7.0).cos() * 5.0 + (x * y / 200.0).sin() * 2.0
+    }
+
+    fn make_erosion() -> Erosion {
+        let config = ErosionConfig {
+            tile_size: 64,
+            overlap: 16,
+            hydraulic: Some(HydraulicConfig {
+                droplets: 2000,
+                ..Default::default()
+            }),
+            thermal: Some(ThermalConfig::default()),
+        };
+        Erosion::new(&config, 11).unwrap()
+    }
+
+    #[test]
+    fn erode_without_seams() {
+        let erosion = make_erosion();
+        let mut heights = vec![0.0; CHUNK_SIZE * CHUNK_SIZE];
+        let mut changed = false;
+        for chunk_coord in &[(-1, -1), (0, -1), (-1, 0), (0, 0), (1, 0)] {
+            erosion.get_chunk_heights(chunk_coord, &mut heights, &base_height);
+            let origin = util::scale_signed_coord_2d(chunk_coord, CHUNK_SIZE as isize);
+            for (index, (x, y)) in util::coord_iter_2d(CHUNK_SIZE).enumerate() {
+                let (x, y) = (origin.0 + x as isize, origin.1 + y as isize);
+                assert_eq!(heights[index], erosion.get_height(x, y, &base_height));
+                changed |= (heights[index] - base_height(x, y)).abs() > 0.5;
+            }
+        }
+        assert!(changed);
+
+        // Walk across several tile borders. The height should never jump much more than the
+        // terrain underneath does.
+        let heights: Vec<_> = (-100..100)
+            .map(|x| erosion.get_height(x, 5, &base_height))
+            .collect();
+        for (x, pair) in (-100..).zip(heights.windows(2)) {
+            let base_change = (base_height(x + 1, 5) - base_height(x, 5)).abs();
+            assert!(
+                (pair[1] - pair[0]).abs() < base_change + 2.0,
+                "{} {:?}",
+                x,
+                pair
+            );
+        }
+
+        // Tiles do not depend on what was cached before them.
+        let fresh = make_erosion();
+        assert_eq!(
+            fresh.get_height(70, -20, &base_height),
+            erosion.get_height(70, -20, &base_height)
+        );
+    }
+
+    #[test]
+    fn thermal_erosion() {
+        let config = ErosionConfig {
+            thermal: Some(ThermalConfig {
+                iterations: 200,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let erosion = Erosion::new(&config, 0).unwrap();
+        // A tall pillar should slump into a mound.
+        let pillar = |x: isize, y: isize| if (x, y) == (10, 10) { 100.0 } else { 0.0 };
+        let center = erosion.get_height(10, 10, &pillar);
+        assert!(center < 50.0);
+        assert!(erosion.get_height(11, 10, &pillar) > 1.0);
+        let mut total = 0.0;
+        for y in -20..40 {
+            for x in -20..40 {
+                total += erosion.get_height(x, y, &pillar);
+            }
+        }
+        assert!((total - 100.0).abs() < 0.1, "{}", total);
+    }
+}
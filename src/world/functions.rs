@@ -1,8 +1,36 @@
-use noise::{NoiseFn, OpenSimplex, BasicMulti, Worley};
+use noise::{BasicMulti, Fbm, MultiFractal, NoiseFn, OpenSimplex, Seedable, Worley};
+use serde::Deserialize;
+
+/// Gives the height of terrain at a point, mostly in the range 0-1.
+pub trait HeightFunction: Send + Sync {
+    fn get_height(&self, x: f64, y: f64) -> f64;
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MountainNoiseParams {
+    /// How much smaller the small details are than the large mountains.
+    pub detail_frequency: f64,
+    /// How much the large simplex noise that varies the height of mountains is scaled.
+    pub variation_frequency: f64,
+    /// Higher values make slopes steeper near the tops of mountains.
+    pub exponent: f64,
+}
+
+impl Default for MountainNoiseParams {
+    fn default() -> Self {
+        Self {
+            detail_frequency: 4.0,
+            variation_frequency: 0.8,
+            exponent: 2.2,
+        }
+    }
+}
 
 pub struct MountainNoise {
     simplex: OpenSimplex,
     worley: Worley,
+    params: MountainNoiseParams,
 }
 
 fn clip(value: f64) -> f64 {
@@ -24,15 +52,16 @@ fn magnitude(dx: f64, dy: f64) -> f64 {
 }
 
 impl MountainNoise {
-    pub fn new() -> MountainNoise {
-        let worley = Worley::new();
+    pub fn new(seed: u32, params: &MountainNoiseParams) -> MountainNoise {
+        let worley = Worley::new().set_seed(seed);
         // Enables using distance to the nearest point
         let worley = worley.enable_range(true);
         // Disables adding the value of the nearest point.
         let worley = worley.set_displacement(0.0);
         MountainNoise {
-            simplex: OpenSimplex::new(),
+            simplex: OpenSimplex::new().set_seed(seed),
             worley,
+            params: params.clone(),
         }
     }
 
@@ -42,7 +71,8 @@ impl MountainNoise {
         let mut base = self.worley.get([x, y]) + 1.0;
 
         // Smaller details.
-        let mut detail = self.worley.get([x * 4.0, y * 4.0]) + 1.0;
+        let frequency = self.params.detail_frequency;
+        let mut detail = self.worley.get([x * frequency, y * frequency]) + 1.0;
         detail = map_to_range(detail, 0.73, 1.0);
         // Only have details close to high points on the macroscopic texture.
         detail *= map_from_range(base, 0.34, 0.79);
@@ -53,10 +83,11 @@ impl MountainNoise {
         base += detail;
         base /= 2.0;
         // Make everything more slopey.
-        base = base.powf(2.2);
+        base = base.powf(self.params.exponent);
 
         // Get some large Simplex noise.
-        let mut rustle = self.simplex.get([x * 0.8, y * 0.8]) + 0.5;
+        let frequency = self.params.variation_frequency;
+        let mut rustle = self.simplex.get([x * frequency, y * frequency]) + 0.5;
         rustle = map_to_range(map_from_range(rustle, 0.15, 1.0), 0.15, 1.0);
         rustle = rustle.powf(2.0);
         // Use it to vary the height of our mountains, to make it less monotonous.
@@ -66,17 +97,55 @@ impl MountainNoise {
     }
 }
 
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MountainNoise2Params {
+    pub octaves: usize,
+    pub frequency: f64,
+    pub lacunarity: f64,
+    pub persistence: f64,
+    /// How far apart the samples used to find the slope of the noise are.
+    pub slope_distance: f64,
+    /// How much flat areas are raised compared to steep ones.
+    pub erosion: f64,
+    /// The noise is divided by this before the exponent is applied.
+    pub divisor: f64,
+    /// Higher values make valleys wider and mountains sharper.
+    pub exponent: f64,
+}
+
+impl Default for MountainNoise2Params {
+    fn default() -> Self {
+        Self {
+            octaves: BasicMulti::DEFAULT_OCTAVES,
+            frequency: BasicMulti::DEFAULT_FREQUENCY,
+            lacunarity: BasicMulti::DEFAULT_LACUNARITY,
+            persistence: 0.5,
+            slope_distance: 0.2,
+            erosion: 0.7,
+            divisor: 1.5,
+            exponent: 2.6,
+        }
+    }
+}
+
 pub struct MountainNoise2 {
     simplex: BasicMulti,
+    params: MountainNoise2Params,
 }
 
 impl MountainNoise2 {
-    pub fn new() -> MountainNoise2 {
-        let mut result = MountainNoise2 {
-            simplex: BasicMulti::new(),
-        };
-        result.simplex.persistence = 0.5;
-        result
+    pub fn new(seed: u32, params: &MountainNoise2Params) -> MountainNoise2 {
+        let simplex = BasicMulti::new()
+            .set_seed(seed)
+            .set_octaves(params.octaves)
+            .set_frequency(params.frequency)
+            .set_lacunarity(params.lacunarity)
+            .set_persistence(params.persistence);
+        MountainNoise2 {
+            simplex,
+            params: params.clone(),
+        }
     }
 
     fn get_noise(&self, coord: [f64; 2]) -> f64 {
@@ -84,7 +153,7 @@ impl MountainNoise2 {
     }
 
     pub fn get(&self, x: f64, y: f64) -> f64 {
-        let d = 0.2;
+        let d = self.params.slope_distance;
         let left = self.get_noise([x - d, y]);
         let right = self.get_noise([x + d, y]);
         let up = self.get_noise([x, y - d]);
@@ -93,7 +162,50 @@ impl MountainNoise2 {
         let slope = magnitude(dx, dy);
 
         let base = self.get_noise([x, y]);
-        let eroded = base + (1.0 - slope) * 0.7;
-        (eroded / 1.5).powf(2.6)
+        let eroded = base + (1.0 - slope) * self.params.erosion;
+        (eroded / self.params.divisor).powf(self.params.exponent)
+    }
+}
+
+/// Smooth noise giving the temperature and moisture at a point, both in the range 0-1.
+pub struct ClimateNoise {
+    temperature: Fbm,
+    moisture: Fbm,
+    contrast: f64,
+}
+
+impl ClimateNoise {
+    pub fn new(seed: u32, contrast: f64) -> ClimateNoise {
+        // Different seeds than the terrain so that the climate does not follow the terrain.
+        let make_noise = |offset| {
+            Fbm::new()
+                .set_seed(seed.wrapping_add(offset))
+                .set_octaves(4)
+        };
+        ClimateNoise {
+            temperature: make_noise(1),
+            moisture: make_noise(2),
+            contrast,
+        }
+    }
+
+    pub fn get(&self, x: f64, y: f64) -> (f64, f64) {
+        let map = |value: f64| clip(value * self.contrast * 0.5 + 0.5);
+        (
+            map(self.temperature.get([x, y])),
+            map(self.moisture.get([x, y])),
+        )
+    }
+}
+
+impl HeightFunction for MountainNoise {
+    fn get_height(&self, x: f64, y: f64) -> f64 {
+        self.get(x, y)
+    }
+}
+
+impl HeightFunction for MountainNoise2 {
+    fn get_height(&self, x: f64, y: f64) -> f64 {
+        self.get(x, y)
     }
 }
@@ -1,85 +1,779 @@
-use super::{functions, Heightmap, UnpackedChunkData};
-use crate::render::{constants::*, Material, MATERIALS};
+use super::caves::Caves;
+use super::erosion::Erosion;
+use super::functions::{self, HeightFunction};
+use super::structures::{Structures, Surface};
+use super::water::Water;
+use super::worldgen_config::{self, BiomeConfig, MaterialId, NoiseKind, WorldgenConfig};
+use super::{Heightmap, UnpackedChunkData};
+use crate::render::{constants::*, materials, AIR_MATERIAL};
 use crate::util::{self, prelude::*};
-use lazy_static::lazy_static;
 use rand::prelude::*;
+use rand::rngs::StdRng;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
 
-lazy_static! {
-    static ref MOUNTAIN_NOISE: functions::MountainNoise2 = functions::MountainNoise2::new();
+/// The names of the built in generators, any of which can be used as the generator of a world.
+pub const GENERATOR_NAMES: [&str; 5] = [
+    "biomes",
+    "mountains",
+    "worley_mountains",
+    "superflat",
+    "empty",
+];
+
+/// Decides what the blocks of a world are before they are edited.
+pub trait WorldGenerator: Send + Sync {
+    /// Fills data with the blocks of the chunk at the given coordinate. Generating the same chunk
+    /// again must give exactly the same result, no matter which chunks were generated in between.
+    fn generate_chunk(&self, data: &mut UnpackedChunkData, chunk_coord: &util::SignedCoord3D);
+
+    /// Returns true if the chunk is certainly nothing but air, without generating it. Returning
+    /// false is always allowed, it only means the chunk has to be generated to find out.
+    fn is_chunk_empty(&self, _chunk_coord: &util::SignedCoord3D) -> bool {
+        false
+    }
+
+    /// Loads any config files of the generator again if they have changed since they were last
+    /// loaded. Returns true if the generator changed. If a file is invalid the generator keeps
+    /// using the old version.
+    fn reload(&self) -> io::Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Creates one of the built in generators. Parameters that are not given use default values,
+/// parameters that the generator does not know about are an error.
+pub fn create_generator(
+    name: &str,
+    seed: u64,
+    params: &toml::value::Table,
+) -> io::Result<Box<dyn WorldGenerator>> {
+    Ok(match name {
+        "biomes" | "mountains" | "worley_mountains" => {
+            let params: TerrainParams = parse_params(name, params)?;
+            Box::new(TerrainGenerator::new(name, seed, params.config)?)
+        }
+        "superflat" => {
+            let params: SuperflatParams = parse_params(name, params)?;
+            Box::new(SuperflatGenerator { params })
+        }
+        "empty" => {
+            parse_params::<EmptyParams>(name, params)?;
+            Box::new(EmptyGenerator)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "There is no world generator named '{}', expected one of {}.",
+                    name,
+                    GENERATOR_NAMES.join(", ")
+                ),
+            ))
+        }
+    })
 }
 
-const SCALE: f64 = 0600.0;
+fn parse_params<T: DeserializeOwned>(name: &str, params: &toml::value::Table) -> io::Result<T> {
+    toml::Value::Table(params.clone())
+        .try_into()
+        .map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid parameters for the {} generator: {}", name, err),
+            )
+        })
+}
 
-fn height(x: isize, y: isize) -> isize {
-    (MOUNTAIN_NOISE.get(x as f64 / SCALE, y as f64 / SCALE) * SCALE * 0.2 + 10.0) as isize
+/// The noise functions only take 32 bit seeds. A seed of zero stays zero so that worlds made
+/// before seeds existed keep their terrain.
+fn noise_seed(seed: u64) -> u32 {
+    (seed ^ (seed >> 32)) as u32
 }
 
-pub fn generate_heightmap(
-    data: &mut Heightmap,
-    chunk_coord: &util::SignedCoord2D,
-) {
-    let origin = util::scale_signed_coord_2d(chunk_coord, CHUNK_SIZE as isize);
+// Scrambles the bits of a value so that similar inputs give very different outputs.
+fn mix(value: u64) -> u64 {
+    let mut value = value.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    value ^ (value >> 31)
+}
 
-    let mut index = 0;
-    for (x, y) in util::coord_iter_2d(CHUNK_SIZE) {
-        let (x, y) = (x as isize, y as isize);
-        data.data[index] = height(origin.0 + x, origin.1 + y);
-        index += 1;
+/// Returns a number that only depends on the seed and the coordinate, but looks random.
+pub(super) fn hash_coord(seed: u64, coord: &util::SignedCoord3D) -> u64 {
+    let mut state = mix(seed);
+    for &component in &[coord.0, coord.1, coord.2] {
+        state = mix(state ^ component as u64);
     }
+    state
+}
+
+/// Returns a random number generator that only depends on the seed and the coordinate of the
+/// chunk, so that chunks come out the same no matter what order they are generated in.
+fn chunk_random(seed: u64, chunk_coord: &util::SignedCoord3D) -> StdRng {
+    StdRng::seed_from_u64(hash_coord(seed, chunk_coord))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TerrainParams {
+    /// A worldgen config file to use instead of the built in one. Relative paths start from the
+    /// working directory.
+    config: Option<PathBuf>,
+}
+
+/// Returns a random number from 0 to 1 that only depends on the seed and the coordinate of a
+/// column, so that it is the same in every chunk the column passes through.
+fn column_random(seed: u64, x: isize, y: isize) -> f64 {
+    let value = mix(mix(mix(seed) ^ x as u64) ^ y as u64);
+    (value >> 11) as f64 / (1u64 << 53) as f64
+}
+
+pub(super) fn smoothstep(value: f64) -> f64 {
+    value * value * (3.0 - 2.0 * value)
+}
+
+// How many columns of chunks Terrain keeps the heightmaps of. Each one takes up about 100 KB.
+const MAX_CACHED_COLUMNS: usize = 256;
+
+/// The parts of a column of chunks that are the same in every chunk of it.
+struct ChunkColumns {
+    heightmap: Heightmap,
+    // The index of the biome each column is made of.
+    biomes: Vec<usize>,
+    // How high water goes in each column, or NO_WATER.
+    water_levels: Vec<isize>,
+    // Only structures reach this height or above, everything else is air.
+    top: isize,
 }
 
-fn material(random: &mut ThreadRng, height: isize) -> usize {
-    if height < 20 {
-        2
-    } else if height < 80 {
-        let threshold = (height - 20) as u32;
-        if random.next_u32() % (80 - 20) < threshold {
-            5
+type ColumnSlot = Arc<Mutex<Option<Arc<ChunkColumns>>>>;
+
+// Heightmaps of chunk columns that have been used recently. Every chunk of a column needs the same
+// heightmap, which is expensive to generate with erosion and water.
+struct ColumnCache {
+    // Each slot along with when it was last used. A slot is empty while its column is generated,
+    // so that other threads wait for it instead of generating it too.
+    slots: HashMap<util::SignedCoord2D, (ColumnSlot, u64)>,
+    clock: u64,
+}
+
+// A noise function along with the scale and weight of its layer.
+type NoiseLayer = (Box<dyn HeightFunction>, f64, f64);
+
+/// Everything the noise based generators need from a worldgen config, ready to use.
+struct Terrain {
+    config: WorldgenConfig,
+    seed: u64,
+    // None if there is only one biome, so there is nothing to choose between.
+    climate: Option<functions::ClimateNoise>,
+    // The noise layers of each biome.
+    biome_layers: Vec<Vec<NoiseLayer>>,
+    caves: Caves,
+    water: Water,
+    // None if the config does not enable erosion.
+    erosion: Option<Erosion>,
+    structures: Structures,
+    columns: Mutex<ColumnCache>,
+}
+
+impl Terrain {
+    fn new(config: WorldgenConfig, seed: u64) -> Terrain {
+        let noise_seed = noise_seed(seed);
+        let make_layer = |layer: &worldgen_config::NoiseLayer| -> NoiseLayer {
+            let noise: Box<dyn HeightFunction> = match &layer.noise {
+                NoiseKind::ErodedFractal(params) => {
+                    Box::new(functions::MountainNoise2::new(noise_seed, params))
+                }
+                NoiseKind::Worley(params) => {
+                    Box::new(functions::MountainNoise::new(noise_seed, params))
+                }
+            };
+            (noise, layer.scale, layer.weight)
+        };
+        let biome_layers = config
+            .biomes
+            .iter()
+            .map(|biome| biome.layers.iter().map(make_layer).collect())
+            .collect();
+        let climate = if config.biomes.len() > 1 {
+            let contrast = config.climate.contrast;
+            Some(functions::ClimateNoise::new(noise_seed, contrast))
+        } else {
+            None
+        };
+        let caves = Caves::new(&config.caves, seed, noise_seed);
+        let water = Water::new(&config.water, seed, noise_seed);
+        let erosion = Erosion::new(&config.erosion, seed);
+        let structures = Structures::new(&config.structures, &config.biomes, seed);
+        Terrain {
+            config,
+            seed,
+            climate,
+            biome_layers,
+            caves,
+            water,
+            erosion,
+            structures,
+            columns: Mutex::new(ColumnCache {
+                slots: HashMap::new(),
+                clock: 0,
+            }),
+        }
+    }
+
+    fn biome_height(&self, biome: usize, x: isize, y: isize) -> f64 {
+        let mut value = 0.0;
+        for (noise, scale, weight) in &self.biome_layers[biome] {
+            value += noise.get_height(x as f64 / scale, y as f64 / scale) * weight;
+        }
+        self.config.biomes[biome].map_height(value)
+    }
+
+    /// Returns the height of a column and the index of the biome whose materials it is made of.
+    fn column(&self, x: isize, y: isize) -> (isize, usize) {
+        let (height, biome) = self.uneroded_column(x, y);
+        if let Some(erosion) = &self.erosion {
+            let base_height = |x, y| self.uneroded_column(x, y).0;
+            (erosion.get_height(x, y, &base_height) as isize, biome)
         } else {
-            2
+            (height as isize, biome)
+        }
+    }
+
+    /// Describes a column the way it ends up after water is added, for placing structures.
+    fn surface(&self, x: isize, y: isize) -> Surface {
+        let (height, biome) = self.column(x, y);
+        let column_height = |x, y| self.column(x, y).0;
+        let (height, water_level) = self.water.fill_column(x, y, height, &column_height);
+        Surface {
+            height,
+            biome,
+            water_level,
         }
-    } else if height < 160 {
-        let threshold = (height - 80) as u32;
-        if random.next_u32() % (160 - 80) < threshold {
-            6
+    }
+
+    /// Like column, but returns the height before it is eroded.
+    fn uneroded_column(&self, x: isize, y: isize) -> (f64, usize) {
+        let climate = if let Some(climate) = &self.climate {
+            climate
         } else {
-            5
+            return (self.biome_height(0, x, y), 0);
+        };
+        let scale = self.config.climate.scale;
+        let (temperature, moisture) = climate.get(x as f64 / scale, y as f64 / scale);
+        let distance = |biome: &BiomeConfig| {
+            let (dt, dm) = (biome.temperature - temperature, biome.moisture - moisture);
+            (dt * dt + dm * dm).sqrt()
+        };
+        let biomes = &self.config.biomes;
+        let closest = biomes.iter().map(distance).fold(f64::INFINITY, f64::min);
+        let blend = self.config.climate.blend;
+        // Biomes that are almost as close as the closest one are blended in.
+        let weight = |biome: &BiomeConfig| {
+            let extra_distance = distance(biome) - closest;
+            if extra_distance <= 0.0 {
+                1.0
+            } else if extra_distance < blend {
+                smoothstep(1.0 - extra_distance / blend)
+            } else {
+                0.0
+            }
+        };
+
+        let (mut height, mut total_weight) = (0.0, 0.0);
+        for (index, biome) in biomes.iter().enumerate() {
+            let weight = weight(biome);
+            if weight > 0.0 {
+                height += self.biome_height(index, x, y) * weight;
+                total_weight += weight;
+            }
+        }
+        // Where biomes are blended, the materials of one of them are picked at random using the
+        // same weights as the height, so that the materials mix as well.
+        let mut pick = column_random(self.seed, x, y) * total_weight;
+        let mut picked = 0;
+        for (index, biome) in biomes.iter().enumerate() {
+            let weight = weight(biome);
+            if weight > 0.0 {
+                picked = index;
+                if pick < weight {
+                    break;
+                }
+                pick -= weight;
+            }
+        }
+        (height / total_weight, picked)
+    }
+
+    /// Returns the heightmap of a column of chunks, generating it if it is not cached.
+    fn get_columns(&self, chunk_coord: &util::SignedCoord2D) -> Arc<ChunkColumns> {
+        let slot = {
+            let mut cache = self.columns.lock().unwrap();
+            cache.clock += 1;
+            let clock = cache.clock;
+            let slot = Arc::clone(
+                &cache
+                    .slots
+                    .entry(*chunk_coord)
+                    .and_modify(|(_, last_used)| *last_used = clock)
+                    .or_insert_with(|| (Arc::new(Mutex::new(None)), clock))
+                    .0,
+            );
+            if cache.slots.len() > MAX_CACHED_COLUMNS {
+                let oldest = *cache
+                    .slots
+                    .iter()
+                    .min_by_key(|(_, (_, last_used))| *last_used)
+                    .unwrap()
+                    .0;
+                cache.slots.remove(&oldest);
+            }
+            slot
+        };
+        let mut slot = slot.lock().unwrap();
+        if slot.is_none() {
+            *slot = Some(Arc::new(self.generate_columns(chunk_coord)));
+        }
+        Arc::clone(slot.as_ref().unwrap())
+    }
+
+    /// Fills in the height of every column of a chunk, along with the biome it is made of and how
+    /// high water goes in it.
+    fn generate_columns(&self, chunk_coord: &util::SignedCoord2D) -> ChunkColumns {
+        let origin = util::scale_signed_coord_2d(chunk_coord, CHUNK_SIZE as isize);
+        let mut heightmap = Heightmap::new();
+        let mut biomes = vec![0; CHUNK_SIZE * CHUNK_SIZE];
+
+        let mut index = 0;
+        for (x, y) in util::coord_iter_2d(CHUNK_SIZE) {
+            let (x, y) = (x as isize, y as isize);
+            let (height, biome) = self.uneroded_column(origin.0 + x, origin.1 + y);
+            heightmap.data[index] = height as isize;
+            biomes[index] = biome;
+            index += 1;
+        }
+        if let Some(erosion) = &self.erosion {
+            let mut heights = vec![0.0; CHUNK_SIZE * CHUNK_SIZE];
+            let base_height = |x, y| self.uneroded_column(x, y).0;
+            erosion.get_chunk_heights(chunk_coord, &mut heights, &base_height);
+            for (eroded, height) in heights.iter().zip(heightmap.data.iter_mut()) {
+                *height = *eroded as isize;
+            }
+        }
+        let column_height = |x, y| self.column(x, y).0;
+        let water_levels = self
+            .water
+            .fill_columns(&mut heightmap, chunk_coord, &column_height);
+        let max_surface_offset = self.caves.get_max_surface_offset();
+        let top = heightmap
+            .data
+            .iter()
+            .zip(&water_levels)
+            .map(|(height, level)| (height + max_surface_offset).max(*level))
+            .max()
+            .unwrap();
+        ChunkColumns {
+            heightmap,
+            biomes,
+            water_levels,
+            top,
+        }
+    }
+
+    /// See WorldGenerator::is_chunk_empty.
+    fn is_chunk_empty(&self, chunk_coord: &util::SignedCoord3D) -> bool {
+        let origin = chunk_coord.scale(CHUNK_SIZE as isize);
+        if origin.2 + (CHUNK_SIZE as isize) < self.config.solid_below {
+            return false;
         }
-    } else {
-        6
+        let columns = self.get_columns(&(chunk_coord.0, chunk_coord.1));
+        let surface = |x, y| self.surface(x, y);
+        columns.top <= origin.2 && !self.structures.reach_into(chunk_coord, &surface)
     }
 }
 
-pub fn generate_chunk(
-    data: &mut UnpackedChunkData,
-    chunk_coord: &util::SignedCoord3D,
-    heightmap: &super::Heightmap,
-) {
-    let size = CHUNK_SIZE as isize;
-    let origin = chunk_coord.scale(size);
+// A config file that is loaded again when it changes.
+struct ConfigFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+/// Terrain whose height comes from noise, divided into biomes made of different materials. What
+/// exactly it looks like is described by a worldgen config.
+struct TerrainGenerator {
+    seed: u64,
+    // None if the generator uses a built in config.
+    config_file: Option<Mutex<ConfigFile>>,
+    // Chunks hold on to the terrain they started with, so that reloading does not change the
+    // terrain halfway through a chunk.
+    terrain: RwLock<Arc<Terrain>>,
+}
+
+impl TerrainGenerator {
+    fn new(name: &str, seed: u64, config_path: Option<PathBuf>) -> io::Result<TerrainGenerator> {
+        let (config, config_file) = if let Some(path) = config_path {
+            let config = WorldgenConfig::load(&path)?;
+            let modified = std::fs::metadata(&path)?.modified().ok();
+            (config, Some(Mutex::new(ConfigFile { path, modified })))
+        } else {
+            (WorldgenConfig::builtin(name).unwrap(), None)
+        };
+        Ok(TerrainGenerator {
+            seed,
+            config_file,
+            terrain: RwLock::new(Arc::new(Terrain::new(config, seed))),
+        })
+    }
+}
+
+impl WorldGenerator for TerrainGenerator {
+    fn generate_chunk(&self, data: &mut UnpackedChunkData, chunk_coord: &util::SignedCoord3D) {
+        let size = CHUNK_SIZE as isize;
+        let origin = chunk_coord.scale(size);
+        let terrain = Arc::clone(&self.terrain.read().unwrap());
+        let config = &terrain.config;
 
-    let mut random = rand::thread_rng();
+        let columns = terrain.get_columns(&(chunk_coord.0, chunk_coord.1));
+        let (heightmap, water_levels) = (&columns.heightmap, &columns.water_levels);
+        let water = terrain.water.get_material();
+        let mut random = chunk_random(self.seed, chunk_coord);
+        let caves = terrain.caves.prepare_chunk(chunk_coord);
+        let max_surface_offset = terrain.caves.get_max_surface_offset();
+        // Below solid_below the heightmap is ignored, although caves are still carved out.
+        let deep = origin.2 + size < config.solid_below;
 
-    if origin.2 + size < 12 {
-        data.fill(&MATERIALS[2]);
-    } else {
         for coord2d in util::coord_iter_2d(CHUNK_SIZE) {
             let height_val = heightmap.get(&coord2d);
-            if height_val < origin.2 {
+            let index2d = util::coord_to_index_2d(&coord2d, CHUNK_SIZE);
+            let biome = &config.biomes[columns.biomes[index2d]];
+            let water_level = water_levels[index2d];
+            let top = (height_val + max_surface_offset).max(water_level);
+            if !deep && top < origin.2 {
                 for cz in 0..CHUNK_SIZE {
-                    data.set_block(&(coord2d.0, coord2d.1, cz), Material::air());
+                    data.set_block(&(coord2d.0, coord2d.1, cz), AIR_MATERIAL);
                 }
                 continue;
             }
+            let surface = |lz: usize| {
+                height_val as f64 + caves.get_surface_offset(&(coord2d.0, coord2d.1, lz))
+            };
+            // True if the block is below the surface, whether or not a cave is carved there.
+            let underground = |lz: usize| deep || ((origin.2 + lz as isize) as f64) < surface(lz);
             for lz in 0..CHUNK_SIZE {
+                let coord = (coord2d.0, coord2d.1, lz);
                 let z = origin.2 + lz as isize;
-                if z >= height_val {
-                    data.set_block(&(coord2d.0, coord2d.1, lz), Material::air());
+                if !underground(lz) || caves.is_carved(&coord) {
+                    // Caves only flood where they open up into the bottom of the water.
+                    if z < water_level && (!underground(lz) || height_val < water_level) {
+                        data.set_block(&coord, water);
+                    } else {
+                        data.set_block(&coord, AIR_MATERIAL);
+                    }
                     continue;
                 }
-                let material_val = material(&mut random, z);
-                data.set_block(&(coord2d.0, coord2d.1, lz), MATERIALS[material_val].clone());
+                let above = (coord2d.0, coord2d.1, lz + 1);
+                // The floors of caves that are not open to the sky sometimes glow.
+                if let Some(glow_material) = terrain.caves.get_glow_material() {
+                    if caves.is_carved(&above)
+                        && underground(lz + 1)
+                        && terrain.caves.is_glowing(&origin.add(coord.signed()))
+                    {
+                        data.set_block(&coord, glow_material);
+                        continue;
+                    }
+                }
+                let material_val = if deep {
+                    biome.strata[0].material.0
+                } else {
+                    biome.pick_material(&mut random, z, surface(lz).ceil() as isize)
+                };
+                data.set_block(&coord, material_val);
+            }
+        }
+
+        // Structures go last, so that they can be placed on top of everything else.
+        let surface = |x, y| terrain.surface(x, y);
+        terrain.structures.place(data, chunk_coord, &surface);
+    }
+
+    fn is_chunk_empty(&self, chunk_coord: &util::SignedCoord3D) -> bool {
+        self.terrain.read().unwrap().is_chunk_empty(chunk_coord)
+    }
+
+    fn reload(&self) -> io::Result<bool> {
+        let mut file = match &self.config_file {
+            Some(file) => file.lock().unwrap(),
+            None => return Ok(false),
+        };
+        let modified = std::fs::metadata(&file.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified == file.modified {
+            return Ok(false);
+        }
+        // Remember the new time even if loading fails, so that the same error is not reported
+        // over and over again.
+        file.modified = modified;
+        let config = WorldgenConfig::load(&file.path)?;
+        *self.terrain.write().unwrap() = Arc::new(Terrain::new(config, self.seed));
+        Ok(true)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SuperflatParams {
+    /// Every block below this height is solid.
+    height: isize,
+    /// The material the ground is made of.
+    material: MaterialId,
+}
+
+impl Default for SuperflatParams {
+    fn default() -> Self {
+        Self {
+            height: 16,
+            material: MaterialId(materials::GRASS),
+        }
+    }
+}
+
+/// Flat ground made of a single material.
+struct SuperflatGenerator {
+    params: SuperflatParams,
+}
+
+impl WorldGenerator for SuperflatGenerator {
+    fn generate_chunk(&self, data: &mut UnpackedChunkData, chunk_coord: &util::SignedCoord3D) {
+        let origin = chunk_coord.scale(CHUNK_SIZE as isize);
+        let ground = self.params.material.0;
+        for coord in util::coord_iter_3d(CHUNK_SIZE) {
+            if origin.2 + (coord.2 as isize) < self.params.height {
+                data.set_block(&coord, ground);
+            } else {
+                data.set_block(&coord, AIR_MATERIAL);
+            }
+        }
+    }
+
+    fn is_chunk_empty(&self, chunk_coord: &util::SignedCoord3D) -> bool {
+        chunk_coord.2 * CHUNK_SIZE as isize >= self.params.height
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EmptyParams {}
+
+/// Nothing but air.
+struct EmptyGenerator;
+
+impl WorldGenerator for EmptyGenerator {
+    fn generate_chunk(&self, data: &mut UnpackedChunkData, _chunk_coord: &util::SignedCoord3D) {
+        data.fill(AIR_MATERIAL);
+    }
+
+    fn is_chunk_empty(&self, _chunk_coord: &util::SignedCoord3D) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::MATERIALS;
+    use crate::world::PackedChunk;
+
+    fn generate(generator: &dyn WorldGenerator, coord: &util::SignedCoord3D) -> PackedChunk {
+        let mut unpacked = UnpackedChunkData::new();
+        generator.generate_chunk(&mut unpacked, coord);
+        unpacked.pack()
+    }
+
+    #[test]
+    fn deterministic() {
+        let coords = [(0, 0, 0), (0, 0, 1), (3, -2, 1), (-1, 7, 2)];
+        for name in &GENERATOR_NAMES {
+            let generator = create_generator(name, 7, &Default::default()).unwrap();
+            let generator = generator.as_ref();
+            let forwards: Vec<_> = coords.iter().map(|c| generate(generator, c)).collect();
+            let backwards: Vec<_> = coords
+                .iter()
+                .rev()
+                .map(|c| generate(generator, c))
+                .collect();
+            for (first, second) in forwards.iter().zip(backwards.iter().rev()) {
+                assert!(first == second);
+            }
+        }
+        let generator = create_generator("mountains", 7, &Default::default()).unwrap();
+        let other_seed = create_generator("mountains", 8, &Default::default()).unwrap();
+        let first: Vec<_> = coords
+            .iter()
+            .map(|c| generate(generator.as_ref(), c))
+            .collect();
+        let second: Vec<_> = coords
+            .iter()
+            .map(|c| generate(other_seed.as_ref(), c))
+            .collect();
+        assert!(first != second);
+    }
+
+    #[test]
+    fn skip_empty_chunks() {
+        for name in &GENERATOR_NAMES {
+            let generator = create_generator(name, 7, &Default::default()).unwrap();
+            let mut num_empty = 0;
+            for z in -1..10 {
+                let coord = (1, -1, z);
+                if generator.is_chunk_empty(&coord) {
+                    assert!(
+                        generate(generator.as_ref(), &coord) == PackedChunk::Empty,
+                        "{} {}",
+                        name,
+                        z
+                    );
+                    num_empty += 1;
+                }
+            }
+            assert!(num_empty > 0, "{}", name);
+        }
+
+        // Every chunk of a column shares the same heightmap.
+        let terrain = Terrain::new(WorldgenConfig::builtin("mountains").unwrap(), 7);
+        for z in 0..4 {
+            terrain.is_chunk_empty(&(0, 0, z));
+        }
+        assert_eq!(terrain.columns.lock().unwrap().slots.len(), 1);
+    }
+
+    #[test]
+    fn reload_config() {
+        let config = |height: f64| {
+            format!(
+                "solid_below = 0\n\
+                 [[biomes]]\n\
+                 name = \"flat\"\n\
+                 height_curve = [[0.0, {0}], [1.0, {0}]]\n\
+                 [[biomes.layers]]\n\
+                 scale = 100.0\n\
+                 noise = {{ kind = \"worley\" }}\n\
+                 [[biomes.strata]]\n\
+                 material = \"stone\"\n\
+                 height = 0",
+                height
+            )
+        };
+        let path = std::env::temp_dir().join(format!(
+            "raytrace-worldgen-{:08X}.toml",
+            rand::thread_rng().next_u32()
+        ));
+        std::fs::write(&path, config(30.0)).unwrap();
+        let mut params = toml::value::Table::new();
+        let path_value = toml::Value::String(path.to_str().unwrap().to_owned());
+        params.insert("config".to_owned(), path_value);
+        let generator = create_generator("mountains", 0, &params).unwrap();
+        let mut unpacked = UnpackedChunkData::new();
+        let is_solid = |unpacked: &UnpackedChunkData| {
+            let material = unpacked.materials[util::coord_to_index_3d(&(0, 0, 35), CHUNK_SIZE)];
+            MATERIALS[material as usize].is_solid()
+        };
+        generator.generate_chunk(&mut unpacked, &(0, 0, 0));
+        assert!(!is_solid(&unpacked));
+        assert!(!generator.reload().unwrap());
+
+        let set_modified = |seconds| {
+            let time = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(seconds);
+            let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+            file.set_modified(time).unwrap();
+        };
+        std::fs::write(&path, config(40.0)).unwrap();
+        set_modified(1000);
+        assert!(generator.reload().unwrap());
+        generator.generate_chunk(&mut unpacked, &(0, 0, 0));
+        assert!(is_solid(&unpacked));
+
+        // An invalid config should be reported and leave the terrain as it was.
+        std::fs::write(&path, "solid_below = []").unwrap();
+        set_modified(2000);
+        assert!(generator.reload().is_err());
+        assert!(!generator.reload().unwrap());
+        generator.generate_chunk(&mut unpacked, &(0, 0, 0));
+        assert!(is_solid(&unpacked));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn blend_biomes() {
+        let biome = |name: &str, temperature: f64, height: f64, material: &str| {
+            format!(
+                "[[biomes]]\n\
+                 name = \"{name}\"\n\
+                 temperature = {temperature}\n\
+                 height_curve = [[0.0, {height}], [1.0, {height}]]\n\
+                 surface_material = \"{material}\"\n\
+                 layers = [{{ scale = 100.0, noise = {{ kind = \"worley\" }} }}]\n\
+                 strata = [{{ material = \"stone\", height = 0 }}]\n",
+                name = name,
+                temperature = temperature,
+                height = height,
+                material = material
+            )
+        };
+        let text = format!(
+            "solid_below = 0\n\
+             climate = {{ scale = 1000.0, contrast = 2.0, blend = 0.3 }}\n\
+             {}{}",
+            biome("low", 0.0, 20.0, "grass"),
+            biome("high", 1.0, 60.0, "snow")
+        );
+        let terrain = Terrain::new(WorldgenConfig::parse(&text).unwrap(), 3);
+        let columns: Vec<_> = (0..6000).map(|x| terrain.column(x, 0)).collect();
+        let heights: Vec<_> = columns.iter().map(|column| column.0).collect();
+        assert!(heights.contains(&20));
+        assert!(heights.contains(&60));
+        // The height should change gradually between the biomes instead of jumping.
+        assert!(heights.iter().any(|height| *height > 25 && *height < 55));
+        for pair in heights.windows(2) {
+            assert!((pair[0] - pair[1]).abs() <= 2, "{:?}", pair);
+        }
+        for (height, biome) in columns {
+            if height == 20 {
+                assert_eq!(biome, 0);
+            } else if height == 60 {
+                assert_eq!(biome, 1);
             }
         }
     }
+
+    #[test]
+    fn generator_params() {
+        let mut params = toml::value::Table::new();
+        params.insert("height".to_owned(), toml::Value::Integer(70));
+        let stone = toml::Value::String("stone".to_owned());
+        params.insert("material".to_owned(), stone);
+        let generator = create_generator("superflat", 0, &params).unwrap();
+        let mut unpacked = UnpackedChunkData::new();
+        generator.generate_chunk(&mut unpacked, &(2, -3, 1));
+        let get_block = |coord| unpacked.materials[util::coord_to_index_3d(coord, CHUNK_SIZE)];
+        assert_eq!(get_block(&(0, 0, 5)), materials::STONE);
+        assert_eq!(get_block(&(9, 9, 6)), AIR_MATERIAL);
+
+        params.insert("material".to_owned(), toml::Value::Integer(1000));
+        assert!(create_generator("superflat", 0, &params).is_err());
+        let unknown = toml::Value::String("cheese".to_owned());
+        params.insert("material".to_owned(), unknown);
+        assert!(create_generator("superflat", 0, &params).is_err());
+        params.insert("mystery".to_owned(), toml::Value::Integer(1));
+        assert!(create_generator("superflat", 0, &params).is_err());
+        assert!(create_generator("empty", 0, &params).is_err());
+        assert!(create_generator("mountains", 0, &params).is_err());
+        assert!(create_generator("volcanoes", 0, &Default::default()).is_err());
+    }
 }
@@ -0,0 +1,222 @@
+use super::manifest::MANIFEST_FILE_NAME;
+use super::WorldManifest;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The name of the world that is used when no other world is specified.
+pub const DEFAULT_WORLD_NAME: &str = "default";
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+// Copies everything in one directory into another, including subdirectories and their contents.
+fn copy_dir_contents(from: &Path, to: &Path) -> io::Result<()> {
+    for entry in std::fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            std::fs::create_dir_all(&target)?;
+            copy_dir_contents(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+/// A directory containing named worlds. Each world is a subdirectory containing a manifest and the
+/// world's chunks.
+pub struct WorldLibrary {
+    dir: PathBuf,
+}
+
+impl WorldLibrary {
+    pub fn default_dir() -> PathBuf {
+        dirs::config_dir()
+            .expect("System somehow doesn't have a config dir?")
+            .join("raytrace")
+            .join("worlds")
+    }
+
+    /// Opens the library in the given directory, creating the directory if it does not exist.
+    pub fn open(dir: PathBuf) -> io::Result<WorldLibrary> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(WorldLibrary { dir })
+    }
+
+    /// Opens the library in the user's config directory. Before worlds had names, there was a
+    /// single world stored in the config directory, this moves it into the library as the default
+    /// world.
+    pub fn open_default() -> io::Result<WorldLibrary> {
+        let library = Self::open(Self::default_dir())?;
+        let legacy_dir = dirs::config_dir()
+            .expect("System somehow doesn't have a config dir?")
+            .join("raytrace")
+            .join("world");
+        if legacy_dir.is_dir() && !library.exists(DEFAULT_WORLD_NAME) {
+            println!(
+                "Moving the world in {:?} into the world library as '{}'.",
+                legacy_dir, DEFAULT_WORLD_NAME
+            );
+            let world_dir = library.get_world_dir(DEFAULT_WORLD_NAME);
+            std::fs::rename(&legacy_dir, &world_dir)?;
+            // Old worlds were always generated from mountains with a seed of zero.
+            let mut manifest = WorldManifest::new(DEFAULT_WORLD_NAME, 0);
+            manifest.generator = "mountains".to_owned();
+            manifest.save(&world_dir.join(MANIFEST_FILE_NAME))?;
+        }
+        Ok(library)
+    }
+
+    pub fn get_dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn get_world_dir(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    pub fn get_manifest_path(&self, name: &str) -> PathBuf {
+        self.get_world_dir(name).join(MANIFEST_FILE_NAME)
+    }
+
+    /// World names are used as directory names, so they can't contain anything that would make
+    /// them point somewhere else.
+    pub fn check_name(name: &str) -> io::Result<()> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == ' ');
+        if valid {
+            Ok(())
+        } else {
+            Err(invalid_input(format!(
+                "'{}' is not a valid world name, names can only contain letters, numbers, spaces, \
+                 '_' and '-'.",
+                name
+            )))
+        }
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.get_manifest_path(name).is_file()
+    }
+
+    /// Returns the manifests of all worlds in the library, sorted by name.
+    pub fn list(&self) -> io::Result<Vec<WorldManifest>> {
+        let mut worlds = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let manifest_path = entry?.path().join(MANIFEST_FILE_NAME);
+            if !manifest_path.is_file() {
+                continue;
+            }
+            match WorldManifest::load(&manifest_path) {
+                Ok(manifest) => worlds.push(manifest),
+                Err(err) => {
+                    println!("WARNING: Failed to read {:?}.", manifest_path);
+                    println!("Caused by: {}", err);
+                }
+            }
+        }
+        worlds.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(worlds)
+    }
+
+    /// Creates a new, empty world described by the given manifest.
+    pub fn create(&self, manifest: &WorldManifest) -> io::Result<()> {
+        Self::check_name(&manifest.name)?;
+        if self.exists(&manifest.name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("A world named '{}' already exists.", manifest.name),
+            ));
+        }
+        std::fs::create_dir_all(self.get_world_dir(&manifest.name))?;
+        manifest.save(&self.get_manifest_path(&manifest.name))
+    }
+
+    pub fn load_manifest(&self, name: &str) -> io::Result<WorldManifest> {
+        Self::check_name(name)?;
+        if !self.exists(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("There is no world named '{}'.", name),
+            ));
+        }
+        WorldManifest::load(&self.get_manifest_path(name))
+    }
+
+    /// Copies a world and all its chunks to a new world with a different name.
+    pub fn copy(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut manifest = self.load_manifest(from)?;
+        manifest.name = to.to_owned();
+        self.create(&manifest)?;
+        copy_dir_contents(&self.get_world_dir(from), &self.get_world_dir(to))?;
+        // The copied manifest still has the old name.
+        manifest.save(&self.get_manifest_path(to))
+    }
+
+    pub fn delete(&self, name: &str) -> io::Result<()> {
+        // Make sure the directory actually contains a world before deleting it.
+        self.load_manifest(name)?;
+        std::fs::remove_dir_all(self.get_world_dir(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn make_library() -> WorldLibrary {
+        WorldLibrary::open(std::env::temp_dir().join(format!(
+            "raytraceTestLibrary{:08X}",
+            rand::thread_rng().next_u32()
+        )))
+        .unwrap()
+    }
+
+    #[test]
+    fn manage_worlds() {
+        let library = make_library();
+        library.create(&WorldManifest::new("first", 12)).unwrap();
+        assert!(library.create(&WorldManifest::new("first", 13)).is_err());
+        assert!(library
+            .create(&WorldManifest::new("../escape", 13))
+            .is_err());
+        std::fs::write(library.get_world_dir("first").join("chunk"), &[1, 2, 3]).unwrap();
+        let nested_dir = library.get_world_dir("first").join("columns").join("0");
+        std::fs::create_dir_all(&nested_dir).unwrap();
+        std::fs::write(nested_dir.join("column"), &[4, 5]).unwrap();
+
+        library.copy("first", "second").unwrap();
+        let names: Vec<_> = library
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|w| w.name)
+            .collect();
+        assert_eq!(names, vec!["first".to_owned(), "second".to_owned()]);
+        let copied = library.load_manifest("second").unwrap();
+        assert_eq!(copied.seed, 12);
+        assert_eq!(
+            std::fs::read(library.get_world_dir("second").join("chunk")).unwrap(),
+            vec![1, 2, 3]
+        );
+        let copied_column = library
+            .get_world_dir("second")
+            .join("columns")
+            .join("0")
+            .join("column");
+        assert_eq!(std::fs::read(copied_column).unwrap(), vec![4, 5]);
+
+        library.delete("first").unwrap();
+        assert!(!library.exists("first"));
+        assert!(library.delete("first").is_err());
+        assert_eq!(library.list().unwrap().len(), 1);
+
+        std::fs::remove_dir_all(library.get_dir()).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+use super::chunk_file::{self, ChunkFileError};
+use super::{ChunkStorageCoord, ChunkStore, PackedChunk};
+use super::{UnpackedChunkData, WorldGenerator};
+use std::collections::HashSet;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+pub type LoadResult = (ChunkStorageCoord, Result<PackedChunk, ChunkFileError>);
+
+struct ChunkRequest {
+    coord: ChunkStorageCoord,
+    // If true the chunk is generated again even if it has been stored before.
+    regenerate: bool,
+}
+
+/// Everything needed to load or generate chunks. Shared between ChunkStorage and its workers.
+pub struct ChunkSource {
+    store: Mutex<Box<dyn ChunkStore>>,
+    generator: Box<dyn WorldGenerator>,
+    // Stored in the header of every chunk so that chunks generated with a different seed are not
+    // mixed into the world.
+    seed: u64,
+}
+
+impl ChunkSource {
+    pub fn new(
+        store: Box<dyn ChunkStore>,
+        generator: Box<dyn WorldGenerator>,
+        seed: u64,
+    ) -> ChunkSource {
+        ChunkSource {
+            store: Mutex::new(store),
+            generator,
+            seed,
+        }
+    }
+
+    pub fn lock_store(&self) -> MutexGuard<'_, Box<dyn ChunkStore>> {
+        self.store.lock().expect("Chunk store was poisoned.")
+    }
+
+    pub fn write_chunk(&self, coord: &ChunkStorageCoord, chunk: &PackedChunk) -> io::Result<()> {
+        // Compress before locking so that other threads can use the store in the meantime.
+        let bytes = chunk_file::write_packed_chunk(chunk, self.seed)?;
+        self.lock_store().write_chunk(coord, &bytes)
+    }
+
+    fn store_chunk(&self, coord: &ChunkStorageCoord, chunk: &PackedChunk) {
+        if let Err(err) = self.write_chunk(coord, chunk) {
+            println!("WARNING: Failed to write chunk data for {:?}.", coord);
+            println!("Caused by: {}", err);
+        }
+    }
+
+    /// Generates the chunk at the given coordinate. Chunks the generator knows are empty are
+    /// skipped without generating or packing them. uc_buffer is used as scratch space.
+    pub fn generate_chunk(
+        &self,
+        coord: &ChunkStorageCoord,
+        uc_buffer: &mut UnpackedChunkData,
+    ) -> PackedChunk {
+        if self.generator.is_chunk_empty(coord) {
+            return PackedChunk::Empty;
+        }
+        self.generator.generate_chunk(uc_buffer, coord);
+        uc_buffer.pack()
+    }
+
+    /// Generates the chunk at the given coordinate and stores it, replacing anything that was
+    /// stored for it before. uc_buffer is used as scratch space.
+    pub fn generate_and_store_chunk(
+        &self,
+        coord: &ChunkStorageCoord,
+        uc_buffer: &mut UnpackedChunkData,
+    ) -> PackedChunk {
+        let chunk = self.generate_chunk(coord, uc_buffer);
+        self.store_chunk(coord, &chunk);
+        chunk
+    }
+
+    /// See WorldGenerator::reload.
+    pub fn reload_generator(&self) -> io::Result<bool> {
+        self.generator.reload()
+    }
+
+    /// Loads the chunk at the given coordinate, generating it if it has never been stored before.
+    /// uc_buffer is used as scratch space while generating.
+    pub fn load_chunk(
+        &self,
+        coord: &ChunkStorageCoord,
+        uc_buffer: &mut UnpackedChunkData,
+    ) -> Result<PackedChunk, ChunkFileError> {
+        let bytes = self.lock_store().read_chunk(coord)?;
+        if let Some(bytes) = bytes {
+            chunk_file::read_packed_chunk(&bytes, self.seed)
+        } else {
+            Ok(self.generate_and_store_chunk(coord, uc_buffer))
+        }
+    }
+}
+
+fn run_worker(
+    source: Arc<ChunkSource>,
+    requests: Arc<Mutex<Receiver<ChunkRequest>>>,
+    results: Sender<LoadResult>,
+    stopping: Arc<AtomicBool>,
+) {
+    let mut uc_buffer = UnpackedChunkData::new();
+    loop {
+        let request = match requests.lock().unwrap().recv() {
+            Ok(request) => request,
+            // The loader has been dropped.
+            Err(..) => return,
+        };
+        if stopping.load(Ordering::Relaxed) {
+            return;
+        }
+        let result = if request.regenerate {
+            Ok(source.generate_and_store_chunk(&request.coord, &mut uc_buffer))
+        } else {
+            source.load_chunk(&request.coord, &mut uc_buffer)
+        };
+        if results.send((request.coord, result)).is_err() {
+            return;
+        }
+    }
+}
+
+/// Loads and generates chunks on background threads. Requested chunks are sent back in the order
+/// they finish, which is not necessarily the order they were requested in.
+pub struct ChunkLoader {
+    // None once the loader is being dropped.
+    request_sender: Option<Sender<ChunkRequest>>,
+    result_receiver: Receiver<LoadResult>,
+    // Chunks that have been requested but not received yet.
+    pending: HashSet<ChunkStorageCoord>,
+    // Set when the workers should stop, even if there are still requests left.
+    stopping: Arc<AtomicBool>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl ChunkLoader {
+    /// Starts a loader with one worker for each processor except the one the caller is using.
+    pub fn new(source: Arc<ChunkSource>) -> ChunkLoader {
+        let num_workers = thread::available_parallelism()
+            .map(|count| count.get())
+            .unwrap_or(2)
+            .saturating_sub(1)
+            .max(1);
+        Self::with_workers(source, num_workers)
+    }
+
+    pub fn with_workers(source: Arc<ChunkSource>, num_workers: usize) -> ChunkLoader {
+        let (request_sender, request_receiver) = mpsc::channel();
+        let (result_sender, result_receiver) = mpsc::channel();
+        let request_receiver = Arc::new(Mutex::new(request_receiver));
+        let stopping = Arc::new(AtomicBool::new(false));
+        let workers = (0..num_workers)
+            .map(|index| {
+                let source = Arc::clone(&source);
+                let requests = Arc::clone(&request_receiver);
+                let results = result_sender.clone();
+                let stopping = Arc::clone(&stopping);
+                thread::Builder::new()
+                    .name(format!("chunk worker {}", index))
+                    .spawn(move || run_worker(source, requests, results, stopping))
+                    .expect("Failed to start chunk worker.")
+            })
+            .collect();
+        ChunkLoader {
+            request_sender: Some(request_sender),
+            result_receiver,
+            pending: HashSet::new(),
+            stopping,
+            workers,
+        }
+    }
+
+    pub fn get_num_workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn is_pending(&self, coord: &ChunkStorageCoord) -> bool {
+        self.pending.contains(coord)
+    }
+
+    pub fn get_num_pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Asks a worker to load the given chunk, unless it has already been requested. If regenerate
+    /// is true the chunk is generated and stored again even if it was stored before.
+    pub fn request(&mut self, coord: &ChunkStorageCoord, regenerate: bool) {
+        if self.pending.insert(*coord) {
+            let request = ChunkRequest {
+                coord: *coord,
+                regenerate,
+            };
+            self.request_sender
+                .as_ref()
+                .unwrap()
+                .send(request)
+                .expect("All chunk workers have stopped.");
+        }
+    }
+
+    /// Returns a chunk that has finished loading, if there is one.
+    pub fn try_receive(&mut self) -> Option<LoadResult> {
+        let result = self.result_receiver.try_recv().ok()?;
+        self.pending.remove(&result.0);
+        Some(result)
+    }
+
+    /// Waits until a requested chunk has finished loading. Returns None if nothing is pending.
+    pub fn receive(&mut self) -> Option<LoadResult> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let result = self
+            .result_receiver
+            .recv()
+            .expect("All chunk workers have stopped.");
+        self.pending.remove(&result.0);
+        Some(result)
+    }
+}
+
+impl Drop for ChunkLoader {
+    fn drop(&mut self) {
+        self.stopping.store(true, Ordering::Relaxed);
+        // Dropping the sender wakes up workers waiting for requests.
+        self.request_sender = None;
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                println!("WARNING: A chunk worker panicked.");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::{create_generator, MemoryStore};
+
+    #[test]
+    fn load_in_background() {
+        let generator = create_generator("mountains", 0, &Default::default()).unwrap();
+        let source = Arc::new(ChunkSource::new(Box::new(MemoryStore::new()), generator, 0));
+        let mut loader = ChunkLoader::with_workers(Arc::clone(&source), 3);
+        let coords = [(0, 0, 0), (1, 0, 0), (0, -1, 2), (5, 5, 5)];
+        for coord in &coords {
+            loader.request(coord, false);
+            loader.request(coord, true);
+        }
+        assert_eq!(loader.get_num_pending(), coords.len());
+        let mut received = HashSet::new();
+        while let Some((coord, result)) = loader.receive() {
+            result.unwrap();
+            assert!(received.insert(coord));
+        }
+        assert_eq!(received.len(), coords.len());
+        // Everything generated by the workers should have been stored.
+        for coord in &coords {
+            assert!(source.lock_store().has_chunk(coord));
+        }
+    }
+
+    #[test]
+    fn store_empty_chunks_without_packing() {
+        let generator = create_generator("mountains", 0, &Default::default()).unwrap();
+        let coord = (0, 0, 20);
+        assert!(generator.is_chunk_empty(&coord));
+        let source = ChunkSource::new(Box::new(MemoryStore::new()), generator, 0);
+        let mut uc_buffer = UnpackedChunkData::new();
+        let chunk = source.generate_and_store_chunk(&coord, &mut uc_buffer);
+        assert!(chunk == PackedChunk::Empty);
+        assert_eq!(chunk.get_size(), 0);
+        // Only the header is stored, there is no body of blocks.
+        let bytes = source.lock_store().read_chunk(&coord).unwrap().unwrap();
+        assert_eq!(bytes.len(), chunk_file::HEADER_SIZE);
+    }
+}
@@ -0,0 +1,80 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The name of the manifest file inside a world directory.
+pub const MANIFEST_FILE_NAME: &str = "world.toml";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CameraState {
+    pub origin: [f32; 3],
+    pub heading: f32,
+    pub pitch: f32,
+}
+
+impl Default for CameraState {
+    fn default() -> Self {
+        CameraState {
+            origin: [-30.0, -128.0, 100.0],
+            heading: std::f32::consts::PI * 0.5,
+            pitch: 0.0,
+        }
+    }
+}
+
+/// Everything about a world that is not stored in its chunks.
+// TOML requires tables to come after plain values, so the order of the fields matters.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WorldManifest {
+    pub name: String,
+    /// Which world generator creates new chunks for this world.
+    pub generator: String,
+    /// TOML can only store signed integers, so seeds are limited to 63 bits.
+    pub seed: u64,
+    /// When the world was created, in seconds since the unix epoch.
+    pub created: u64,
+    #[serde(default)]
+    pub sun_angle: f32,
+    #[serde(default)]
+    pub camera: CameraState,
+    #[serde(default)]
+    pub generator_params: toml::value::Table,
+}
+
+impl WorldManifest {
+    pub fn new(name: &str, seed: u64) -> WorldManifest {
+        WorldManifest {
+            name: name.to_owned(),
+            generator: "biomes".to_owned(),
+            seed,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or(0),
+            sun_angle: 0.0,
+            camera: Default::default(),
+            generator_params: Default::default(),
+        }
+    }
+
+    pub fn load(path: &Path) -> io::Result<WorldManifest> {
+        let text = std::fs::read_to_string(path)?;
+        toml::from_str(&text).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} is not a valid world manifest: {}", path, err),
+            )
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let text =
+            toml::to_string(self).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        // Write to a temporary file first so that a crash while saving can't destroy the old
+        // manifest.
+        let temp_path = path.with_extension("toml.tmp");
+        std::fs::write(&temp_path, text)?;
+        std::fs::rename(&temp_path, path)
+    }
+}
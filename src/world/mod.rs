@@ -1,10 +1,30 @@
+mod cache;
+mod caves;
 mod chunk;
+mod chunk_file;
 mod chunk_storage;
+mod erosion;
 pub(self) mod functions;
 mod generate;
 mod heightmap;
+mod library;
+mod loader;
+mod manifest;
+mod palette;
+mod region;
+mod store;
+mod structures;
+mod water;
+mod worldgen_config;
 
+pub use cache::{CacheStats, ChunkCache, DEFAULT_CACHE_BUDGET};
 pub use chunk::*;
+pub use chunk_file::ChunkFileError;
 pub use chunk_storage::*;
 pub use generate::*;
 pub use heightmap::*;
+pub use library::*;
+pub use manifest::{CameraState, WorldManifest};
+pub use palette::PaletteChunk;
+pub use store::*;
+pub use worldgen_config::WorldgenConfig;
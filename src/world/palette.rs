@@ -0,0 +1,496 @@
+use crate::render::{constants::*, AIR_MATERIAL};
+use crate::util::{self, prelude::*};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, Read, Write};
+
+// Indices are stored in words of this many bits.
+const WORD_BITS: usize = 64;
+// How many bits an index can take up at most.
+const MAX_BITS: usize = 16;
+// How many different materials a chunk can hold. One less than the indices allow, so that the
+// length of the palette fits in the u16 it is written as.
+const MAX_PALETTE_LEN: usize = (1 << MAX_BITS) - 1;
+
+// Returns the smallest number of bits that can hold indices into a palette of the given length.
+// Only powers of two are used, so that an index never crosses the boundary between two words.
+fn bits_for(palette_len: usize) -> usize {
+    let mut bits = 1;
+    while 1 << bits < palette_len {
+        bits *= 2;
+    }
+    bits
+}
+
+// Returns the index of the cube of the given LOD that contains the block at the given index.
+// LOD n is made of cubes 2^n blocks wide.
+fn lod_index(index: usize, lod: usize) -> usize {
+    let (x, y, z) = (
+        index % CHUNK_SIZE,
+        index / CHUNK_SIZE % CHUNK_SIZE,
+        index / (CHUNK_SIZE * CHUNK_SIZE),
+    );
+    let stride = CHUNK_SIZE >> lod;
+    ((z >> lod) * stride + (y >> lod)) * stride + (x >> lod)
+}
+
+fn get_bit(bits: &[u64], index: usize) -> bool {
+    bits[index / 64] & 1 << (index % 64) != 0
+}
+
+fn set_bit(bits: &mut [u64], index: usize, value: bool) {
+    if value {
+        bits[index / 64] |= 1 << (index % 64);
+    } else {
+        bits[index / 64] &= !(1 << (index % 64));
+    }
+}
+
+// Calls f with every coordinate from start up to but not including end.
+fn for_each_in_box(start: Coord3D, end: Coord3D, mut f: impl FnMut(Coord3D)) {
+    for z in start.2..end.2 {
+        for y in start.1..end.1 {
+            for x in start.0..end.0 {
+                f((x, y, z));
+            }
+        }
+    }
+}
+
+/// The blocks of a chunk, stored as a small palette of materials and the index into that
+/// palette of every block. Indices take up as few bits as the palette allows, so a chunk made of a
+/// handful of materials takes up a small fraction of the memory of one material per block.
+/// The minefield is not stored, instead every cube of every LOD remembers whether it contains any
+/// visible blocks, which is enough to work out the minefield of any block when it is uploaded.
+#[derive(Clone)]
+pub struct PaletteChunk {
+    palette: Vec<u32>,
+    // Whether rays stop at each material of the palette.
+    visible: Vec<bool>,
+    // How many blocks use each material of the palette. Unused materials are replaced when a new
+    // one is added.
+    counts: Vec<usize>,
+    // How many bits the index of each block takes up.
+    bits: usize,
+    words: Vec<u64>,
+    // lods[n] has a bit for every cube of LOD n + 1, set if the cube contains any visible blocks.
+    lods: Vec<Vec<u64>>,
+}
+
+impl PaletteChunk {
+    /// Creates a chunk made of nothing but the given material. Every cube of every LOD contains
+    /// the same blocks, so this does not have to look at any of them.
+    pub fn filled(material: u32) -> PaletteChunk {
+        let visible = material != AIR_MATERIAL;
+        let lods = (1..=MAX_CHUNK_LOD)
+            .map(|lod| {
+                let num_cubes = CHUNK_VOLUME >> (lod * 3);
+                let mut bits = vec![0; num_cubes.div_ceil(64)];
+                if visible {
+                    for index in 0..num_cubes {
+                        set_bit(&mut bits, index, true);
+                    }
+                }
+                bits
+            })
+            .collect();
+        PaletteChunk {
+            palette: vec![material],
+            visible: vec![visible],
+            counts: vec![CHUNK_VOLUME],
+            bits: 1,
+            words: vec![0; CHUNK_VOLUME / WORD_BITS],
+            lods,
+        }
+    }
+
+    /// Creates a chunk from the material of every block.
+    pub fn from_materials(materials: &[u32]) -> PaletteChunk {
+        assert!(materials.len() == CHUNK_VOLUME);
+        let mut palette = Vec::new();
+        let mut indices = Vec::with_capacity(CHUNK_VOLUME);
+        // Neighboring blocks are usually the same, so remember the last one to skip the search.
+        let mut last = None;
+        for &material in materials {
+            let index = match last {
+                Some((last_material, last_index)) if last_material == material => last_index,
+                _ => match palette.iter().position(|&entry| entry == material) {
+                    Some(index) => index,
+                    None => {
+                        palette.push(material);
+                        palette.len() - 1
+                    }
+                },
+            };
+            last = Some((material, index));
+            indices.push(index as u16);
+        }
+        Self::from_indices(&palette, &indices)
+    }
+
+    // Creates a chunk from a palette and the index into it of every block.
+    fn from_indices(palette: &[u32], indices: &[u16]) -> PaletteChunk {
+        assert!(palette.len() <= MAX_PALETTE_LEN, "Too many materials in one chunk.");
+        let bits = bits_for(palette.len());
+        let mut chunk = PaletteChunk {
+            palette: palette.to_vec(),
+            visible: palette
+                .iter()
+                .map(|&material| material != AIR_MATERIAL)
+                .collect(),
+            counts: vec![0; palette.len()],
+            bits,
+            words: vec![0; CHUNK_VOLUME * bits / WORD_BITS],
+            lods: Vec::new(),
+        };
+        for (index, &palette_index) in indices.iter().enumerate() {
+            chunk.set_index(index, palette_index as usize);
+            chunk.counts[palette_index as usize] += 1;
+        }
+        chunk.lods = (1..=MAX_CHUNK_LOD)
+            .map(|lod| vec![0; (CHUNK_VOLUME >> (lod * 3)).div_ceil(64)])
+            .collect();
+        chunk.update_lods((0, 0, 0), CHUNK_SIZE.repeat());
+        chunk
+    }
+
+    fn get_index(&self, index: usize) -> usize {
+        let bit = index * self.bits;
+        let mask = (1 << self.bits) - 1;
+        (self.words[bit / WORD_BITS] >> (bit % WORD_BITS) & mask) as usize
+    }
+
+    fn set_index(&mut self, index: usize, palette_index: usize) {
+        let bit = index * self.bits;
+        let mask = (1 << self.bits) - 1;
+        let word = &mut self.words[bit / WORD_BITS];
+        *word &= !(mask << (bit % WORD_BITS));
+        *word |= (palette_index as u64) << (bit % WORD_BITS);
+    }
+
+    /// Returns the material of the block at the given index.
+    pub fn get_material(&self, index: usize) -> u32 {
+        self.palette[self.get_index(index)]
+    }
+
+    /// Returns the material every block of the chunk is made of, if they are all the same.
+    pub fn get_uniform_material(&self) -> Option<u32> {
+        let mut used = (0..self.palette.len()).filter(|&index| self.counts[index] > 0);
+        match (used.next(), used.next()) {
+            (Some(index), None) => Some(self.palette[index]),
+            _ => None,
+        }
+    }
+
+    /// Returns the minefield value of the block at the given index. This is 0 for visible
+    /// blocks, otherwise it is one more than the LOD of the largest cube around the block that
+    /// contains no visible blocks.
+    pub fn get_distance(&self, index: usize) -> u8 {
+        if self.visible[self.get_index(index)] {
+            return 0;
+        }
+        for (lod, bits) in self.lods.iter().enumerate() {
+            if get_bit(bits, lod_index(index, lod + 1)) {
+                return lod as u8 + 1;
+            }
+        }
+        MAX_CHUNK_LOD as u8 + 1
+    }
+
+    /// Changes the material of the block at the given index. Only the cubes containing the block
+    /// are checked for visible blocks again.
+    pub fn set_material(&mut self, index: usize, material: u32) {
+        let coord = util::index_to_coord_3d(index, CHUNK_SIZE);
+        self.fill_box(coord, (1, 1, 1), material);
+    }
+
+    /// Changes the material of every block in the box of the given size starting at the given
+    /// coordinate, which must be inside the chunk. Only the cubes overlapping the box are checked
+    /// for visible blocks again, so small boxes take little time no matter what the rest of the
+    /// chunk looks like.
+    pub fn fill_box(&mut self, start: Coord3D, size: Coord3D, material: u32) {
+        let end = start.add(size);
+        assert!(end.inside(CHUNK_SIZE.repeat()));
+        if size.0 == 0 || size.1 == 0 || size.2 == 0 {
+            return;
+        }
+        let new_index = self.find_or_add(material);
+        let mut visibility_changed = false;
+        for_each_in_box(start, end, |coord| {
+            let index = util::coord_to_index_3d(&coord, CHUNK_SIZE);
+            let old_index = self.get_index(index);
+            if old_index != new_index {
+                self.counts[old_index] -= 1;
+                self.counts[new_index] += 1;
+                self.set_index(index, new_index);
+                visibility_changed |= self.visible[old_index] != self.visible[new_index];
+            }
+        });
+        if visibility_changed {
+            self.update_lods(start, end);
+        }
+    }
+
+    // Returns the index of a material in the palette, adding it if it is not there yet.
+    fn find_or_add(&mut self, material: u32) -> usize {
+        if let Some(index) = self.palette.iter().position(|&entry| entry == material) {
+            return index;
+        }
+        let visible = material != AIR_MATERIAL;
+        if let Some(index) = self.counts.iter().position(|&count| count == 0) {
+            self.palette[index] = material;
+            self.visible[index] = visible;
+            return index;
+        }
+        assert!(
+            self.palette.len() < MAX_PALETTE_LEN,
+            "Too many materials in one chunk."
+        );
+        self.palette.push(material);
+        self.visible.push(visible);
+        self.counts.push(0);
+        let bits = bits_for(self.palette.len());
+        if bits != self.bits {
+            let indices: Vec<_> = (0..CHUNK_VOLUME).map(|i| self.get_index(i)).collect();
+            self.bits = bits;
+            self.words = vec![0; CHUNK_VOLUME * bits / WORD_BITS];
+            for (index, palette_index) in indices.into_iter().enumerate() {
+                self.set_index(index, palette_index);
+            }
+        }
+        self.palette.len() - 1
+    }
+
+    // Works out again which cubes of each LOD contain visible blocks, for the cubes overlapping
+    // the blocks from start up to but not including end. Each LOD is worked out from the one
+    // below it, so only eight blocks or smaller cubes are looked at for every cube.
+    fn update_lods(&mut self, start: Coord3D, end: Coord3D) {
+        let last = end.sub((1, 1, 1));
+        for lod in 1..=MAX_CHUNK_LOD {
+            let lod_start = start.shrink(1 << lod);
+            let lod_end = last.shrink(1 << lod).add((1, 1, 1));
+            let stride = CHUNK_SIZE >> lod;
+            for_each_in_box(lod_start, lod_end, |cube| {
+                let first_child = cube.scale(2);
+                let mut occupied = false;
+                for_each_in_box(first_child, first_child.add((2, 2, 2)), |child| {
+                    occupied = occupied || self.is_occupied(child, lod - 1);
+                });
+                let bits = &mut self.lods[lod - 1];
+                set_bit(bits, util::coord_to_index_3d(&cube, stride), occupied);
+            });
+        }
+    }
+
+    // True if the cube of the given LOD at the given coordinate contains any visible blocks. LOD 0
+    // is made of single blocks.
+    fn is_occupied(&self, coord: Coord3D, lod: usize) -> bool {
+        let index = util::coord_to_index_3d(&coord, CHUNK_SIZE >> lod);
+        if lod == 0 {
+            self.visible[self.get_index(index)]
+        } else {
+            get_bit(&self.lods[lod - 1], index)
+        }
+    }
+
+    /// How much memory the chunk takes up, in bytes.
+    pub fn get_size(&self) -> usize {
+        use std::mem::size_of;
+        let lods: usize = self.lods.iter().map(Vec::len).sum();
+        let entry_size = size_of::<u32>() + size_of::<bool>() + size_of::<usize>();
+        self.palette.len() * entry_size + (self.words.len() + lods) * size_of::<u64>()
+    }
+
+    /// Writes the palette and indices of the chunk. Materials that are no longer used are left out.
+    pub fn write_to(&self, target: &mut impl Write) -> io::Result<()> {
+        let mut remap = vec![0; self.palette.len()];
+        let mut palette = Vec::new();
+        for (index, &material) in self.palette.iter().enumerate() {
+            if self.counts[index] > 0 {
+                remap[index] = palette.len();
+                palette.push(material);
+            }
+        }
+        target.write_u16::<LittleEndian>(palette.len() as u16)?;
+        for &material in &palette {
+            target.write_u32::<LittleEndian>(material)?;
+        }
+        let bits = bits_for(palette.len());
+        let mut word = 0u64;
+        for index in 0..CHUNK_VOLUME {
+            let bit = index * bits % WORD_BITS;
+            word |= (remap[self.get_index(index)] as u64) << bit;
+            if bit + bits == WORD_BITS {
+                target.write_u64::<LittleEndian>(word)?;
+                word = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a chunk written by write_to.
+    pub fn read_from(source: &mut impl Read) -> io::Result<PaletteChunk> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        let palette_len = source.read_u16::<LittleEndian>()? as usize;
+        if palette_len == 0 {
+            return Err(invalid("The palette of a chunk is empty."));
+        }
+        let mut palette = Vec::with_capacity(palette_len);
+        for _ in 0..palette_len {
+            palette.push(source.read_u32::<LittleEndian>()?);
+        }
+        let bits = bits_for(palette_len);
+        let mask = (1 << bits) - 1;
+        let mut indices = Vec::with_capacity(CHUNK_VOLUME);
+        for _ in 0..CHUNK_VOLUME * bits / WORD_BITS {
+            let word = source.read_u64::<LittleEndian>()?;
+            for bit in (0..WORD_BITS).step_by(bits) {
+                let index = (word >> bit & mask) as u16;
+                if index as usize >= palette_len {
+                    return Err(invalid("A block of a chunk is not in its palette."));
+                }
+                indices.push(index);
+            }
+        }
+        Ok(Self::from_indices(&palette, &indices))
+    }
+}
+
+// Two chunks are the same if all their blocks are, no matter how their palettes are laid out.
+impl PartialEq for PaletteChunk {
+    fn eq(&self, other: &Self) -> bool {
+        (0..CHUNK_VOLUME).all(|index| self.get_material(index) == other.get_material(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+
+    // Stone below z = 10, water between 10 and 20 and air above.
+    fn make_materials() -> Vec<u32> {
+        (0..CHUNK_VOLUME)
+            .map(|index| match util::index_to_coord_3d(index, CHUNK_SIZE).2 {
+                0..=9 => 4,
+                10..=19 => 7,
+                _ => AIR_MATERIAL,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn get_and_set() {
+        let materials = make_materials();
+        let mut chunk = PaletteChunk::from_materials(&materials);
+        assert_eq!(chunk.bits, 2);
+        for (index, &material) in materials.iter().enumerate() {
+            assert_eq!(chunk.get_material(index), material);
+        }
+        let index = |coord| util::coord_to_index_3d(&coord, CHUNK_SIZE);
+        assert_eq!(chunk.get_distance(index((5, 5, 5))), 0);
+        assert_eq!(chunk.get_distance(index((5, 5, 15))), 0);
+        // The cube of LOD 4 from z = 16 to 32 contains water, the ones above it do not.
+        assert_eq!(chunk.get_distance(index((5, 5, 25))), 4);
+        assert_eq!(chunk.get_distance(index((5, 5, 40))), 6);
+
+        // Adding more materials than the indices can hold makes them wider.
+        let solid = |material: usize| 100 + material as u32;
+        for material in 0..20 {
+            chunk.set_material(index((material, 0, 40)), solid(material));
+        }
+        assert_eq!(chunk.bits, 8);
+        assert_eq!(chunk.get_material(index((19, 0, 40))), solid(19));
+        assert_eq!(chunk.get_material(index((20, 0, 40))), AIR_MATERIAL);
+        assert_eq!(chunk.get_material(index((5, 5, 5))), 4);
+        assert_eq!(chunk.get_distance(index((0, 0, 40))), 0);
+        assert_eq!(chunk.get_distance(index((19, 1, 40))), 1);
+        assert_eq!(chunk.get_distance(index((30, 1, 40))), 4);
+        assert_eq!(chunk.get_distance(index((5, 5, 60))), 5);
+
+        // Removing the only visible block of a cube makes it empty again.
+        for material in 0..20 {
+            chunk.set_material(index((material, 0, 40)), AIR_MATERIAL);
+        }
+        assert_eq!(chunk.get_distance(index((5, 5, 40))), 6);
+        assert_eq!(chunk.get_uniform_material(), None);
+        for index in 0..CHUNK_VOLUME {
+            chunk.set_material(index, 2);
+        }
+        assert_eq!(chunk.get_uniform_material(), Some(2));
+    }
+
+    #[test]
+    fn filled() {
+        for &material in &[AIR_MATERIAL, 4] {
+            let chunk = PaletteChunk::filled(material);
+            let expected = PaletteChunk::from_indices(&[material], &vec![0; CHUNK_VOLUME]);
+            assert_eq!(chunk.bits, expected.bits);
+            assert!(chunk.lods == expected.lods);
+            assert_eq!(chunk.get_uniform_material(), Some(material));
+        }
+    }
+
+    #[test]
+    fn fill_boxes() {
+        let mut chunk = PaletteChunk::from_materials(&make_materials());
+        let stone = 4;
+        let air = AIR_MATERIAL;
+        let boxes = [
+            ((30, 30, 30), (10, 3, 20), stone),
+            ((0, 0, 5), (CHUNK_SIZE, CHUNK_SIZE, 3), air),
+            ((31, 31, 40), (1, 1, 1), air),
+            ((0, 0, 10), (CHUNK_SIZE, CHUNK_SIZE, 10), air),
+            ((33, 2, 0), (0, 5, 5), stone),
+        ];
+        for &(start, size, material) in &boxes {
+            chunk.fill_box(start, size, material);
+            // Updating only the cubes around the box should give the same minefield as working it
+            // out from scratch.
+            let materials: Vec<_> = (0..CHUNK_VOLUME).map(|i| chunk.get_material(i)).collect();
+            let expected = PaletteChunk::from_materials(&materials);
+            for index in 0..CHUNK_VOLUME {
+                assert_eq!(chunk.get_distance(index), expected.get_distance(index));
+            }
+        }
+        let index = |coord| util::coord_to_index_3d(&coord, CHUNK_SIZE);
+        assert_eq!(chunk.get_material(index((35, 31, 40))), stone);
+        assert_eq!(chunk.get_material(index((31, 31, 40))), air);
+        assert_eq!(chunk.get_distance(index((31, 31, 40))), 1);
+        assert_eq!(chunk.get_material(index((0, 0, 6))), air);
+        assert_eq!(chunk.get_material(index((0, 0, 15))), air);
+    }
+
+    #[test]
+    fn write_and_read() {
+        let mut chunk = PaletteChunk::from_materials(&make_materials());
+        // A material that is no longer used should not be written.
+        chunk.set_material(0, 1234);
+        chunk.set_material(0, 4);
+        assert_eq!(chunk.palette.len(), 4);
+        let mut bytes = Vec::new();
+        chunk.write_to(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), 2 + 3 * 4 + CHUNK_VOLUME * 2 / 8);
+        let read = PaletteChunk::read_from(&mut &bytes[..]).unwrap();
+        assert_eq!(read.palette.len(), 3);
+        for index in 0..CHUNK_VOLUME {
+            assert_eq!(read.get_material(index), chunk.get_material(index));
+            assert_eq!(read.get_distance(index), chunk.get_distance(index));
+        }
+        assert!(PaletteChunk::read_from(&mut &bytes[..100]).is_err());
+    }
+
+    #[test]
+    fn write_and_read_full_palette() {
+        let palette: Vec<_> = (0..MAX_PALETTE_LEN as u32).collect();
+        let indices: Vec<_> = (0..CHUNK_VOLUME)
+            .map(|index| (index % MAX_PALETTE_LEN) as u16)
+            .collect();
+        let chunk = PaletteChunk::from_indices(&palette, &indices);
+        assert_eq!(chunk.bits, MAX_BITS);
+        let mut bytes = Vec::new();
+        chunk.write_to(&mut bytes).unwrap();
+        let read = PaletteChunk::read_from(&mut &bytes[..]).unwrap();
+        assert_eq!(read.palette.len(), MAX_PALETTE_LEN);
+        assert!(read == chunk);
+    }
+}
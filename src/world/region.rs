@@ -0,0 +1,287 @@
+use super::ChunkStorageCoord;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// How many chunks a region spans along each axis.
+pub const REGION_SIZE: usize = 8;
+pub const REGION_VOLUME: usize = REGION_SIZE * REGION_SIZE * REGION_SIZE;
+
+// Each table entry is a u64 offset, a u32 length and a u32 capacity.
+const TABLE_ENTRY_SIZE: u64 = 16;
+const TABLE_SIZE: u64 = TABLE_ENTRY_SIZE * REGION_VOLUME as u64;
+
+pub type RegionCoord = (isize, isize, isize);
+
+/// Returns which region a chunk belongs to and the index of the chunk inside that region.
+pub fn locate_chunk(coord: &ChunkStorageCoord) -> (RegionCoord, usize) {
+    let size = REGION_SIZE as isize;
+    let region = (
+        coord.0.div_euclid(size),
+        coord.1.div_euclid(size),
+        coord.2.div_euclid(size),
+    );
+    let local = (
+        coord.0.rem_euclid(size) as usize,
+        coord.1.rem_euclid(size) as usize,
+        coord.2.rem_euclid(size) as usize,
+    );
+    (
+        region,
+        (local.2 * REGION_SIZE + local.1) * REGION_SIZE + local.0,
+    )
+}
+
+pub fn get_region_path(base: &Path, coord: &RegionCoord) -> PathBuf {
+    base.join(format!("{}.{}.{}.region", coord.0, coord.1, coord.2))
+}
+
+#[derive(Clone, Copy, Default)]
+struct TableEntry {
+    // An offset of zero means the chunk is not stored in this region.
+    offset: u64,
+    length: u32,
+    // How many bytes are reserved at offset.
+    capacity: u32,
+}
+
+/// A file containing up to REGION_VOLUME chunks. The file starts with a table containing the
+/// location of every chunk, followed by the chunk data itself.
+///
+/// Chunk data is never overwritten while the table on disk points at it. New data is written to
+/// unused space, and the table on disk is only changed to point at it once sync has made sure the
+/// data is on disk, so if the program stops partway through a write the table still points at the
+/// old, intact data. Space freed by a write is not reused until the table no longer points at it.
+pub struct RegionFile {
+    file: File,
+    table: Vec<TableEntry>,
+    // Entries of the table that have changed since the table on disk was last written.
+    changed_entries: Vec<usize>,
+    // Space that the table on disk may still point at, even though the table in memory does not.
+    freed: Vec<TableEntry>,
+}
+
+impl RegionFile {
+    /// Opens an existing region file, or returns None if it does not exist.
+    pub fn open(path: &Path) -> io::Result<Option<RegionFile>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut table = vec![TableEntry::default(); REGION_VOLUME];
+        let mut raw_table = vec![0; TABLE_SIZE as usize];
+        file.read_exact(&mut raw_table)?;
+        let mut reader = &raw_table[..];
+        for entry in &mut table {
+            entry.offset = reader.read_u64::<LittleEndian>()?;
+            entry.length = reader.read_u32::<LittleEndian>()?;
+            entry.capacity = reader.read_u32::<LittleEndian>()?;
+        }
+        Ok(Some(RegionFile {
+            file,
+            table,
+            changed_entries: Vec::new(),
+            freed: Vec::new(),
+        }))
+    }
+
+    /// Opens an existing region file, creating an empty one if it does not exist yet.
+    pub fn open_or_create(path: &Path) -> io::Result<RegionFile> {
+        if let Some(region) = Self::open(path)? {
+            return Ok(region);
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.write_all(&vec![0; TABLE_SIZE as usize])?;
+        Ok(RegionFile {
+            file,
+            table: vec![TableEntry::default(); REGION_VOLUME],
+            changed_entries: Vec::new(),
+            freed: Vec::new(),
+        })
+    }
+
+    pub fn has_chunk(&self, index: usize) -> bool {
+        self.table[index].offset != 0
+    }
+
+    /// Returns the raw bytes that were stored for the chunk at the given index, or None if that
+    /// chunk has never been written.
+    pub fn read_chunk(&mut self, index: usize) -> io::Result<Option<Vec<u8>>> {
+        let entry = self.table[index];
+        if entry.offset == 0 {
+            return Ok(None);
+        }
+        let mut data = vec![0; entry.length as usize];
+        self.file.seek(SeekFrom::Start(entry.offset))?;
+        self.file.read_exact(&mut data)?;
+        Ok(Some(data))
+    }
+
+    /// Returns the offset of the first gap between stored chunks that can hold the given number of
+    /// bytes, or the end of the stored data if there is no such gap. Freed space counts as used
+    /// until the table has been synced.
+    fn find_space(&self, length: u64) -> u64 {
+        let mut used: Vec<_> = self
+            .table
+            .iter()
+            .chain(self.freed.iter())
+            .filter(|entry| entry.offset != 0)
+            .map(|entry| (entry.offset, entry.offset + entry.capacity as u64))
+            .collect();
+        used.sort_unstable();
+        let mut position = TABLE_SIZE;
+        for (start, end) in used {
+            if start >= position + length {
+                return position;
+            }
+            position = position.max(end);
+        }
+        position
+    }
+
+    /// Stores the given bytes as the chunk at the given index, replacing any old data. Reads see the
+    /// new data right away, but the old data is what will be read after reopening the file until
+    /// sync has been called.
+    pub fn write_chunk(&mut self, index: usize, data: &[u8]) -> io::Result<()> {
+        let entry = TableEntry {
+            offset: self.find_space(data.len() as u64),
+            length: data.len() as u32,
+            capacity: data.len() as u32,
+        };
+        self.file.seek(SeekFrom::Start(entry.offset))?;
+        self.file.write_all(data)?;
+        let old_entry = std::mem::replace(&mut self.table[index], entry);
+        if old_entry.offset != 0 {
+            self.freed.push(old_entry);
+        }
+        if !self.changed_entries.contains(&index) {
+            self.changed_entries.push(index);
+        }
+        Ok(())
+    }
+
+    /// Waits until everything written to the region so far is on disk. The chunk data is synced
+    /// before the table is changed to point at it, and the table is synced before the space it
+    /// used to point at can be reused.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.changed_entries.is_empty() {
+            return Ok(());
+        }
+        self.file.sync_data()?;
+        for position in 0..self.changed_entries.len() {
+            self.write_table_entry(self.changed_entries[position])?;
+        }
+        self.file.sync_data()?;
+        self.changed_entries.clear();
+        self.freed.clear();
+        Ok(())
+    }
+
+    // Table entries are small and aligned to their size, so they never straddle two disk sectors
+    // and are written all at once.
+    fn write_table_entry(&mut self, index: usize) -> io::Result<()> {
+        let entry = self.table[index];
+        let mut raw_entry = Vec::with_capacity(TABLE_ENTRY_SIZE as usize);
+        raw_entry.write_u64::<LittleEndian>(entry.offset)?;
+        raw_entry.write_u32::<LittleEndian>(entry.length)?;
+        raw_entry.write_u32::<LittleEndian>(entry.capacity)?;
+        self.file
+            .seek(SeekFrom::Start(index as u64 * TABLE_ENTRY_SIZE))?;
+        self.file.write_all(&raw_entry)
+    }
+}
+
+impl Drop for RegionFile {
+    fn drop(&mut self) {
+        if let Err(err) = self.sync() {
+            println!("WARNING: Failed to save region file.");
+            println!("Caused by: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn make_temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "raytraceTestRegion{:08X}",
+            rand::thread_rng().next_u32()
+        ))
+    }
+
+    #[test]
+    fn locate() {
+        assert_eq!(locate_chunk(&(0, 0, 0)), ((0, 0, 0), 0));
+        assert_eq!(locate_chunk(&(1, 0, 0)), ((0, 0, 0), 1));
+        assert_eq!(
+            locate_chunk(&(0, 0, 1)),
+            ((0, 0, 0), REGION_SIZE * REGION_SIZE)
+        );
+        assert_eq!(
+            locate_chunk(&(-1, -1, -1)),
+            ((-1, -1, -1), REGION_VOLUME - 1)
+        );
+    }
+
+    #[test]
+    fn write_and_rewrite() {
+        let path = make_temp_path();
+        let mut region = RegionFile::open_or_create(&path).unwrap();
+        assert!(!region.has_chunk(3));
+        region.write_chunk(3, &[1; 100]).unwrap();
+        region.write_chunk(5, &[2; 20]).unwrap();
+        region.sync().unwrap();
+        // Rewriting must not touch the old data, even if the new data would fit in its place.
+        region.write_chunk(3, &[3; 50]).unwrap();
+        assert_eq!(region.table[3].offset, TABLE_SIZE + 120);
+        // The table on disk still points at the old data, so other chunks can't use it yet.
+        region.write_chunk(6, &[4; 80]).unwrap();
+        assert_eq!(region.table[6].offset, TABLE_SIZE + 170);
+        assert_eq!(region.read_chunk(3).unwrap().unwrap(), vec![3; 50]);
+        let mut on_disk = RegionFile::open(&path).unwrap().unwrap();
+        assert_eq!(on_disk.read_chunk(3).unwrap().unwrap(), vec![1; 100]);
+        assert!(!on_disk.has_chunk(6));
+        drop(on_disk);
+        drop(region);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), TABLE_SIZE + 250);
+
+        let mut region = RegionFile::open(&path).unwrap().unwrap();
+        assert!(region.has_chunk(3));
+        assert_eq!(region.read_chunk(3).unwrap().unwrap(), vec![3; 50]);
+        assert_eq!(region.read_chunk(5).unwrap().unwrap(), vec![2; 20]);
+        assert_eq!(region.read_chunk(6).unwrap().unwrap(), vec![4; 80]);
+        assert_eq!(region.read_chunk(4).unwrap(), None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reuse_freed_space() {
+        let path = make_temp_path();
+        let mut region = RegionFile::open_or_create(&path).unwrap();
+        region.write_chunk(3, &[1; 100]).unwrap();
+        region.write_chunk(5, &[2; 20]).unwrap();
+        region.write_chunk(3, &[3; 50]).unwrap();
+        region.sync().unwrap();
+        // Once the table no longer points at the old data, its space should be reused.
+        region.write_chunk(6, &[4; 80]).unwrap();
+        assert_eq!(region.table[6].offset, TABLE_SIZE);
+        region.sync().unwrap();
+        drop(region);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), TABLE_SIZE + 170);
+
+        let mut region = RegionFile::open(&path).unwrap().unwrap();
+        assert_eq!(region.read_chunk(3).unwrap().unwrap(), vec![3; 50]);
+        assert_eq!(region.read_chunk(5).unwrap().unwrap(), vec![2; 20]);
+        assert_eq!(region.read_chunk(6).unwrap().unwrap(), vec![4; 80]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
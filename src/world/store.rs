@@ -0,0 +1,373 @@
+use super::region::{self, RegionCoord, RegionFile};
+use super::ChunkStorageCoord;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Somewhere that encoded chunk data can be persisted. Implementations only deal with opaque bytes,
+/// encoding and verifying the data is done by ChunkStorage.
+pub trait ChunkStore: Send {
+    fn has_chunk(&mut self, coord: &ChunkStorageCoord) -> bool;
+    /// Returns the bytes last written for the given chunk, or None if it was never written.
+    fn read_chunk(&mut self, coord: &ChunkStorageCoord) -> io::Result<Option<Vec<u8>>>;
+    /// Writes must never leave a half-written chunk in place of the old data, even if the program
+    /// stops partway through. Written chunks may not survive the program stopping until flush has
+    /// been called.
+    fn write_chunk(&mut self, coord: &ChunkStorageCoord, data: &[u8]) -> io::Result<()>;
+    /// Waits until everything written so far is on disk.
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+// Region files are kept open after they are first used, this limits how many file handles that
+// can take up.
+const MAX_OPEN_REGIONS: usize = 64;
+
+/// Stores chunks in a directory of region files, each holding a cube of nearby chunks.
+pub struct DirectoryStore {
+    dir: PathBuf,
+    regions: HashMap<RegionCoord, RegionFile>,
+}
+
+impl DirectoryStore {
+    /// Opens the store in the given directory, creating the directory if it does not exist.
+    pub fn open(dir: PathBuf) -> io::Result<DirectoryStore> {
+        std::fs::create_dir_all(&dir)?;
+        let mut store = DirectoryStore {
+            dir,
+            regions: HashMap::new(),
+        };
+        if let Err(err) = store.convert_legacy_chunks() {
+            println!("WARNING: Failed to convert old chunk files to region files.");
+            println!("Caused by: {}", err);
+        }
+        Ok(store)
+    }
+
+    pub fn get_dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Old worlds stored every chunk in its own file, named after the hex representation of its
+    /// coordinate. This moves any such files into region files.
+    fn convert_legacy_chunks(&mut self) -> io::Result<()> {
+        let mut legacy_chunks = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if let Some(coord) = Self::parse_legacy_path(&path) {
+                legacy_chunks.push((coord, path));
+            }
+        }
+        if legacy_chunks.is_empty() {
+            return Ok(());
+        }
+        println!(
+            "Converting {} chunk files to region files...",
+            legacy_chunks.len()
+        );
+        for (coord, path) in &legacy_chunks {
+            let data = std::fs::read(path)?;
+            self.write_chunk(coord, &data)?;
+        }
+        // The old files are only removed once their data is safely in the region files.
+        self.flush()?;
+        for (_, path) in legacy_chunks {
+            std::fs::remove_file(&path)?;
+        }
+        Ok(())
+    }
+
+    fn parse_legacy_path(path: &Path) -> Option<ChunkStorageCoord> {
+        let name = path.file_name()?.to_str()?;
+        if name.len() != 48 || !name.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let parse = |part: &str| {
+            u64::from_str_radix(part, 16)
+                .ok()
+                .map(|v| v as i64 as isize)
+        };
+        Some((
+            parse(&name[0..16])?,
+            parse(&name[16..32])?,
+            parse(&name[32..48])?,
+        ))
+    }
+
+    /// Returns the region file containing the given chunk coordinate. If create is false and the
+    /// region has never been written to, None is returned.
+    fn borrow_region(
+        &mut self,
+        coord: &ChunkStorageCoord,
+        create: bool,
+    ) -> io::Result<Option<(&mut RegionFile, usize)>> {
+        let (region_coord, index) = region::locate_chunk(coord);
+        if !self.regions.contains_key(&region_coord) {
+            let path = region::get_region_path(&self.dir, &region_coord);
+            let region = if create {
+                Some(RegionFile::open_or_create(&path)?)
+            } else {
+                RegionFile::open(&path)?
+            };
+            if let Some(region) = region {
+                if self.regions.len() >= MAX_OPEN_REGIONS {
+                    self.flush()?;
+                    self.regions.clear();
+                }
+                self.regions.insert(region_coord, region);
+            } else {
+                return Ok(None);
+            }
+        }
+        Ok(self
+            .regions
+            .get_mut(&region_coord)
+            .map(|region| (region, index)))
+    }
+}
+
+impl ChunkStore for DirectoryStore {
+    fn has_chunk(&mut self, coord: &ChunkStorageCoord) -> bool {
+        match self.borrow_region(coord, false) {
+            Ok(Some((region, index))) => region.has_chunk(index),
+            _ => false,
+        }
+    }
+
+    fn read_chunk(&mut self, coord: &ChunkStorageCoord) -> io::Result<Option<Vec<u8>>> {
+        if let Some((region, index)) = self.borrow_region(coord, false)? {
+            region.read_chunk(index)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn write_chunk(&mut self, coord: &ChunkStorageCoord, data: &[u8]) -> io::Result<()> {
+        let (region, index) = self.borrow_region(coord, true)?.unwrap();
+        region.write_chunk(index, data)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for region in self.regions.values_mut() {
+            region.sync()?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps chunks in memory only, they are lost once the store is dropped. Useful for tests and
+/// worlds that do not need to be saved.
+pub struct MemoryStore {
+    chunks: HashMap<ChunkStorageCoord, Vec<u8>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            chunks: HashMap::new(),
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> MemoryStore {
+        Self::new()
+    }
+}
+
+impl ChunkStore for MemoryStore {
+    fn has_chunk(&mut self, coord: &ChunkStorageCoord) -> bool {
+        self.chunks.contains_key(coord)
+    }
+
+    fn read_chunk(&mut self, coord: &ChunkStorageCoord) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.chunks.get(coord).cloned())
+    }
+
+    fn write_chunk(&mut self, coord: &ChunkStorageCoord, data: &[u8]) -> io::Result<()> {
+        self.chunks.insert(*coord, data.to_owned());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+const ARCHIVE_MAGIC: [u8; 8] = *b"RTARCHV1";
+// Each record starts with three i64 coordinates and a u32 length.
+const RECORD_HEADER_SIZE: u64 = 28;
+
+/// Stores every chunk in a single file, which is easier to copy around than a directory. The file
+/// is a log of records, each containing a chunk coordinate and its data. Rewriting a chunk appends
+/// a new record, the newest record for a coordinate is the one that is used. A record that was only
+/// partially written when the program stopped is ignored.
+pub struct ArchiveStore {
+    file: File,
+    // Where the data of the newest record for each chunk is, and how long it is.
+    index: HashMap<ChunkStorageCoord, (u64, u32)>,
+}
+
+impl ArchiveStore {
+    /// Opens the archive at the given path, creating it if it does not exist.
+    pub fn open(path: &Path) -> io::Result<ArchiveStore> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let length = file.metadata()?.len();
+        if length == 0 {
+            file.write_all(&ARCHIVE_MAGIC)?;
+            return Ok(ArchiveStore {
+                file,
+                index: HashMap::new(),
+            });
+        }
+
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic)?;
+        if magic != ARCHIVE_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} is not a chunk archive.", path),
+            ));
+        }
+        let mut index = HashMap::new();
+        let mut position = ARCHIVE_MAGIC.len() as u64;
+        let mut reader = io::BufReader::new(&mut file);
+        while position + RECORD_HEADER_SIZE <= length {
+            let coord = (
+                reader.read_i64::<LittleEndian>()? as isize,
+                reader.read_i64::<LittleEndian>()? as isize,
+                reader.read_i64::<LittleEndian>()? as isize,
+            );
+            let data_length = reader.read_u32::<LittleEndian>()?;
+            let data_start = position + RECORD_HEADER_SIZE;
+            if data_start + data_length as u64 > length {
+                // The program stopped while this record was being written, ignore it.
+                break;
+            }
+            index.insert(coord, (data_start, data_length));
+            reader.seek(SeekFrom::Current(data_length as i64))?;
+            position = data_start + data_length as u64;
+        }
+        drop(reader);
+        if position < length {
+            // Remove the partial record, otherwise records appended after it could not be found.
+            file.set_len(position)?;
+        }
+        Ok(ArchiveStore { file, index })
+    }
+}
+
+impl ChunkStore for ArchiveStore {
+    fn has_chunk(&mut self, coord: &ChunkStorageCoord) -> bool {
+        self.index.contains_key(coord)
+    }
+
+    fn read_chunk(&mut self, coord: &ChunkStorageCoord) -> io::Result<Option<Vec<u8>>> {
+        if let Some((start, length)) = self.index.get(coord) {
+            let mut data = vec![0; *length as usize];
+            self.file.seek(SeekFrom::Start(*start))?;
+            self.file.read_exact(&mut data)?;
+            Ok(Some(data))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn write_chunk(&mut self, coord: &ChunkStorageCoord, data: &[u8]) -> io::Result<()> {
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE as usize + data.len());
+        record.write_i64::<LittleEndian>(coord.0 as i64)?;
+        record.write_i64::<LittleEndian>(coord.1 as i64)?;
+        record.write_i64::<LittleEndian>(coord.2 as i64)?;
+        record.write_u32::<LittleEndian>(data.len() as u32)?;
+        record.extend_from_slice(data);
+        let start = self.file.seek(SeekFrom::End(0))?;
+        self.file.write_all(&record)?;
+        self.index
+            .insert(*coord, (start + RECORD_HEADER_SIZE, data.len() as u32));
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+
+    fn make_temp_path(prefix: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}{:08X}", prefix, rand::thread_rng().next_u32()))
+    }
+
+    fn exercise(store: &mut dyn ChunkStore) {
+        assert!(!store.has_chunk(&(0, 0, 0)));
+        assert_eq!(store.read_chunk(&(0, 0, 0)).unwrap(), None);
+        store.write_chunk(&(0, 0, 0), &[1, 2, 3]).unwrap();
+        store.write_chunk(&(-9, 4, 100), &[4; 50]).unwrap();
+        store.write_chunk(&(0, 0, 0), &[5; 20]).unwrap();
+        assert!(store.has_chunk(&(0, 0, 0)));
+        assert_eq!(store.read_chunk(&(0, 0, 0)).unwrap(), Some(vec![5; 20]));
+        assert_eq!(store.read_chunk(&(-9, 4, 100)).unwrap(), Some(vec![4; 50]));
+        store.flush().unwrap();
+    }
+
+    #[test]
+    fn memory_store() {
+        exercise(&mut MemoryStore::new());
+    }
+
+    #[test]
+    fn directory_store() {
+        let dir = make_temp_path("raytraceTestDir");
+        exercise(&mut DirectoryStore::open(dir.clone()).unwrap());
+        let mut store = DirectoryStore::open(dir.clone()).unwrap();
+        assert_eq!(store.read_chunk(&(0, 0, 0)).unwrap(), Some(vec![5; 20]));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn archive_store() {
+        let path = make_temp_path("raytraceTestArchive");
+        exercise(&mut ArchiveStore::open(&path).unwrap());
+        let mut store = ArchiveStore::open(&path).unwrap();
+        assert_eq!(store.read_chunk(&(0, 0, 0)).unwrap(), Some(vec![5; 20]));
+        assert_eq!(store.read_chunk(&(-9, 4, 100)).unwrap(), Some(vec![4; 50]));
+        drop(store);
+
+        // Simulate the program stopping while a record was being written.
+        let complete_length = std::fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[7; RECORD_HEADER_SIZE as usize - 4])
+            .unwrap();
+        drop(file);
+        let mut store = ArchiveStore::open(&path).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), complete_length);
+        store.write_chunk(&(1, 1, 1), &[6; 10]).unwrap();
+        drop(store);
+        let mut store = ArchiveStore::open(&path).unwrap();
+        assert_eq!(store.read_chunk(&(1, 1, 1)).unwrap(), Some(vec![6; 10]));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn convert_legacy_chunks() {
+        let dir = make_temp_path("raytraceTestDir");
+        std::fs::create_dir(&dir).unwrap();
+        let coord = (-3, 2, -1);
+        let legacy_name = format!("{:016X}{:016X}{:016X}", coord.0, coord.1, coord.2);
+        std::fs::write(dir.join(&legacy_name), &[1, 2, 3]).unwrap();
+
+        let mut store = DirectoryStore::open(dir.clone()).unwrap();
+        assert!(!dir.join(&legacy_name).exists());
+        assert_eq!(store.read_chunk(&coord).unwrap(), Some(vec![1, 2, 3]));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
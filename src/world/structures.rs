@@ -0,0 +1,454 @@
+use super::generate::hash_coord;
+use super::worldgen_config::{BiomeConfig, StructureConfig, StructureShape};
+use super::UnpackedChunkData;
+use crate::render::{constants::*, MATERIALS};
+use crate::util::{self, prelude::*};
+use rand::prelude::*;
+use rand::rngs::StdRng;
+
+// Added to the seed so that structures do not line up with other features.
+const STRUCTURE_SALT: u64 = 0x5354_5255;
+// How deep the walls of ruins go into the ground, so that they do not float on slopes.
+const RUIN_FOUNDATION: isize = 3;
+
+/// The parts of a column that decide whether and where a structure is placed on it.
+pub struct Surface {
+    /// The height of the terrain, after erosion and after lakes and rivers are dug out.
+    pub height: isize,
+    pub biome: usize,
+    pub water_level: isize,
+}
+
+/// What a block of a structure is allowed to replace.
+#[derive(Clone, Copy)]
+enum Replaces {
+    /// Air and liquids.
+    Empty,
+    Solid,
+    Anything,
+}
+
+impl Replaces {
+    fn allows(self, current: u32) -> bool {
+        let solid = MATERIALS[current as usize].is_solid();
+        match self {
+            Replaces::Empty => !solid,
+            Replaces::Solid => solid,
+            Replaces::Anything => true,
+        }
+    }
+}
+
+struct Block {
+    coord: util::SignedCoord3D,
+    material: u32,
+    replaces: Replaces,
+}
+
+// A kind of structure along with how far it can reach from the column it is placed on.
+struct StructureKind {
+    config: StructureConfig,
+    // The indices of the biomes it can be placed in, or every biome if empty.
+    biomes: Vec<usize>,
+    horizontal_reach: isize,
+    // How far below and above the surface it can reach.
+    vertical_reach: (isize, isize),
+}
+
+/// Places structures such as trees and ruins on top of generated terrain. Every structure only
+/// depends on the seed and the square of the world it is in, and every chunk places all of the
+/// structures that reach into it in the same order. That way structures that cross the border
+/// between chunks come out the same in all of them, whichever is generated first.
+pub struct Structures {
+    kinds: Vec<StructureKind>,
+    seed: u64,
+}
+
+impl Structures {
+    pub fn new(configs: &[StructureConfig], biomes: &[BiomeConfig], seed: u64) -> Structures {
+        let kinds = configs
+            .iter()
+            .map(|config| {
+                let (horizontal_reach, vertical_reach) = get_reach(&config.shape);
+                StructureKind {
+                    config: config.clone(),
+                    biomes: config
+                        .biomes
+                        .iter()
+                        .filter_map(|name| biomes.iter().position(|biome| &biome.name == name))
+                        .collect(),
+                    horizontal_reach,
+                    vertical_reach,
+                }
+            })
+            .collect();
+        Structures { kinds, seed }
+    }
+
+    /// Writes the parts of every structure that reach into the chunk. surface describes any
+    /// column of the world.
+    pub fn place(
+        &self,
+        data: &mut UnpackedChunkData,
+        chunk_coord: &util::SignedCoord3D,
+        surface: &dyn Fn(isize, isize) -> Surface,
+    ) {
+        let origin = chunk_coord.scale(CHUNK_SIZE as isize);
+        let end = origin.add((CHUNK_SIZE as isize).repeat());
+        self.for_each_block(&origin, &end, surface, &mut |block| {
+            if let Some(index) = get_index_in_chunk(&origin, &block.coord) {
+                if block.replaces.allows(data.materials[index]) {
+                    data.materials[index] = block.material;
+                }
+            }
+        });
+    }
+
+    /// Returns true if any structure has blocks inside the chunk.
+    pub fn reach_into(
+        &self,
+        chunk_coord: &util::SignedCoord3D,
+        surface: &dyn Fn(isize, isize) -> Surface,
+    ) -> bool {
+        let origin = chunk_coord.scale(CHUNK_SIZE as isize);
+        let end = origin.add((CHUNK_SIZE as isize).repeat());
+        let mut found = false;
+        self.for_each_block(&origin, &end, surface, &mut |block| {
+            found = found || get_index_in_chunk(&origin, &block.coord).is_some();
+        });
+        found
+    }
+
+    /// Calls f with every block of every structure that might reach into the area from start up
+    /// to but not including end, in the order they should be placed in. Some of the blocks can
+    /// be outside of the area.
+    fn for_each_block(
+        &self,
+        start: &util::SignedCoord3D,
+        end: &util::SignedCoord3D,
+        surface: &dyn Fn(isize, isize) -> Surface,
+        f: &mut dyn FnMut(&Block),
+    ) {
+        for (index, kind) in self.kinds.iter().enumerate() {
+            let (size, reach) = (kind.config.spacing, kind.horizontal_reach);
+            let seed = self.seed.wrapping_add(STRUCTURE_SALT + index as u64);
+            for cy in (start.1 - reach).div_euclid(size)..=(end.1 - 1 + reach).div_euclid(size) {
+                for cx in (start.0 - reach).div_euclid(size)..=(end.0 - 1 + reach).div_euclid(size)
+                {
+                    let mut random = StdRng::seed_from_u64(hash_coord(seed, &(cx, cy, 0)));
+                    if random.gen::<f64>() >= kind.config.chance {
+                        continue;
+                    }
+                    let x = cx * size + random.gen_range(0, size);
+                    let y = cy * size + random.gen_range(0, size);
+                    // Skip looking at the terrain if the structure can not reach the area anyway.
+                    if x + reach < start.0 || x - reach >= end.0 {
+                        continue;
+                    }
+                    if y + reach < start.1 || y - reach >= end.1 {
+                        continue;
+                    }
+                    let surface = surface(x, y);
+                    if surface.height + kind.vertical_reach.1 < start.2
+                        || surface.height - kind.vertical_reach.0 >= end.2
+                    {
+                        continue;
+                    }
+                    if !kind.biomes.is_empty() && !kind.biomes.contains(&surface.biome) {
+                        continue;
+                    }
+                    if !kind.config.underwater && surface.water_level > surface.height {
+                        continue;
+                    }
+                    let base = (x, y, surface.height);
+                    for block in build(&kind.config.shape, &mut random, base) {
+                        f(&block);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Returns the index of a block in the chunk starting at origin, or None if it is outside of it.
+fn get_index_in_chunk(origin: &util::SignedCoord3D, coord: &util::SignedCoord3D) -> Option<usize> {
+    let local = coord.sub(*origin);
+    let inside = |value: isize| (0..CHUNK_SIZE as isize).contains(&value);
+    if !inside(local.0) || !inside(local.1) || !inside(local.2) {
+        return None;
+    }
+    let local = (local.0 as usize, local.1 as usize, local.2 as usize);
+    Some(util::coord_to_index_3d(&local, CHUNK_SIZE))
+}
+
+// Returns how far a structure can reach sideways from the column it is placed on, and how far it
+// can reach below and above the surface.
+fn get_reach(shape: &StructureShape) -> (isize, (isize, isize)) {
+    match shape {
+        StructureShape::Tree {
+            max_height,
+            leaf_radius,
+            ..
+        } => {
+            let radius = leaf_radius.ceil() as isize;
+            (radius, (0, max_height + radius))
+        }
+        StructureShape::Boulder { max_radius, .. } => {
+            let radius = max_radius.ceil() as isize + 1;
+            (radius, (radius, radius))
+        }
+        StructureShape::OreVein {
+            length,
+            min_depth,
+            max_depth,
+            ..
+        } => {
+            let length = *length as isize + 1;
+            (length, (max_depth + length, length - min_depth))
+        }
+        StructureShape::Ruin {
+            max_size, height, ..
+        } => (max_size / 2 + 1, (RUIN_FOUNDATION, *height)),
+    }
+}
+
+/// Returns every block of a structure placed on top of the given block.
+fn build(shape: &StructureShape, random: &mut StdRng, base: util::SignedCoord3D) -> Vec<Block> {
+    let mut blocks = Vec::new();
+    match shape {
+        StructureShape::Tree {
+            trunk_material,
+            leaf_material,
+            min_height,
+            max_height,
+            leaf_radius,
+        } => {
+            let trunk_height = random.gen_range(*min_height, max_height + 1);
+            for z in 0..trunk_height {
+                blocks.push(Block {
+                    coord: base.add((0, 0, z)),
+                    material: trunk_material.0,
+                    replaces: Replaces::Empty,
+                });
+            }
+            let top = base.add((0, 0, trunk_height));
+            let center = (0.0, 0.0, 0.0);
+            add_ball(
+                &mut blocks,
+                random,
+                top,
+                center,
+                *leaf_radius,
+                leaf_material.0,
+            );
+        }
+        StructureShape::Boulder {
+            material,
+            min_radius,
+            max_radius,
+        } => {
+            let radius = random.gen::<f64>() * (max_radius - min_radius) + min_radius;
+            // Sink the boulder partway into the ground.
+            let center = (0.5, 0.5, radius * 0.3);
+            add_ball(&mut blocks, random, base, center, radius, material.0);
+            for block in &mut blocks {
+                block.replaces = Replaces::Anything;
+            }
+        }
+        StructureShape::OreVein {
+            material,
+            length,
+            min_depth,
+            max_depth,
+        } => {
+            let depth = random.gen_range(*min_depth, max_depth + 1);
+            let mut position = (
+                base.0 as f64 + 0.5,
+                base.1 as f64 + 0.5,
+                (base.2 - depth) as f64 + 0.5,
+            );
+            for _ in 0..*length {
+                blocks.push(Block {
+                    coord: (
+                        position.0.floor() as isize,
+                        position.1.floor() as isize,
+                        position.2.floor() as isize,
+                    ),
+                    material: material.0,
+                    replaces: Replaces::Solid,
+                });
+                position.0 += random.gen::<f64>() * 2.0 - 1.0;
+                position.1 += random.gen::<f64>() * 2.0 - 1.0;
+                position.2 += random.gen::<f64>() * 2.0 - 1.0;
+            }
+        }
+        StructureShape::Ruin {
+            material,
+            min_size,
+            max_size,
+            height,
+            decay,
+        } => {
+            let size = random.gen_range(*min_size, max_size + 1);
+            let corner = base.sub((size / 2, size / 2, 0));
+            for z in -RUIN_FOUNDATION..*height {
+                for y in 0..size {
+                    for x in 0..size {
+                        if x != 0 && y != 0 && x != size - 1 && y != size - 1 {
+                            continue;
+                        }
+                        // Walls crumble more towards the top.
+                        let decay = decay * (z + 1).max(0) as f64 / *height as f64;
+                        if random.gen::<f64>() < decay {
+                            continue;
+                        }
+                        blocks.push(Block {
+                            coord: corner.add((x, y, z)),
+                            material: material.0,
+                            replaces: Replaces::Anything,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    blocks
+}
+
+// Adds a roughly round ball of blocks around center, which is relative to the origin block.
+fn add_ball(
+    blocks: &mut Vec<Block>,
+    random: &mut StdRng,
+    origin: util::SignedCoord3D,
+    center: (f64, f64, f64),
+    radius: f64,
+    material: u32,
+) {
+    let reach = radius.ceil() as isize + 1;
+    for z in -reach..=reach {
+        for y in -reach..=reach {
+            for x in -reach..=reach {
+                let offset = (
+                    x as f64 + 0.5 - center.0,
+                    y as f64 + 0.5 - center.1,
+                    z as f64 + 0.5 - center.2,
+                );
+                let distance = offset.0 * offset.0 + offset.1 * offset.1 + offset.2 * offset.2;
+                // Make the edge a bit uneven.
+                let edge = radius * (0.8 + random.gen::<f64>() * 0.3);
+                if distance <= edge * edge {
+                    blocks.push(Block {
+                        coord: origin.add((x, y, z)),
+                        material,
+                        replaces: Replaces::Empty,
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::{materials, AIR_MATERIAL};
+    use crate::world::WorldgenConfig;
+    use std::collections::HashMap;
+
+    const STRUCTURES: &str = r#"
+        solid_below = 0
+
+        [[biomes]]
+        name = "flat"
+        height_curve = [[0.0, 10.0], [1.0, 10.0]]
+        [[biomes.layers]]
+        scale = 10.0
+        [biomes.layers.noise]
+        kind = "worley"
+        [[biomes.strata]]
+        material = "stone"
+        height = 0
+
+        [[structures]]
+        spacing = 8
+        chance = 0.8
+        [structures.shape]
+        kind = "tree"
+
+        [[structures]]
+        spacing = 24
+        chance = 1.0
+        [structures.shape]
+        kind = "ruin"
+
+        [[structures]]
+        spacing = 16
+        chance = 1.0
+        [structures.shape]
+        kind = "ore_vein"
+        min_depth = 2
+        max_depth = 6
+    "#;
+
+    // Flat ground at height 10.
+    fn terrain(z: isize) -> u32 {
+        if z < 10 {
+            materials::STONE
+        } else {
+            AIR_MATERIAL
+        }
+    }
+
+    fn surface(_x: isize, _y: isize) -> Surface {
+        Surface {
+            height: 10,
+            biome: 0,
+            water_level: -100,
+        }
+    }
+
+    #[test]
+    fn place_across_chunks() {
+        let config = WorldgenConfig::parse(STRUCTURES).unwrap();
+        let structures = Structures::new(&config.structures, &config.biomes, 4);
+
+        // Place everything in one big area first, which does not involve any chunk borders.
+        let (start, end) = ((-64, -64, -32), (64, 64, 64));
+        let mut world = HashMap::new();
+        structures.for_each_block(&start, &end, &surface, &mut |block| {
+            let current = world
+                .entry(block.coord)
+                .or_insert_with(|| terrain(block.coord.2));
+            if block.replaces.allows(*current) {
+                *current = block.material;
+            }
+        });
+        for material in &[
+            materials::GRASS,
+            materials::GLOWSTONE,
+            materials::STONE,
+            materials::DIRT,
+        ] {
+            assert!(world.values().any(|block| block == material));
+        }
+
+        // Each chunk should come out exactly like its part of the big area.
+        let mut data = UnpackedChunkData::new();
+        for chunk_coord in &[(0, 0, 0), (-1, 0, 0), (0, -1, 0), (-1, -1, 0), (0, 0, -1)] {
+            let origin = chunk_coord.scale(CHUNK_SIZE as isize);
+            for index in 0..CHUNK_VOLUME {
+                let coord = util::index_to_coord_3d(index, CHUNK_SIZE);
+                data.materials[index] = terrain(origin.2 + coord.2 as isize);
+            }
+            structures.place(&mut data, chunk_coord, &surface);
+            for index in 0..CHUNK_VOLUME {
+                let coord = origin.add(util::index_to_coord_3d(index, CHUNK_SIZE).signed());
+                let expected = world
+                    .get(&coord)
+                    .cloned()
+                    .unwrap_or_else(|| terrain(coord.2));
+                assert_eq!(data.materials[index], expected, "{:?}", coord);
+            }
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use super::generate::{hash_coord, smoothstep};
+use super::worldgen_config::{LakeConfig, RiverConfig, WaterConfig};
+use super::Heightmap;
+use crate::render::constants::*;
+use crate::util;
+use noise::{Fbm, MultiFractal, NoiseFn, Seedable};
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::f64::consts::PI;
+
+/// The water level of columns without any water.
+pub const NO_WATER: isize = isize::MIN;
+
+// Added to the seed so that water does not line up with other features.
+const RIVER_SALT: u32 = 5;
+const LAKE_SALT: u64 = 0x4C41_4B45;
+// How many points around the shore of a lake are checked to find how high its water can go.
+const SHORE_SAMPLES: usize = 16;
+
+// A square of columns.
+struct Area {
+    origin: util::SignedCoord2D,
+    size: usize,
+}
+
+struct Lake {
+    center: (f64, f64),
+    radius: f64,
+    level: isize,
+}
+
+/// Fills the low parts of terrain with seas, lakes and rivers.
+pub struct Water {
+    config: WaterConfig,
+    seed: u64,
+    river_noise: Fbm,
+}
+
+impl Water {
+    pub fn new(config: &WaterConfig, seed: u64, noise_seed: u32) -> Water {
+        Water {
+            config: config.clone(),
+            seed,
+            river_noise: Fbm::new()
+                .set_seed(noise_seed.wrapping_add(RIVER_SALT))
+                .set_octaves(3),
+        }
+    }
+
+    pub fn get_material(&self) -> u32 {
+        self.config.material.0
+    }
+
+    /// Digs out lakes and rivers in the heightmap of a chunk and returns how high water goes in
+    /// each of its columns, or NO_WATER. Blocks below that height and above the terrain are
+    /// water. column_height gives the height of any column of the world before water was added,
+    /// so that lakes come out the same in every chunk they cover.
+    pub fn fill_columns(
+        &self,
+        heightmap: &mut Heightmap,
+        chunk_coord: &util::SignedCoord2D,
+        column_height: &dyn Fn(isize, isize) -> isize,
+    ) -> Vec<isize> {
+        let origin = util::scale_signed_coord_2d(chunk_coord, CHUNK_SIZE as isize);
+        let area = Area {
+            origin,
+            size: CHUNK_SIZE,
+        };
+        self.fill_area(&area, &mut heightmap.data, column_height)
+    }
+
+    /// Like fill_columns, but for a single column. Returns the height of the column after lakes
+    /// and rivers are dug out, along with how high water goes in it.
+    pub fn fill_column(
+        &self,
+        x: isize,
+        y: isize,
+        height: isize,
+        column_height: &dyn Fn(isize, isize) -> isize,
+    ) -> (isize, isize) {
+        let area = Area {
+            origin: (x, y),
+            size: 1,
+        };
+        let mut heights = [height];
+        let levels = self.fill_area(&area, &mut heights, column_height);
+        (heights[0], levels[0])
+    }
+
+    fn fill_area(
+        &self,
+        area: &Area,
+        heights: &mut [isize],
+        column_height: &dyn Fn(isize, isize) -> isize,
+    ) -> Vec<isize> {
+        let sea_level = self.config.sea_level.unwrap_or(NO_WATER);
+        let mut levels = vec![sea_level; area.size * area.size];
+        if let Some(rivers) = &self.config.rivers {
+            self.dig_rivers(rivers, area, heights, &mut levels);
+        }
+        if let Some(lakes) = &self.config.lakes {
+            for lake in self.find_lakes(lakes, area, column_height) {
+                dig_lake(&lake, lakes.depth, area, heights, &mut levels);
+            }
+        }
+        levels
+    }
+
+    /// Rivers follow the lines where the river noise crosses zero, filled up to the height the
+    /// terrain was at before the river was dug out.
+    fn dig_rivers(
+        &self,
+        rivers: &RiverConfig,
+        area: &Area,
+        heights: &mut [isize],
+        levels: &mut [isize],
+    ) {
+        for (index, (x, y)) in util::coord_iter_2d(area.size).enumerate() {
+            let (x, y) = (area.origin.0 + x as isize, area.origin.1 + y as isize);
+            let noise = self
+                .river_noise
+                .get([x as f64 / rivers.scale, y as f64 / rivers.scale]);
+            let mut strength = 1.0 - noise.abs() / rivers.width;
+            if strength <= 0.0 {
+                continue;
+            }
+            let height = heights[index];
+            // Fade out over the last few blocks so that rivers do not end abruptly.
+            strength *= ((rivers.max_height - height) as f64 / 8.0).clamp(0.0, 1.0);
+            let depth = (rivers.depth * smoothstep(strength)).round();
+            if depth >= 1.0 {
+                heights[index] = height - depth as isize;
+                levels[index] = levels[index].max(height);
+            }
+        }
+    }
+
+    /// Returns the lakes that might cover part of the area. Every square of
+    /// the lake grid has its own random number generator, so a lake is the same no matter which
+    /// chunk asks for it.
+    fn find_lakes(
+        &self,
+        lakes: &LakeConfig,
+        area: &Area,
+        column_height: &dyn Fn(isize, isize) -> isize,
+    ) -> Vec<Lake> {
+        let origin = area.origin;
+        let size = lakes.cell_size;
+        let reach = lakes.max_radius.ceil() as isize;
+        let end = (origin.0 + area.size as isize, origin.1 + area.size as isize);
+        let mut found = Vec::new();
+        for cy in (origin.1 - reach).div_euclid(size)..=(end.1 + reach).div_euclid(size) {
+            for cx in (origin.0 - reach).div_euclid(size)..=(end.0 + reach).div_euclid(size) {
+                let seed = hash_coord(self.seed.wrapping_add(LAKE_SALT), &(cx, cy, 0));
+                let mut random = StdRng::seed_from_u64(seed);
+                if random.gen::<f64>() >= lakes.chance {
+                    continue;
+                }
+                let center = (
+                    (cx * size) as f64 + random.gen::<f64>() * size as f64,
+                    (cy * size) as f64 + random.gen::<f64>() * size as f64,
+                );
+                let radius =
+                    lakes.min_radius + random.gen::<f64>() * (lakes.max_radius - lakes.min_radius);
+                let outside = |center: f64, start: isize, end: isize| {
+                    center + radius < start as f64 || center - radius > end as f64
+                };
+                if outside(center.0, origin.0, end.0) || outside(center.1, origin.1, end.1) {
+                    continue;
+                }
+                // The water goes up to the lowest point of the shore, so that it does not spill
+                // out of the lake.
+                let level = (0..SHORE_SAMPLES)
+                    .map(|sample| {
+                        let angle = sample as f64 / SHORE_SAMPLES as f64 * PI * 2.0;
+                        column_height(
+                            (center.0 + angle.cos() * radius).floor() as isize,
+                            (center.1 + angle.sin() * radius).floor() as isize,
+                        )
+                    })
+                    .min()
+                    .unwrap();
+                found.push(Lake {
+                    center,
+                    radius,
+                    level,
+                });
+            }
+        }
+        found
+    }
+}
+
+/// Digs a bowl into every column of the lake that is below its water level. Columns above the
+/// water level are left alone and become the shore or islands.
+fn dig_lake(lake: &Lake, depth: f64, area: &Area, heights: &mut [isize], levels: &mut [isize]) {
+    for (index, (x, y)) in util::coord_iter_2d(area.size).enumerate() {
+        let offset = (
+            (area.origin.0 + x as isize) as f64 + 0.5 - lake.center.0,
+            (area.origin.1 + y as isize) as f64 + 0.5 - lake.center.1,
+        );
+        let distance = (offset.0 * offset.0 + offset.1 * offset.1).sqrt() / lake.radius;
+        let height = heights[index];
+        if distance >= 1.0 || height > lake.level {
+            continue;
+        }
+        let bottom = lake.level - (depth * (1.0 - distance * distance)).round() as isize;
+        heights[index] = height.min(bottom);
+        if heights[index] < lake.level {
+            levels[index] = levels[index].max(lake.level);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fill_lakes_and_sea() {
+        let config = WaterConfig {
+            sea_level: Some(-10),
+            lakes: Some(LakeConfig {
+                cell_size: 64,
+                chance: 1.0,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let water = Water::new(&config, 9, 9);
+        // A flat plain with a wall around a square basin, so every lake has the same shore.
+        let column_height = |x: isize, y: isize| if x.abs().max(y.abs()) < 200 { 5 } else { -20 };
+        let mut lake_columns = 0;
+        for chunk_coord in &[(0, 0), (1, 0), (-3, 2), (10, 0)] {
+            let mut heightmap = Heightmap::new();
+            let origin = util::scale_signed_coord_2d(chunk_coord, CHUNK_SIZE as isize);
+            for (index, (x, y)) in util::coord_iter_2d(CHUNK_SIZE).enumerate() {
+                heightmap.data[index] = column_height(origin.0 + x as isize, origin.1 + y as isize);
+            }
+            let levels = water.fill_columns(&mut heightmap, chunk_coord, &column_height);
+            for (index, &level) in levels.iter().enumerate() {
+                let height = heightmap.data[index];
+                if height <= -20 {
+                    // Low ground outside the basin is covered by the sea.
+                    assert_eq!(level, -10);
+                } else if level > -10 {
+                    // A lake on the plain is filled up to the lowest point of its shore.
+                    assert_eq!(level, 5);
+                    assert!(height < 5);
+                    lake_columns += 1;
+                }
+                let (x, y) = util::index_to_coord_2d(index, CHUNK_SIZE);
+                let (x, y) = (origin.0 + x as isize, origin.1 + y as isize);
+                let column = water.fill_column(x, y, column_height(x, y), &column_height);
+                assert_eq!(column, (height, level));
+            }
+        }
+        assert!(lake_columns > 0);
+    }
+}
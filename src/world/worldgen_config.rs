@@ -0,0 +1,1064 @@
+use super::functions::{MountainNoise2Params, MountainNoiseParams};
+use crate::render::{material_by_name, materials, MATERIALS};
+use serde::de::{self, Deserializer, Visitor};
+use serde::Deserialize;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+const MOUNTAINS_CONFIG: &str = include_str!("../../misc/worldgen/mountains.toml");
+const WORLEY_MOUNTAINS_CONFIG: &str = include_str!("../../misc/worldgen/worley_mountains.toml");
+const BIOMES_CONFIG: &str = include_str!("../../misc/worldgen/biomes.toml");
+
+/// A material in a config file, written as its name in misc/materials.csv. Numbers are rejected
+/// because they silently refer to a different material when materials are reordered. Unknown
+/// materials are an error when the config is loaded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MaterialId(pub u32);
+
+fn material_names() -> String {
+    let names: Vec<_> = MATERIALS.iter().map(|material| material.name).collect();
+    names.join(", ")
+}
+
+impl<'de> Deserialize<'de> for MaterialId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct MaterialVisitor;
+
+        impl<'de> Visitor<'de> for MaterialVisitor {
+            type Value = MaterialId;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("the name of a material")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<MaterialId, E> {
+                material_by_name(name).map(MaterialId).ok_or_else(|| {
+                    E::custom(format!(
+                        "There is no material named '{}'. Materials are {}.",
+                        name,
+                        material_names()
+                    ))
+                })
+            }
+
+            fn visit_i64<E: de::Error>(self, index: i64) -> Result<MaterialId, E> {
+                Err(E::custom(format!(
+                    "Materials must be given by name instead of by number, got {}. Materials are \
+                    {}.",
+                    index,
+                    material_names()
+                )))
+            }
+
+            fn visit_u64<E: de::Error>(self, index: u64) -> Result<MaterialId, E> {
+                self.visit_i64(index.min(i64::MAX as u64) as i64)
+            }
+        }
+
+        deserializer.deserialize_any(MaterialVisitor)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum NoiseKind {
+    ErodedFractal(MountainNoise2Params),
+    Worley(MountainNoiseParams),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NoiseLayer {
+    /// How wide the features of the layer are, in blocks.
+    pub scale: f64,
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+    pub noise: NoiseKind,
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Stratum {
+    pub material: MaterialId,
+    /// The height at which this material has completely replaced the one below it.
+    pub height: isize,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClimateConfig {
+    /// How wide areas with a similar climate are, in blocks.
+    pub scale: f64,
+    /// How much the climate noise is stretched. Higher values make extreme climates more common.
+    pub contrast: f64,
+    /// How different a climate can be from the closest biome's climate and still be affected by
+    /// another biome. Larger values make transitions between biomes wider.
+    pub blend: f64,
+}
+
+impl Default for ClimateConfig {
+    fn default() -> Self {
+        Self {
+            scale: 3000.0,
+            contrast: 2.0,
+            blend: 0.1,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BiomeConfig {
+    pub name: String,
+    /// The climate the biome appears in. Every column uses the biome with the climate closest to
+    /// its own, blended with other biomes that are almost as close.
+    #[serde(default = "default_climate")]
+    pub temperature: f64,
+    #[serde(default = "default_climate")]
+    pub moisture: f64,
+    /// Points mapping the combined value of the layers to a height in blocks.
+    pub height_curve: Vec<[f64; 2]>,
+    pub layers: Vec<NoiseLayer>,
+    /// The material of the blocks at the top of each column, instead of the strata.
+    #[serde(default)]
+    pub surface_material: Option<MaterialId>,
+    /// How many blocks deep the surface material goes.
+    #[serde(default = "default_surface_depth")]
+    pub surface_depth: isize,
+    /// Ordered from the bottom up.
+    pub strata: Vec<Stratum>,
+}
+
+fn default_climate() -> f64 {
+    0.5
+}
+
+fn default_surface_depth() -> isize {
+    1
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OverhangConfig {
+    /// How far 3D noise can move the surface up or down, in blocks.
+    pub amplitude: f64,
+    /// How wide the overhangs are, in blocks.
+    pub scale: f64,
+}
+
+impl Default for OverhangConfig {
+    fn default() -> Self {
+        Self {
+            amplitude: 8.0,
+            scale: 40.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CavernConfig {
+    /// How wide caverns are, in blocks.
+    pub scale: f64,
+    /// How many times wider than tall caverns are.
+    pub squash: f64,
+    /// Blocks where the cavern noise is above this value are carved out. Higher values make
+    /// caverns smaller and less common.
+    pub threshold: f64,
+    /// No caverns are carved above this height.
+    pub max_height: isize,
+}
+
+impl Default for CavernConfig {
+    fn default() -> Self {
+        Self {
+            scale: 80.0,
+            squash: 2.0,
+            threshold: 0.45,
+            max_height: 60,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WormConfig {
+    /// The world is divided into cubes of this size, each of which starts the same number of
+    /// tunnels.
+    pub region_size: isize,
+    /// How many tunnels start in each region.
+    pub per_region: usize,
+    /// How many blocks long each tunnel is.
+    pub length: usize,
+    pub min_radius: f64,
+    pub max_radius: f64,
+    /// How sharply tunnels turn, in radians per block.
+    pub turn: f64,
+    /// Tunnels only start between these heights, although they can wander outside of them.
+    pub min_height: isize,
+    pub max_height: isize,
+}
+
+impl Default for WormConfig {
+    fn default() -> Self {
+        Self {
+            region_size: 128,
+            per_region: 2,
+            length: 160,
+            min_radius: 1.5,
+            max_radius: 3.5,
+            turn: 0.25,
+            min_height: -256,
+            max_height: 60,
+        }
+    }
+}
+
+/// Everything that makes terrain more than a heightmap. Each kind of cave is only generated if
+/// its table is present.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CaveConfig {
+    pub overhangs: Option<OverhangConfig>,
+    pub caverns: Option<CavernConfig>,
+    pub worms: Option<WormConfig>,
+    /// A material that is sometimes placed on the floors of caves, to light them up.
+    pub glow_material: Option<MaterialId>,
+    /// The chance that a block of cave floor is made of the glow material.
+    pub glow_chance: f64,
+}
+
+impl CaveConfig {
+    fn validate(&self) -> Result<(), String> {
+        let positive = |value: f64| !value.is_nan() && value > 0.0;
+        if let Some(overhangs) = &self.overhangs {
+            if !positive(overhangs.scale) || overhangs.amplitude.is_nan() {
+                return Err("Overhangs must have a positive scale and an amplitude.".to_owned());
+            }
+        }
+        if let Some(caverns) = &self.caverns {
+            if !positive(caverns.scale) || !positive(caverns.squash) {
+                return Err("The scale and squash of caverns must be positive.".to_owned());
+            }
+        }
+        if let Some(worms) = &self.worms {
+            if worms.region_size <= 0 || !positive(worms.min_radius) {
+                return Err("The region size and radius of tunnels must be positive.".to_owned());
+            }
+            if !(worms.min_radius <= worms.max_radius && worms.min_height <= worms.max_height) {
+                return Err(
+                    "The minimum radius and height of tunnels must not be larger than \
+                    the maximum."
+                        .to_owned(),
+                );
+            }
+            if worms.length > 1000 || worms.per_region > 100 || worms.max_radius > 32.0 {
+                return Err(
+                    "Tunnels are limited to a length of 1000, a radius of 32 and 100 per region."
+                        .to_owned(),
+                );
+            }
+        }
+        if !(0.0..=1.0).contains(&self.glow_chance) {
+            return Err("The glow chance must be between 0 and 1.".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LakeConfig {
+    /// The world is divided into squares of this size, each of which can contain one lake.
+    pub cell_size: isize,
+    /// The chance that a square contains a lake.
+    pub chance: f64,
+    pub min_radius: f64,
+    pub max_radius: f64,
+    /// How far below the water the middle of a lake is dug out, in blocks.
+    pub depth: f64,
+}
+
+impl Default for LakeConfig {
+    fn default() -> Self {
+        Self {
+            cell_size: 256,
+            chance: 0.3,
+            min_radius: 12.0,
+            max_radius: 40.0,
+            depth: 6.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RiverConfig {
+    /// How far apart rivers are, in blocks.
+    pub scale: f64,
+    /// How wide rivers are, as a fraction of the distance between them.
+    pub width: f64,
+    /// How far the middle of a river is dug out, in blocks.
+    pub depth: f64,
+    /// Rivers fade out as the terrain rises up to this height.
+    pub max_height: isize,
+}
+
+impl Default for RiverConfig {
+    fn default() -> Self {
+        Self {
+            scale: 1500.0,
+            width: 0.02,
+            depth: 4.0,
+            max_height: 60,
+        }
+    }
+}
+
+/// Liquid that fills the low parts of the terrain. Lakes and rivers are only generated if their
+/// tables are present.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WaterConfig {
+    /// Everything above the terrain and below this height is filled with water. No sea if this
+    /// is left out.
+    pub sea_level: Option<isize>,
+    /// The material that seas, lakes and rivers are made of.
+    pub material: MaterialId,
+    pub lakes: Option<LakeConfig>,
+    pub rivers: Option<RiverConfig>,
+}
+
+impl Default for WaterConfig {
+    fn default() -> Self {
+        Self {
+            sea_level: None,
+            material: MaterialId(materials::WATER),
+            lakes: None,
+            rivers: None,
+        }
+    }
+}
+
+impl WaterConfig {
+    fn validate(&self) -> Result<(), String> {
+        let positive = |value: f64| !value.is_nan() && value > 0.0;
+        if let Some(lakes) = &self.lakes {
+            if lakes.cell_size <= 0 || !positive(lakes.min_radius) || lakes.depth.is_nan() {
+                return Err("The cell size and radius of lakes must be positive.".to_owned());
+            }
+            if lakes.max_radius.is_nan() || lakes.min_radius > lakes.max_radius {
+                return Err(
+                    "The minimum radius of lakes must not be larger than the maximum.".to_owned(),
+                );
+            }
+            if lakes.max_radius > 256.0 {
+                return Err("Lakes are limited to a radius of 256.".to_owned());
+            }
+            if !(0.0..=1.0).contains(&lakes.chance) {
+                return Err("The chance of lakes must be between 0 and 1.".to_owned());
+            }
+        }
+        if let Some(rivers) = &self.rivers {
+            if !positive(rivers.scale) || !positive(rivers.width) || rivers.depth.is_nan() {
+                return Err("The scale and width of rivers must be positive.".to_owned());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HydraulicConfig {
+    /// How many drops of rain run down each tile.
+    pub droplets: usize,
+    /// How many steps a droplet takes before it is gone.
+    pub lifetime: usize,
+    /// How much droplets keep going in the same direction instead of following the slope, from 0
+    /// to 1.
+    pub inertia: f64,
+    /// How much sediment a droplet can carry, relative to its speed and how much water it has.
+    pub capacity: f64,
+    /// How quickly droplets pick up sediment while they can carry more.
+    pub erosion: f64,
+    /// How quickly droplets drop sediment when they carry more than they can.
+    pub deposition: f64,
+    /// How much of a droplet's water is lost every step.
+    pub evaporation: f64,
+    pub gravity: f64,
+}
+
+impl Default for HydraulicConfig {
+    fn default() -> Self {
+        Self {
+            droplets: 50000,
+            lifetime: 40,
+            inertia: 0.05,
+            capacity: 4.0,
+            erosion: 0.3,
+            deposition: 0.3,
+            evaporation: 0.02,
+            gravity: 4.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThermalConfig {
+    pub iterations: usize,
+    /// Material slides down wherever neighboring columns are further apart than this, in blocks.
+    pub talus: f64,
+    /// How much of the extra height slides down in each iteration, from 0 to 1.
+    pub rate: f64,
+}
+
+impl Default for ThermalConfig {
+    fn default() -> Self {
+        Self {
+            iterations: 20,
+            talus: 1.5,
+            rate: 0.5,
+        }
+    }
+}
+
+/// Simulated erosion of the heightmap. It only happens if at least one of the tables is present.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ErosionConfig {
+    /// Erosion is simulated on square tiles of this many columns.
+    pub tile_size: isize,
+    /// How far tiles reach into their neighbors. The overlapping parts are blended together so
+    /// that there are no seams between tiles.
+    pub overlap: isize,
+    pub hydraulic: Option<HydraulicConfig>,
+    pub thermal: Option<ThermalConfig>,
+}
+
+impl Default for ErosionConfig {
+    fn default() -> Self {
+        Self {
+            tile_size: 256,
+            overlap: 32,
+            hydraulic: None,
+            thermal: None,
+        }
+    }
+}
+
+impl ErosionConfig {
+    fn validate(&self) -> Result<(), String> {
+        let fraction = |value: f64| (0.0..=1.0).contains(&value);
+        let non_negative = |value: f64| value >= 0.0;
+        if self.overlap <= 0 || self.tile_size < self.overlap * 2 {
+            return Err(
+                "The overlap must be positive and at most half of the tile size.".to_owned(),
+            );
+        }
+        if self.tile_size > 4096 {
+            return Err("Tiles are limited to a size of 4096.".to_owned());
+        }
+        if let Some(hydraulic) = &self.hydraulic {
+            let rates = [hydraulic.inertia, hydraulic.erosion, hydraulic.deposition];
+            if !rates.iter().all(|rate| fraction(*rate)) || !fraction(hydraulic.evaporation) {
+                return Err(
+                    "The inertia, erosion, deposition and evaporation of hydraulic erosion must \
+                     be between 0 and 1."
+                        .to_owned(),
+                );
+            }
+            if !non_negative(hydraulic.capacity) || !non_negative(hydraulic.gravity) {
+                return Err(
+                    "The capacity and gravity of hydraulic erosion can not be negative.".to_owned(),
+                );
+            }
+        }
+        if let Some(thermal) = &self.thermal {
+            if !non_negative(thermal.talus) || !fraction(thermal.rate) {
+                return Err(
+                    "The talus of thermal erosion can not be negative and its rate must \
+                    be between 0 and 1."
+                        .to_owned(),
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The shape of a structure and what it is made of.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum StructureShape {
+    /// A trunk with a ball of leaves on top, standing on the surface.
+    Tree {
+        #[serde(default = "default_trunk_material")]
+        trunk_material: MaterialId,
+        #[serde(default = "default_leaf_material")]
+        leaf_material: MaterialId,
+        #[serde(default = "default_min_trunk_height")]
+        min_height: isize,
+        #[serde(default = "default_max_trunk_height")]
+        max_height: isize,
+        #[serde(default = "default_leaf_radius")]
+        leaf_radius: f64,
+    },
+    /// A lumpy ball of rock, partly buried in the surface.
+    Boulder {
+        #[serde(default = "default_stone_material")]
+        material: MaterialId,
+        #[serde(default = "default_min_boulder_radius")]
+        min_radius: f64,
+        #[serde(default = "default_max_boulder_radius")]
+        max_radius: f64,
+    },
+    /// A winding line of material that only replaces solid blocks underground.
+    OreVein {
+        #[serde(default = "default_ore_material")]
+        material: MaterialId,
+        /// How many blocks long the vein is.
+        #[serde(default = "default_vein_length")]
+        length: usize,
+        /// How far below the surface the vein starts, in blocks.
+        #[serde(default = "default_min_vein_depth")]
+        min_depth: isize,
+        #[serde(default = "default_max_vein_depth")]
+        max_depth: isize,
+    },
+    /// The crumbling walls of a small square building.
+    Ruin {
+        #[serde(default = "default_stone_material")]
+        material: MaterialId,
+        /// How many blocks long each wall is.
+        #[serde(default = "default_min_ruin_size")]
+        min_size: isize,
+        #[serde(default = "default_max_ruin_size")]
+        max_size: isize,
+        #[serde(default = "default_ruin_height")]
+        height: isize,
+        /// The chance that a block of wall has crumbled away.
+        #[serde(default = "default_ruin_decay")]
+        decay: f64,
+    },
+}
+
+fn default_trunk_material() -> MaterialId {
+    MaterialId(materials::DIRT)
+}
+
+fn default_leaf_material() -> MaterialId {
+    MaterialId(materials::GRASS)
+}
+
+fn default_min_trunk_height() -> isize {
+    4
+}
+
+fn default_max_trunk_height() -> isize {
+    7
+}
+
+fn default_leaf_radius() -> f64 {
+    2.5
+}
+
+fn default_stone_material() -> MaterialId {
+    MaterialId(materials::STONE)
+}
+
+fn default_min_boulder_radius() -> f64 {
+    1.5
+}
+
+fn default_max_boulder_radius() -> f64 {
+    3.0
+}
+
+fn default_ore_material() -> MaterialId {
+    MaterialId(materials::GLOWSTONE)
+}
+
+fn default_vein_length() -> usize {
+    12
+}
+
+fn default_min_vein_depth() -> isize {
+    4
+}
+
+fn default_max_vein_depth() -> isize {
+    40
+}
+
+fn default_min_ruin_size() -> isize {
+    5
+}
+
+fn default_max_ruin_size() -> isize {
+    9
+}
+
+fn default_ruin_height() -> isize {
+    4
+}
+
+fn default_ruin_decay() -> f64 {
+    0.3
+}
+
+/// Structures of one kind, scattered over the world.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StructureConfig {
+    /// The world is divided into squares of this size, each of which can contain one structure.
+    pub spacing: isize,
+    /// The chance that a square contains a structure.
+    pub chance: f64,
+    /// The names of the biomes the structures are placed in. Every biome if left out.
+    #[serde(default)]
+    pub biomes: Vec<String>,
+    /// Whether the structures can be placed at the bottom of seas, lakes and rivers.
+    #[serde(default)]
+    pub underwater: bool,
+    pub shape: StructureShape,
+}
+
+impl StructureConfig {
+    fn validate(&self, biomes: &[BiomeConfig]) -> Result<(), String> {
+        if self.spacing <= 0 || !(0.0..=1.0).contains(&self.chance) {
+            return Err(
+                "The spacing must be positive and the chance must be between 0 and 1.".to_owned(),
+            );
+        }
+        for name in &self.biomes {
+            if !biomes.iter().any(|biome| &biome.name == name) {
+                return Err(format!("There is no biome named '{}'.", name));
+            }
+        }
+        // Limits keep chunks from having to look too far around them for structures.
+        let size_ok = match &self.shape {
+            StructureShape::Tree {
+                min_height,
+                max_height,
+                leaf_radius,
+                ..
+            } => {
+                0 < *min_height
+                    && min_height <= max_height
+                    && *max_height <= 64
+                    && (0.0..=16.0).contains(leaf_radius)
+            }
+            StructureShape::Boulder {
+                min_radius,
+                max_radius,
+                ..
+            } => 0.0 < *min_radius && min_radius <= max_radius && *max_radius <= 32.0,
+            StructureShape::OreVein {
+                length,
+                min_depth,
+                max_depth,
+                ..
+            } => *length <= 128 && min_depth <= max_depth,
+            StructureShape::Ruin {
+                min_size,
+                max_size,
+                height,
+                decay,
+                ..
+            } => {
+                0 < *min_size
+                    && min_size <= max_size
+                    && *max_size <= 64
+                    && (0..=64).contains(height)
+                    && (0.0..=1.0).contains(decay)
+            }
+        };
+        if !size_ok {
+            return Err(
+                "The minimum size of the structure must be positive and at most the maximum, \
+                 which must not be too large."
+                    .to_owned(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Describes the terrain made by the noise based generators. See misc/worldgen for examples.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorldgenConfig {
+    /// Chunks entirely below this height are filled with the lowest stratum of each biome.
+    pub solid_below: isize,
+    #[serde(default)]
+    pub climate: ClimateConfig,
+    #[serde(default)]
+    pub caves: CaveConfig,
+    #[serde(default)]
+    pub water: WaterConfig,
+    #[serde(default)]
+    pub erosion: ErosionConfig,
+    pub biomes: Vec<BiomeConfig>,
+    /// Placed on top of the terrain once it is generated, in this order.
+    #[serde(default)]
+    pub structures: Vec<StructureConfig>,
+}
+
+impl WorldgenConfig {
+    /// Returns the config that a built in generator uses when it is not given one.
+    pub fn builtin(generator: &str) -> Option<WorldgenConfig> {
+        let text = match generator {
+            "mountains" => MOUNTAINS_CONFIG,
+            "worley_mountains" => WORLEY_MOUNTAINS_CONFIG,
+            "biomes" => BIOMES_CONFIG,
+            _ => return None,
+        };
+        Some(Self::parse(text).expect("Built in worldgen config is invalid."))
+    }
+
+    pub fn parse(text: &str) -> Result<WorldgenConfig, String> {
+        let config: WorldgenConfig = toml::from_str(text).map_err(|err| err.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn load(path: &Path) -> io::Result<WorldgenConfig> {
+        let text = std::fs::read_to_string(path).map_err(|err| {
+            io::Error::new(err.kind(), format!("Failed to read {:?}: {}", path, err))
+        })?;
+        Self::parse(&text).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{:?} is not a valid worldgen config: {}", path, err),
+            )
+        })
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        let climate = &self.climate;
+        if climate.scale.is_nan() || climate.scale <= 0.0 {
+            return Err("The scale of the climate must be positive.".to_owned());
+        }
+        if climate.blend.is_nan() || climate.blend < 0.0 {
+            return Err("The blend distance of the climate can not be negative.".to_owned());
+        }
+        self.caves
+            .validate()
+            .map_err(|err| format!("In caves: {}", err))?;
+        self.water
+            .validate()
+            .map_err(|err| format!("In water: {}", err))?;
+        self.erosion
+            .validate()
+            .map_err(|err| format!("In erosion: {}", err))?;
+        if self.biomes.is_empty() {
+            return Err("There must be at least one biome.".to_owned());
+        }
+        for biome in &self.biomes {
+            biome
+                .validate()
+                .map_err(|err| format!("In biome '{}': {}", biome.name, err))?;
+        }
+        for (index, structure) in self.structures.iter().enumerate() {
+            structure
+                .validate(&self.biomes)
+                .map_err(|err| format!("In structure {}: {}", index + 1, err))?;
+        }
+        Ok(())
+    }
+}
+
+impl BiomeConfig {
+    fn validate(&self) -> Result<(), String> {
+        if self.layers.is_empty() {
+            return Err("There must be at least one noise layer.".to_owned());
+        }
+        if self
+            .layers
+            .iter()
+            .any(|layer| layer.scale.is_nan() || layer.scale <= 0.0)
+        {
+            return Err("The scale of every noise layer must be positive.".to_owned());
+        }
+        if self.height_curve.len() < 2 {
+            return Err("The height curve must have at least two points.".to_owned());
+        }
+        if self
+            .height_curve
+            .windows(2)
+            .any(|pair| pair[0][0] >= pair[1][0])
+        {
+            return Err("The points of the height curve must be in increasing order.".to_owned());
+        }
+        if self.strata.is_empty() {
+            return Err("There must be at least one stratum.".to_owned());
+        }
+        if self
+            .strata
+            .windows(2)
+            .any(|pair| pair[0].height >= pair[1].height)
+        {
+            return Err("The heights of the strata must be in increasing order.".to_owned());
+        }
+        if self.surface_depth < 0 {
+            return Err("The surface depth can not be negative.".to_owned());
+        }
+        Ok(())
+    }
+
+    /// Converts a value of the noise layers to a height using the height curve.
+    pub fn map_height(&self, value: f64) -> f64 {
+        let curve = &self.height_curve;
+        // The first and last segments extend past the ends of the curve.
+        let mut segment = 0;
+        while segment + 2 < curve.len() && value > curve[segment + 1][0] {
+            segment += 1;
+        }
+        let ([x0, y0], [x1, y1]) = (curve[segment], curve[segment + 1]);
+        y0 + (value - x0) * ((y1 - y0) / (x1 - x0))
+    }
+
+    /// Picks the material of a block at the given height in a column whose surface is at
+    /// surface_height. random is only used in the transitions between strata.
+    pub fn pick_material(
+        &self,
+        random: &mut impl rand::RngCore,
+        height: isize,
+        surface_height: isize,
+    ) -> u32 {
+        if let Some(material) = self.surface_material {
+            if height >= surface_height - self.surface_depth {
+                return material.0;
+            }
+        }
+        let strata = &self.strata;
+        if height < strata[0].height {
+            return strata[0].material.0;
+        }
+        for pair in strata.windows(2) {
+            let (below, above) = (&pair[0], &pair[1]);
+            if height < above.height {
+                let threshold = (height - below.height) as u32;
+                let range = (above.height - below.height) as u32;
+                return if random.next_u32() % range < threshold {
+                    above.material.0
+                } else {
+                    below.material.0
+                };
+            }
+        }
+        strata[strata.len() - 1].material.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        solid_below = 0
+
+        [[biomes]]
+        name = "hills"
+        height_curve = [[0.0, 0.0], [1.0, 100.0], [2.0, 150.0]]
+        surface_material = "snow"
+        surface_depth = 2
+
+        [[biomes.layers]]
+        scale = 10.0
+        [biomes.layers.noise]
+        kind = "worley"
+        exponent = 3.0
+
+        [[biomes.strata]]
+        material = "grass"
+        height = 10
+
+        [[biomes.strata]]
+        material = "stone"
+        height = 20
+    "#;
+
+    #[test]
+    fn parse_config() {
+        let config = WorldgenConfig::parse(CONFIG).unwrap();
+        assert_eq!(config.climate.scale, ClimateConfig::default().scale);
+        let biome = &config.biomes[0];
+        assert_eq!((biome.temperature, biome.moisture), (0.5, 0.5));
+        assert_eq!(biome.layers[0].weight, 1.0);
+        match &biome.layers[0].noise {
+            NoiseKind::Worley(params) => {
+                assert_eq!(params.exponent, 3.0);
+                assert_eq!(params.detail_frequency, 4.0);
+            }
+            other => panic!("Expected worley noise, got {:?}", other),
+        }
+        assert_eq!(biome.map_height(-0.5), -50.0);
+        assert_eq!(biome.map_height(0.5), 50.0);
+        assert_eq!(biome.map_height(3.0), 200.0);
+
+        let mut random = rand::thread_rng();
+        assert_eq!(biome.pick_material(&mut random, 9, 100), materials::GRASS);
+        assert_eq!(biome.pick_material(&mut random, 10, 100), materials::GRASS);
+        assert_eq!(biome.pick_material(&mut random, 20, 100), materials::STONE);
+        assert_eq!(biome.pick_material(&mut random, 97, 100), materials::STONE);
+        assert_eq!(biome.pick_material(&mut random, 98, 100), materials::SNOW);
+
+        for name in &["mountains", "worley_mountains", "biomes"] {
+            assert!(WorldgenConfig::builtin(name).is_some());
+        }
+    }
+
+    #[test]
+    fn parse_caves() {
+        let config = WorldgenConfig::parse(CONFIG).unwrap();
+        assert!(config.caves.overhangs.is_none());
+        assert!(config.caves.caverns.is_none());
+        assert!(config.caves.worms.is_none());
+
+        let text = format!(
+            "{}\n[caves]\nglow_material = \"glowstone\"\n[caves.worms]\nlength = 40",
+            CONFIG
+        );
+        let config = WorldgenConfig::parse(&text).unwrap();
+        let worms = config.caves.worms.unwrap();
+        assert_eq!(worms.length, 40);
+        assert_eq!(worms.region_size, WormConfig::default().region_size);
+        let glowstone = MaterialId(materials::GLOWSTONE);
+        assert_eq!(config.caves.glow_material, Some(glowstone));
+        assert!(config.caves.caverns.is_none());
+    }
+
+    #[test]
+    fn parse_water() {
+        let config = WorldgenConfig::parse(CONFIG).unwrap();
+        assert_eq!(config.water.sea_level, None);
+        assert!(config.water.lakes.is_none());
+
+        let text = format!(
+            "{}\n[water]\nsea_level = 5\n[water.rivers]\ndepth = 2.0",
+            CONFIG
+        );
+        let config = WorldgenConfig::parse(&text).unwrap();
+        assert_eq!(config.water.sea_level, Some(5));
+        assert!(MATERIALS[config.water.material.0 as usize].is_liquid());
+        assert_eq!(config.water.rivers.unwrap().depth, 2.0);
+        assert!(config.water.lakes.is_none());
+    }
+
+    #[test]
+    fn parse_structures() {
+        let config = WorldgenConfig::parse(CONFIG).unwrap();
+        assert!(config.structures.is_empty());
+
+        let text = format!(
+            "{}\n[[structures]]\nspacing = 16\nchance = 0.5\nbiomes = [\"hills\"]\n\
+             [structures.shape]\nkind = \"boulder\"\nmax_radius = 4.0",
+            CONFIG
+        );
+        let config = WorldgenConfig::parse(&text).unwrap();
+        let structure = &config.structures[0];
+        assert_eq!(structure.biomes, vec!["hills".to_owned()]);
+        assert!(!structure.underwater);
+        match &structure.shape {
+            StructureShape::Boulder {
+                material,
+                max_radius,
+                ..
+            } => {
+                assert_eq!(*material, MaterialId(materials::STONE));
+                assert_eq!(*max_radius, 4.0);
+            }
+            other => panic!("Expected a boulder, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn material_names() {
+        // Indices are rejected, since they refer to a different material once materials are
+        // reordered. The error should say which names can be used instead.
+        let text = CONFIG.replace(
+            "material = \"stone\"",
+            &format!("material = {}", materials::STONE),
+        );
+        let err = WorldgenConfig::parse(&text).unwrap_err();
+        assert!(
+            err.contains("Materials must be given by name instead of by number"),
+            "{}",
+            err
+        );
+        assert!(err.contains("air, "), "{}", err);
+
+        let text = CONFIG.replace("\"grass\"", "\"gras\"");
+        let err = WorldgenConfig::parse(&text).unwrap_err();
+        assert!(
+            err.contains("There is no material named 'gras'."),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn reject_invalid_config() {
+        let structure = |fields: &str, shape: &str| {
+            format!(
+                "{}\n[[structures]]\n{}\n[structures.shape]\n{}",
+                CONFIG, fields, shape
+            )
+        };
+        let invalid = [
+            CONFIG.replace("exponent", "exponnent"),
+            CONFIG.replace("worley", "perlin"),
+            CONFIG.replace("[2.0, 150.0]", "[0.5, 150.0]"),
+            CONFIG.replace("height = 20", "height = 5"),
+            CONFIG.replace("material = \"stone\"", "material = 4000"),
+            CONFIG.replace("material = \"stone\"", "material = 4"),
+            CONFIG.replace("surface_material = \"snow\"", "surface_material = \"lava\""),
+            CONFIG.replace("scale = 10.0", "scale = 0.0"),
+            CONFIG.replace("name = \"hills\"", ""),
+            "solid_below = 0\nbiomes = []".to_owned(),
+            format!("{}\n[caves]\nglow_material = 4000", CONFIG),
+            format!("{}\n[caves]\nglow_chance = 2.0", CONFIG),
+            format!("{}\n[caves.caverns]\nsquash = 0.0", CONFIG),
+            format!(
+                "{}\n[caves.worms]\nmin_radius = 5.0\nmax_radius = 2.0",
+                CONFIG
+            ),
+            format!("{}\n[caves.worms]\nregion_size = 0", CONFIG),
+            format!("{}\n[caves.tunnels]", CONFIG),
+            format!("{}\n[water]\nmaterial = 4000", CONFIG),
+            format!("{}\n[water.lakes]\nchance = -1.0", CONFIG),
+            format!("{}\n[water.lakes]\nmax_radius = 5.0", CONFIG),
+            format!("{}\n[water.rivers]\nwidth = 0.0", CONFIG),
+            structure("spacing = 0\nchance = 0.5", "kind = \"tree\""),
+            structure("spacing = 8\nchance = 1.5", "kind = \"tree\""),
+            structure("spacing = 8\nchance = 0.5", "kind = \"tower\""),
+            structure(
+                "spacing = 8\nchance = 0.5\nbiomes = [\"desert\"]",
+                "kind = \"tree\"",
+            ),
+            structure(
+                "spacing = 8\nchance = 0.5",
+                "kind = \"tree\"\nmin_height = 9\nmax_height = 3",
+            ),
+            structure(
+                "spacing = 8\nchance = 0.5",
+                "kind = \"ruin\"\nmaterial = 4000",
+            ),
+            structure(
+                "spacing = 8\nchance = 0.5",
+                "kind = \"boulder\"\nmax_radius = 1.0",
+            ),
+        ];
+        for text in &invalid {
+            assert!(WorldgenConfig::parse(text).is_err(), "{}", text);
+        }
+    }
+}